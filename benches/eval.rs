@@ -0,0 +1,76 @@
+//! Evaluation benchmarks, run with `cargo bench`. Each one defines its
+//! procedures once and then times repeated calls, on both engines.
+
+use std::time::{Duration, Instant};
+
+use risp::{
+    env::Env,
+    eval::eval,
+    runtime::Engine,
+    sync::{Rc, RefCell},
+};
+
+/// Name, definitions and the expression that is timed.
+const BENCHES: &[(&str, &str, &str)] = &[
+    (
+        "fact",
+        "(define fact (lambda (n) (if (< n 1) 1 (* n (fact (- n 1))))))",
+        "(fact 20)",
+    ),
+    (
+        "sum-n",
+        "(define sum-n (lambda (n a) (if (= n 0) a (sum-n (- n 1) (+ n a)))))",
+        "(sum-n 10000 0)",
+    ),
+    (
+        "fib",
+        "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))",
+        "(fib 20)",
+    ),
+    (
+        "list-walk",
+        "((define items (range 1000))
+          (define walk (lambda (i acc)
+            (if (= i 1000) acc (walk (+ i 1) (+ acc (length items)))))))",
+        "(walk 0 0)",
+    ),
+];
+
+const MEASURE_FOR: Duration = Duration::from_secs(2);
+
+fn main() {
+    let filter = std::env::args().nth(1).filter(|arg| !arg.starts_with('-'));
+    for (name, setup, expr) in BENCHES {
+        if filter
+            .as_ref()
+            .is_some_and(|filter| !name.contains(filter.as_str()))
+        {
+            continue;
+        }
+        for engine in [Engine::TreeWalker, Engine::Bytecode] {
+            bench(name, setup, expr, engine);
+        }
+    }
+}
+
+fn bench(name: &str, setup: &str, expr: &str, engine: Engine) {
+    let mut env = Rc::new(RefCell::new(Env::new()));
+    env.borrow().runtime().set_engine(engine);
+    eval(setup, &mut env).unwrap();
+    eval(expr, &mut env).unwrap();
+
+    let mut iterations = 0u32;
+    let start = Instant::now();
+    while start.elapsed() < MEASURE_FOR {
+        eval(expr, &mut env).unwrap();
+        iterations += 1;
+    }
+    let per_iter = start.elapsed() / iterations;
+    println!(
+        "{:<10} {:<12} {:>12?}/iter ({} iterations)",
+        name,
+        format!("{:?}", engine),
+        per_iter,
+        iterations
+    );
+}
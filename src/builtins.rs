@@ -0,0 +1,782 @@
+use std::cell::Cell;
+use std::fmt;
+use std::io::BufRead;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    env::Env,
+    eval::{self, EvalError},
+    hash_table::{HashKey, HashTable},
+    object::Object,
+    runtime::list_size,
+    sync::{Rc, RefCell},
+};
+
+mod char;
+mod control;
+pub(crate) mod hash;
+mod list;
+mod persistent;
+mod sequence;
+mod stream;
+mod string;
+pub(crate) mod vector;
+
+/// A group of builtins that a host can grant or withhold as a whole.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Capability {
+    /// Functions without side effects.
+    Pure,
+    /// Reading from stdin and writing to stdout.
+    Console,
+    /// Reading and writing files.
+    Filesystem,
+    /// Environment variables and exiting the process.
+    Process,
+    /// The system clock and sleeping.
+    Time,
+    /// Random numbers.
+    Random,
+    /// Driving and inspecting the interpreter itself, e.g. the garbage
+    /// collector.
+    Runtime,
+}
+
+impl Capability {
+    pub const ALL: &'static [Capability] = &[
+        Capability::Pure,
+        Capability::Console,
+        Capability::Filesystem,
+        Capability::Process,
+        Capability::Time,
+        Capability::Random,
+        Capability::Runtime,
+    ];
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Capability::Pure => "pure",
+            Capability::Console => "console",
+            Capability::Filesystem => "filesystem",
+            Capability::Process => "process",
+            Capability::Time => "time",
+            Capability::Random => "random",
+            Capability::Runtime => "runtime",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+pub type BuiltinFn = fn(&[Object], &mut Rc<RefCell<Env>>) -> Result<Object, EvalError>;
+
+/// A function implemented in Rust. Arguments are evaluated before it is called.
+#[derive(Clone, Copy)]
+pub struct Builtin {
+    pub name: &'static str,
+    pub capability: Capability,
+    pub func: BuiltinFn,
+}
+
+impl fmt::Debug for Builtin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Builtin({})", self.name)
+    }
+}
+
+impl PartialEq for Builtin {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+const fn builtin(name: &'static str, capability: Capability, func: BuiltinFn) -> Builtin {
+    Builtin {
+        name,
+        capability,
+        func,
+    }
+}
+
+pub const BUILTINS: &[Builtin] = &[
+    builtin("apply", Capability::Pure, apply),
+    builtin("map", Capability::Pure, sequence::map),
+    builtin("filter", Capability::Pure, sequence::filter),
+    builtin("reduce", Capability::Pure, sequence::reduce),
+    builtin("for-each", Capability::Pure, sequence::for_each),
+    builtin("funcall", Capability::Pure, funcall),
+    builtin("values", Capability::Pure, values),
+    builtin("call-with-values", Capability::Pure, call_with_values),
+    builtin("call/cc", Capability::Pure, control::call_cc),
+    builtin(
+        "call-with-current-continuation",
+        Capability::Pure,
+        control::call_cc,
+    ),
+    builtin("dynamic-wind", Capability::Pure, control::dynamic_wind),
+    builtin("make-generator", Capability::Pure, control::make_generator),
+    builtin("yield", Capability::Pure, control::yield_),
+    builtin("generator?", Capability::Pure, control::is_generator),
+    builtin("generator-next", Capability::Pure, control::generator_next),
+    builtin(
+        "generator-done?",
+        Capability::Pure,
+        control::is_generator_done,
+    ),
+    builtin("+", Capability::Pure, add),
+    builtin("-", Capability::Pure, sub),
+    builtin("*", Capability::Pure, mul),
+    builtin("/", Capability::Pure, div),
+    builtin("%", Capability::Pure, rem),
+    builtin("<", Capability::Pure, lt),
+    builtin(">", Capability::Pure, gt),
+    builtin("=", Capability::Pure, eq),
+    builtin("!=", Capability::Pure, ne),
+    builtin("&", Capability::Pure, and),
+    builtin("|", Capability::Pure, or),
+    builtin("not", Capability::Pure, not),
+    builtin("number?", Capability::Pure, is_number),
+    builtin("string?", Capability::Pure, is_string),
+    builtin("list?", Capability::Pure, is_list),
+    builtin("keyword?", Capability::Pure, is_keyword),
+    builtin("procedure?", Capability::Pure, is_procedure),
+    builtin("char?", Capability::Pure, is_char),
+    builtin("char->integer", Capability::Pure, char::char_to_integer),
+    builtin("integer->char", Capability::Pure, char::integer_to_char),
+    builtin("char-upcase", Capability::Pure, char::char_upcase),
+    builtin("char-downcase", Capability::Pure, char::char_downcase),
+    builtin(
+        "char-alphabetic?",
+        Capability::Pure,
+        char::is_char_alphabetic,
+    ),
+    builtin("char-numeric?", Capability::Pure, char::is_char_numeric),
+    builtin(
+        "char-whitespace?",
+        Capability::Pure,
+        char::is_char_whitespace,
+    ),
+    builtin("string-ref", Capability::Pure, string::string_ref),
+    builtin("string->list", Capability::Pure, string::string_to_list),
+    builtin("list->string", Capability::Pure, string::list_to_string),
+    builtin("string-length", Capability::Pure, string::string_length),
+    builtin("substring", Capability::Pure, string::substring),
+    builtin("string-split", Capability::Pure, string::string_split),
+    builtin("string-join", Capability::Pure, string::string_join),
+    builtin("string-trim", Capability::Pure, string::string_trim),
+    builtin(
+        "string-trim-left",
+        Capability::Pure,
+        string::string_trim_left,
+    ),
+    builtin(
+        "string-trim-right",
+        Capability::Pure,
+        string::string_trim_right,
+    ),
+    builtin("string-contains", Capability::Pure, string::string_contains),
+    builtin("string-index", Capability::Pure, string::string_index),
+    builtin("string-replace", Capability::Pure, string::string_replace),
+    builtin("string-upcase", Capability::Pure, string::string_upcase),
+    builtin("string-downcase", Capability::Pure, string::string_downcase),
+    builtin("string->symbol", Capability::Pure, string::string_to_symbol),
+    builtin("symbol->string", Capability::Pure, string::symbol_to_string),
+    builtin("format", Capability::Pure, string::format),
+    builtin("vector?", Capability::Pure, vector::is_vector),
+    builtin("vector", Capability::Pure, vector::vector),
+    builtin("make-vector", Capability::Pure, vector::make_vector),
+    builtin("vector-length", Capability::Pure, vector::vector_length),
+    builtin("vector-ref", Capability::Pure, vector::vector_ref),
+    builtin("vector-set!", Capability::Pure, vector::vector_set),
+    builtin("vector-map", Capability::Pure, vector::vector_map),
+    builtin("vector->list", Capability::Pure, vector::vector_to_list),
+    builtin("list->vector", Capability::Pure, vector::list_to_vector),
+    builtin("hash", Capability::Pure, hash::hash),
+    builtin("hash?", Capability::Pure, hash::is_hash),
+    builtin("hash-count", Capability::Pure, hash::hash_count),
+    builtin("hash-ref", Capability::Pure, hash::hash_ref),
+    builtin("hash-contains?", Capability::Pure, hash::hash_contains),
+    builtin("hash-set!", Capability::Pure, hash::hash_set),
+    builtin("hash-remove!", Capability::Pure, hash::hash_remove),
+    builtin("hash-keys", Capability::Pure, hash::hash_keys),
+    builtin("hash-values", Capability::Pure, hash::hash_values),
+    builtin("hash->list", Capability::Pure, hash::hash_to_list),
+    builtin("hash-for-each", Capability::Pure, hash::hash_for_each),
+    builtin("length", Capability::Pure, list::length),
+    builtin("empty?", Capability::Pure, list::is_empty),
+    builtin("nil?", Capability::Pure, list::is_nil),
+    builtin("append", Capability::Pure, list::append),
+    builtin("reverse", Capability::Pure, list::reverse),
+    builtin("nth", Capability::Pure, list::nth),
+    builtin("first", Capability::Pure, list::first),
+    builtin("rest", Capability::Pure, list::rest),
+    builtin("take", Capability::Pure, list::take),
+    builtin("drop", Capability::Pure, list::drop),
+    builtin("member", Capability::Pure, list::member),
+    builtin("alist-ref", Capability::Pure, list::alist_ref),
+    builtin("range", Capability::Pure, list::range),
+    builtin("zip", Capability::Pure, list::zip),
+    builtin("any", Capability::Pure, list::any),
+    builtin("every", Capability::Pure, list::every),
+    builtin("fold-left", Capability::Pure, list::fold_left),
+    builtin("fold-right", Capability::Pure, list::fold_right),
+    builtin("sort", Capability::Pure, list::sort),
+    builtin("force", Capability::Pure, stream::force),
+    builtin("make-promise", Capability::Pure, stream::make_promise),
+    builtin("promise?", Capability::Pure, stream::is_promise),
+    builtin("stream", Capability::Pure, stream::stream),
+    builtin("stream?", Capability::Pure, stream::is_stream),
+    builtin("stream-null?", Capability::Pure, stream::is_stream_null),
+    builtin("stream-car", Capability::Pure, stream::stream_car),
+    builtin("stream-cdr", Capability::Pure, stream::stream_cdr),
+    builtin("stream-map", Capability::Pure, stream::stream_map),
+    builtin("stream-filter", Capability::Pure, stream::stream_filter),
+    builtin("stream-take", Capability::Pure, stream::stream_take),
+    builtin("stream->list", Capability::Pure, stream::stream_to_list),
+    builtin("iterate", Capability::Pure, stream::iterate),
+    builtin(
+        "persistent-vector",
+        Capability::Pure,
+        persistent::persistent_vector,
+    ),
+    builtin(
+        "persistent-map",
+        Capability::Pure,
+        persistent::persistent_map,
+    ),
+    builtin(
+        "persistent-vector?",
+        Capability::Pure,
+        persistent::is_persistent_vector,
+    ),
+    builtin(
+        "persistent-map?",
+        Capability::Pure,
+        persistent::is_persistent_map,
+    ),
+    builtin("conj", Capability::Pure, persistent::conj),
+    builtin("assoc", Capability::Pure, persistent::assoc),
+    builtin("dissoc", Capability::Pure, persistent::dissoc),
+    builtin("get", Capability::Pure, persistent::get),
+    builtin("count", Capability::Pure, persistent::count),
+    builtin("print", Capability::Console, print),
+    builtin("read-line", Capability::Console, read_line),
+    builtin("read-file", Capability::Filesystem, read_file),
+    builtin("write-file", Capability::Filesystem, write_file),
+    builtin("file-exists?", Capability::Filesystem, file_exists),
+    builtin("getenv", Capability::Process, getenv),
+    builtin("exit", Capability::Process, exit),
+    builtin("current-time", Capability::Time, current_time),
+    builtin("sleep", Capability::Time, sleep),
+    builtin("random", Capability::Random, random),
+    builtin("gc", Capability::Runtime, gc),
+    builtin("gc-stats", Capability::Runtime, gc_stats),
+];
+
+/// Binds the builtins of every group in `capabilities` into `env`.
+pub fn install(env: &mut Env, capabilities: &[Capability]) {
+    for builtin in BUILTINS {
+        if capabilities.contains(&builtin.capability) {
+            env.set(builtin.name, Object::Builtin(*builtin));
+        }
+    }
+}
+
+pub fn find(name: &str) -> Option<&'static Builtin> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+fn check_arity(name: &str, args: &[Object], arity: usize) -> Result<(), EvalError> {
+    if args.len() != arity {
+        return Err(format!(
+            "Invalid number of arguments for {}: expected {}, found {}",
+            name,
+            arity,
+            args.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn check_arity_range(name: &str, args: &[Object], min: usize, max: usize) -> Result<(), EvalError> {
+    if args.len() < min || args.len() > max {
+        return Err(format!(
+            "Invalid number of arguments for {}: expected {} to {}, found {}",
+            name,
+            min,
+            max,
+            args.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn check_min_arity(name: &str, args: &[Object], min: usize) -> Result<(), EvalError> {
+    if args.len() < min {
+        return Err(format!(
+            "Invalid number of arguments for {}: expected at least {}, found {}",
+            name,
+            min,
+            args.len()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+fn procedure_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a Object, EvalError> {
+    if eval::is_procedure(arg) {
+        Ok(arg)
+    } else {
+        Err(format!("{} expects a procedure but found {}", name, arg).into())
+    }
+}
+
+fn string_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a str, EvalError> {
+    match arg {
+        Object::String(s) => Ok(s),
+        _ => Err(format!("{} expects a string but found {}", name, arg).into()),
+    }
+}
+
+/// Splits the arguments of `(apply f a b (c d))` into `f` and `(a b c d)`.
+pub fn spread_args(args: Vec<Object>) -> Result<(Object, Vec<Object>), EvalError> {
+    let mut args = args.into_iter();
+    let func = match args.next() {
+        Some(func) => func,
+        None => return Err("apply expects a procedure and a list of arguments".into()),
+    };
+    let mut spread = args.collect::<Vec<_>>();
+    match spread.pop() {
+        Some(Object::ListData(last)) => spread.extend(last.iter().cloned()),
+        Some(last) => return Err(format!("apply expects a list but found {}", last).into()),
+        None => return Err("apply expects a procedure and a list of arguments".into()),
+    }
+    Ok((func, spread))
+}
+
+/// Splits the arguments of `(funcall f a b)` into `f` and `(a b)`.
+pub fn funcall_args(args: Vec<Object>) -> Result<(Object, Vec<Object>), EvalError> {
+    let mut args = args.into_iter();
+    match args.next() {
+        Some(func) => Ok((func, args.collect())),
+        None => Err("funcall expects a procedure".into()),
+    }
+}
+
+/// Calls the producer of `(call-with-values producer consumer)` and returns
+/// the consumer together with the values to pass to it.
+pub fn produce_values(
+    mut args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<(Object, Vec<Object>), EvalError> {
+    check_arity("call-with-values", &args, 2)?;
+    let consumer = args.pop().unwrap();
+    let values = eval::apply(&args[0], vec![], env)?;
+    Ok((consumer, values.into_values()))
+}
+
+fn apply(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let (func, args) = spread_args(args.to_vec())?;
+    eval::apply(&func, args, env)
+}
+
+fn funcall(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let (func, args) = funcall_args(args.to_vec())?;
+    eval::apply(&func, args, env)
+}
+
+fn values(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    env.borrow()
+        .runtime()
+        .allocate(Object::from_values(args.to_vec()), list_size(args.len()))
+}
+
+fn call_with_values(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let (func, args) = produce_values(args.to_vec(), env)?;
+    eval::apply(&func, args, env)
+}
+
+fn binary(op: &str, args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity(op, args, 2)?;
+    eval::binary_op(op, &args[0], &args[1], env)
+}
+
+fn add(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("+", args, env)
+}
+
+fn sub(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("-", args, env)
+}
+
+fn mul(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("*", args, env)
+}
+
+fn div(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("/", args, env)
+}
+
+fn rem(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("%", args, env)
+}
+
+fn lt(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("<", args, env)
+}
+
+fn gt(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary(">", args, env)
+}
+
+fn eq(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("=", args, env)
+}
+
+fn ne(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("!=", args, env)
+}
+
+fn and(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("&", args, env)
+}
+
+fn or(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    binary("|", args, env)
+}
+
+fn integer_arg(name: &str, arg: &Object) -> Result<i64, EvalError> {
+    match arg {
+        Object::Integer(n) => Ok(*n),
+        _ => Err(format!("{} expects an integer but found {}", name, arg).into()),
+    }
+}
+
+fn char_arg(name: &str, arg: &Object) -> Result<char, EvalError> {
+    match arg {
+        Object::Char(c) => Ok(*c),
+        _ => Err(format!("{} expects a character but found {}", name, arg).into()),
+    }
+}
+
+fn list_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a [Object], EvalError> {
+    match arg {
+        Object::ListData(list) => Ok(list),
+        _ => Err(format!("{} expects a list but found {}", name, arg).into()),
+    }
+}
+
+fn not(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("not", args, 1)?;
+    match &args[0] {
+        Object::Bool(b) => Ok(Object::Bool(!b)),
+        arg => Err(format!("not expects a boolean but found {}", arg).into()),
+    }
+}
+
+fn is_number(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("number?", args, 1)?;
+    Ok(Object::Bool(matches!(
+        args[0],
+        Object::Integer(_) | Object::Float(_)
+    )))
+}
+
+fn is_string(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::String(_))))
+}
+
+fn is_list(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("list?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::ListData(_))))
+}
+
+fn is_keyword(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("keyword?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::KeywordLiteral(_))))
+}
+
+fn is_procedure(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("procedure?", args, 1)?;
+    Ok(Object::Bool(eval::is_procedure(&args[0])))
+}
+
+fn is_char(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("char?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::Char(_))))
+}
+
+fn print(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    for obj in args {
+        print!("{} ", obj);
+    }
+    println!();
+    Ok(Object::Void)
+}
+
+fn read_line(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("read-line", args, 0)?;
+    let mut line = String::new();
+    std::io::stdin()
+        .lock()
+        .read_line(&mut line)
+        .map_err(|e| format!("read-line failed: {}", e))?;
+    Ok(Object::String(line.trim_end_matches(['\n', '\r']).into()))
+}
+
+fn read_file(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("read-file", args, 1)?;
+    let path = string_arg("read-file", &args[0])?;
+    let contents =
+        std::fs::read_to_string(path).map_err(|e| format!("Cannot read {}: {}", path, e))?;
+    let size = contents.len();
+    env.borrow()
+        .runtime()
+        .allocate(Object::String(contents.into()), size)
+}
+
+fn write_file(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("write-file", args, 2)?;
+    let path = string_arg("write-file", &args[0])?;
+    let contents = string_arg("write-file", &args[1])?;
+    std::fs::write(path, contents).map_err(|e| format!("Cannot write {}: {}", path, e))?;
+    Ok(Object::Void)
+}
+
+fn file_exists(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("file-exists?", args, 1)?;
+    let path = string_arg("file-exists?", &args[0])?;
+    Ok(Object::Bool(std::path::Path::new(path).exists()))
+}
+
+fn getenv(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("getenv", args, 1)?;
+    let name = string_arg("getenv", &args[0])?;
+    match std::env::var(name) {
+        Ok(val) => Ok(Object::String(val.into())),
+        Err(_) => Ok(Object::Void),
+    }
+}
+
+fn exit(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let code = match args {
+        [] => 0,
+        [Object::Integer(code)] => {
+            i32::try_from(*code).map_err(|_| format!("exit: exit code {} out of range", code))?
+        }
+        _ => return Err("exit expects an optional integer exit code".into()),
+    };
+    std::process::exit(code)
+}
+
+fn current_time(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("current-time", args, 0)?;
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|e| format!("current-time failed: {}", e))?;
+    Ok(Object::Float(now.as_secs_f64()))
+}
+
+fn sleep(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("sleep", args, 1)?;
+    match &args[0] {
+        Object::Integer(ms) if *ms >= 0 => {
+            std::thread::sleep(Duration::from_millis(*ms as u64));
+            Ok(Object::Void)
+        }
+        arg => Err(format!("sleep expects milliseconds but found {}", arg).into()),
+    }
+}
+
+thread_local! {
+    static RANDOM_STATE: Cell<u64> = Cell::new(random_seed());
+}
+
+fn random_seed() -> u64 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_nanos() as u64)
+        .unwrap_or(0);
+    // xorshift must not start from zero.
+    nanos | 1
+}
+
+/// xorshift64*, good enough for scripts and needs no extra dependency.
+fn next_random() -> u64 {
+    RANDOM_STATE.with(|state| {
+        let mut x = state.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        state.set(x);
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    })
+}
+
+/// A uniformly distributed integer in [0, bound). Draws above the largest
+/// multiple of `bound` are rejected, since reducing them would favour the
+/// smaller results.
+fn random_below(bound: u64) -> u64 {
+    let limit = u64::MAX - u64::MAX % bound;
+    loop {
+        let x = next_random();
+        if x < limit {
+            return x % bound;
+        }
+    }
+}
+
+/// `(random)` returns a float in [0, 1), `(random n)` an integer in [0, n).
+fn random(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    match args {
+        [] => Ok(Object::Float(
+            (next_random() >> 11) as f64 / (1u64 << 53) as f64,
+        )),
+        [Object::Integer(n)] if *n > 0 => Ok(Object::Integer(random_below(*n as u64) as i64)),
+        _ => Err("random expects no arguments or a positive integer".into()),
+    }
+}
+
+/// `(gc)` frees the procedures and containers that only refer to each
+/// other and returns how many objects that was.
+fn gc(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("gc", args, 0)?;
+    let freed = env.borrow().runtime().collect_garbage();
+    Ok(Object::Integer(freed as i64))
+}
+
+/// `(gc-stats)` returns the counts of live objects and collections as a
+/// hash table, e.g. `(hash-ref (gc-stats) :closures)`.
+fn gc_stats(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("gc-stats", args, 0)?;
+    let stats = env.borrow().runtime().gc_stats();
+    let mut table = HashTable::new();
+    for (name, count) in [
+        ("frames", stats.frames),
+        ("closures", stats.closures),
+        ("promises", stats.promises),
+        ("containers", stats.containers),
+        ("collections", stats.collections),
+        ("freed", stats.freed),
+    ] {
+        table.insert(
+            HashKey::KeywordLiteral(name.to_string()),
+            Object::Integer(count as i64),
+        );
+    }
+    hash::new_table(table, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::eval;
+
+    #[test]
+    fn test_missing_capability() {
+        let mut env = Rc::new(RefCell::new(Env::with_capabilities(&[Capability::Pure])));
+        let err = eval("(read-file \"/etc/hostname\")", &mut env).unwrap_err();
+        assert_eq!(
+            err,
+            EvalError::MissingCapability(Capability::Filesystem, "read-file".to_string())
+        );
+
+        let err = eval("print", &mut env).unwrap_err();
+        assert_eq!(
+            err,
+            EvalError::MissingCapability(Capability::Console, "print".to_string())
+        );
+
+        let err = eval("(gc)", &mut env).unwrap_err();
+        assert_eq!(
+            err,
+            EvalError::MissingCapability(Capability::Runtime, "gc".to_string())
+        );
+
+        let result = eval("(not false)", &mut env).unwrap();
+        assert_eq!(result, Object::Bool(true));
+    }
+
+    #[test]
+    fn test_filesystem() {
+        let mut env = Rc::new(RefCell::new(Env::with_capabilities(&[
+            Capability::Pure,
+            Capability::Filesystem,
+        ])));
+        let path = std::env::temp_dir().join(format!("risp-test-{}", std::process::id()));
+        let program = format!(
+            "( (write-file \"{0}\" \"hello\") (file-exists? \"{0}\") (read-file \"{0}\") )",
+            path.display()
+        );
+        let result = eval(&program, &mut env).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Bool(true),
+                Object::String("hello".into())
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_exit_code_out_of_range() {
+        let mut env = Rc::new(RefCell::new(Env::with_capabilities(&[Capability::Process])));
+        let err = eval("(exit 4294967296)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "exit: exit code 4294967296 out of range");
+    }
+
+    #[test]
+    fn test_random() {
+        let mut env = Rc::new(RefCell::new(Env::with_capabilities(&[Capability::Random])));
+        for _ in 0..100 {
+            match eval("(random 10)", &mut env).unwrap() {
+                Object::Integer(n) => assert!((0..10).contains(&n)),
+                obj => panic!("Expected an integer but got {}", obj),
+            }
+        }
+        for bound in [1, 3, i64::MAX] {
+            match eval(&format!("(random {})", bound), &mut env).unwrap() {
+                Object::Integer(n) => assert!((0..bound).contains(&n)),
+                obj => panic!("Expected an integer but got {}", obj),
+            }
+        }
+    }
+
+    #[test]
+    fn test_apply_and_funcall() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define make-adder (lambda (a) (lambda (b) (+ a b))))
+            ((make-adder 1) 2)
+            ((lambda (x) (* x x)) 5)
+            (apply + (list 1 2))
+            (apply (make-adder 10) (list 5))
+            (apply list? 1 (list))
+            (apply (lambda (a . rest) rest) 1 2 (list 3))
+            (funcall make-adder 3)
+            (funcall (funcall make-adder 3) 4)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result.to_string(),
+            "(3 25 3 15 false (2 3) Lambda(b) (+ a b) 7)"
+        );
+    }
+
+    #[test]
+    fn test_apply_in_tail_position() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define count (lambda (n) (if (= n 0) 0 (apply count (list (- n 1))))))
+            (count 100000)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(0)])));
+    }
+}
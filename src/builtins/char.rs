@@ -0,0 +1,105 @@
+//! Builtins on `Object::Char`.
+
+use super::{char_arg, check_arity, integer_arg};
+use crate::{
+    env::Env,
+    eval::EvalError,
+    object::Object,
+    sync::{Rc, RefCell},
+};
+
+pub fn char_to_integer(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("char->integer", args, 1)?;
+    let c = char_arg("char->integer", &args[0])?;
+    Ok(Object::Integer(c as i64))
+}
+
+pub fn integer_to_char(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("integer->char", args, 1)?;
+    let n = integer_arg("integer->char", &args[0])?;
+    match u32::try_from(n).ok().and_then(char::from_u32) {
+        Some(c) => Ok(Object::Char(c)),
+        None => Err(format!("integer->char: {} is not a valid character code", n).into()),
+    }
+}
+
+/// Case mappings that would turn one character into several, like `ß`, leave
+/// the character unchanged.
+fn single_char(mut mapped: impl Iterator<Item = char>, c: char) -> char {
+    match (mapped.next(), mapped.next()) {
+        (Some(mapped), None) => mapped,
+        _ => c,
+    }
+}
+
+pub fn char_upcase(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("char-upcase", args, 1)?;
+    let c = char_arg("char-upcase", &args[0])?;
+    Ok(Object::Char(single_char(c.to_uppercase(), c)))
+}
+
+pub fn char_downcase(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("char-downcase", args, 1)?;
+    let c = char_arg("char-downcase", &args[0])?;
+    Ok(Object::Char(single_char(c.to_lowercase(), c)))
+}
+
+pub fn is_char_alphabetic(
+    args: &[Object],
+    _env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    check_arity("char-alphabetic?", args, 1)?;
+    Ok(Object::Bool(
+        char_arg("char-alphabetic?", &args[0])?.is_alphabetic(),
+    ))
+}
+
+pub fn is_char_numeric(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("char-numeric?", args, 1)?;
+    Ok(Object::Bool(
+        char_arg("char-numeric?", &args[0])?.is_numeric(),
+    ))
+}
+
+pub fn is_char_whitespace(
+    args: &[Object],
+    _env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    check_arity("char-whitespace?", args, 1)?;
+    Ok(Object::Bool(
+        char_arg("char-whitespace?", &args[0])?.is_whitespace(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::object::Object;
+    use crate::sync::{Rc, RefCell};
+
+    #[test]
+    fn test_chars() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (char->integer #\\A)
+            (integer->char 955)
+            (char-upcase #\\a)
+            (char-alphabetic? #\\space)
+            (= #\\a #\\a)
+            (< #\\a #\\b)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Integer(65),
+                Object::Char('λ'),
+                Object::Char('A'),
+                Object::Bool(false),
+                Object::Bool(true),
+                Object::Bool(true),
+            ]))
+        );
+    }
+}
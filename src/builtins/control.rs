@@ -0,0 +1,254 @@
+use super::{check_arity, check_arity_range, procedure_arg};
+use crate::{
+    continuation::Continuation,
+    env::Env,
+    eval::{self, EvalError},
+    generator::{self, Generator},
+    object::Object,
+    sync::{Rc, RefCell},
+};
+
+/// `(call/cc f)` calls `f` with an escape continuation. Invoking it while
+/// `f` is running makes `call/cc` return the value passed to it.
+pub fn call_cc(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("call/cc", args, 1)?;
+    let func = procedure_arg("call/cc", &args[0])?;
+    let k = Continuation::new();
+    let result = eval::apply(func, vec![Object::Continuation(k.clone())], env);
+    k.end();
+    match result {
+        Err(EvalError::Escape(target, value)) if Rc::ptr_eq(&target, &k) => Ok(*value),
+        result => result,
+    }
+}
+
+/// `(dynamic-wind before thunk after)` calls the three thunks in order and
+/// returns the result of `thunk`. `after` also runs when `thunk` is left by
+/// a continuation or an error.
+pub fn dynamic_wind(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("dynamic-wind", args, 3)?;
+    let before = procedure_arg("dynamic-wind", &args[0])?;
+    let thunk = procedure_arg("dynamic-wind", &args[1])?;
+    let after = procedure_arg("dynamic-wind", &args[2])?;
+    eval::apply(before, vec![], env)?;
+    let result = eval::apply(thunk, vec![], env);
+    eval::apply(after, vec![], env)?;
+    result
+}
+
+fn generator_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a Generator, EvalError> {
+    match arg {
+        Object::Generator(generator) => Ok(generator),
+        _ => Err(format!("{} expects a generator but found {}", name, arg).into()),
+    }
+}
+
+/// `(make-generator thunk)` returns a generator that runs `thunk` up to its
+/// first `yield` when it is first resumed.
+pub fn make_generator(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("make-generator", args, 1)?;
+    let thunk = procedure_arg("make-generator", &args[0])?;
+    Ok(Object::Generator(Rc::new(Generator::new(
+        thunk.clone(),
+        env,
+    )?)))
+}
+
+/// `(yield value)` suspends the generator it is called from. The generator
+/// gets `value` to its caller and `yield` returns the value it is resumed
+/// with.
+pub fn yield_(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("yield", args, 0, 1)?;
+    generator::yield_value(args.first().cloned().unwrap_or(Object::Void))
+}
+
+pub fn is_generator(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("generator?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::Generator(_))))
+}
+
+/// `(generator-next gen)` or `(generator-next gen value)` resumes `gen` and
+/// returns the next value it yields, or nil once it has finished.
+pub fn generator_next(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("generator-next", args, 1, 2)?;
+    let generator = generator_arg("generator-next", &args[0])?;
+    let value = args.get(1).cloned().unwrap_or(Object::Void);
+    Ok(generator.resume(value)?.unwrap_or(Object::Void))
+}
+
+pub fn is_generator_done(
+    args: &[Object],
+    _env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    check_arity("generator-done?", args, 1)?;
+    let generator = generator_arg("generator-done?", &args[0])?;
+    Ok(Object::Bool(generator.is_done()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::sync::{Rc, RefCell};
+
+    fn run(program: &str) -> String {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval(program, &mut env).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_call_cc_escapes() {
+        let result = run("(
+            (define find-first (lambda (pred items)
+                (call/cc (lambda (return)
+                    (begin
+                        (for-each (lambda (x) (if (pred x) (return x) nil)) items)
+                        false)))))
+            (find-first (lambda (x) (> x 2)) (list 1 2 3 4))
+            (find-first (lambda (x) (> x 9)) (list 1 2 3 4))
+            (call-with-current-continuation (lambda (k) 1))
+            (+ 1 (call/cc (lambda (k) (+ 10 (k 2)))))
+            (call/cc (lambda (outer) (+ 1 (call/cc (lambda (inner) (outer 5))))))
+        )");
+        assert_eq!(result, "(3 false 1 3 5)");
+    }
+
+    #[test]
+    fn test_dynamic_wind_runs_after_on_escape() {
+        let result = run("(
+            (define log (hash))
+            (define note (lambda (x) (hash-set! log (hash-count log) x)))
+            (call/cc (lambda (k)
+                (dynamic-wind
+                    (lambda () (note :before))
+                    (lambda () (begin (note :during) (k :escaped) (note :unreachable)))
+                    (lambda () (note :after)))))
+            (dynamic-wind (lambda () nil) (lambda () :normal) (lambda () (note :done)))
+            (hash-values log)
+        )");
+        assert_eq!(result, "(:escaped :normal (:before :during :after :done))");
+    }
+
+    #[test]
+    fn test_continuation_errors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval("(define saved (call/cc (lambda (k) k)))", &mut env).unwrap();
+        let err = eval("(saved 1)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Continuation called after its call/cc returned: only escaping continuations are supported"
+        );
+        let result = eval("(procedure? saved)", &mut env).unwrap();
+        assert_eq!(result.to_string(), "true");
+    }
+
+    #[test]
+    #[cfg(feature = "sync")]
+    fn test_generators_are_not_available() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(make-generator (lambda () (yield 1)))", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Generators are not available with the sync feature"
+        );
+    }
+
+    #[test]
+    #[cfg(not(feature = "sync"))]
+    fn test_generators() {
+        let result = run("(
+            (define g (make-generator (lambda () (begin (yield 1) (yield 2) 3))))
+            (generator? g)
+            (generator-next g)
+            (generator-done? g)
+            (generator-next g)
+            (nil? (generator-next g))
+            (generator-done? g)
+            (define echo (make-generator (lambda () (yield (+ 1 (yield 0))))))
+            (generator-next echo)
+            (generator-next echo 5)
+        )");
+        assert_eq!(result, "(true 1 false 2 true true 0 6)");
+    }
+
+    #[test]
+    #[cfg(not(feature = "sync"))]
+    fn test_yield_from_nested_calls() {
+        let result = run("(
+            (define walk (lambda (tree)
+                (if (list? tree) (for-each walk tree) (yield tree))))
+            (define leaves (lambda (tree) (make-generator (lambda () (walk tree)))))
+            (map (lambda (x) (* x 10)) (leaves (list 1 (list 2 (list 3)) 4)))
+            (define naturals (make-generator (lambda ()
+                (define loop (lambda (n) (begin (yield n) (loop (+ n 1)))))
+                (loop 0))))
+            (map + (list 10 20) naturals)
+        )");
+        assert_eq!(result, "((10 20 30 40) (10 21))");
+    }
+
+    #[test]
+    #[cfg(not(feature = "sync"))]
+    fn test_generator_errors_and_depth() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(yield 1)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "yield called outside of a generator");
+        let err = eval(
+            "(map (lambda (x) x) (make-generator (lambda () (begin (yield 1) (car 1)))))",
+            &mut env,
+        )
+        .unwrap_err();
+        assert_eq!(err.to_string(), "Unbound function: car");
+
+        // The frames of a suspended body are only counted while it runs.
+        eval(
+            "(
+                (define deep (lambda (n) (if (= n 0) (yield :bottom) (+ 1 (deep (- n 1))))))
+                (define g (make-generator (lambda () (deep 50))))
+                (generator-next g)
+            )",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(env.borrow().runtime().depth(), 0);
+        let result = eval("(generator-next g 1)", &mut env).unwrap();
+        assert_eq!(result, crate::object::Object::Void);
+        assert_eq!(env.borrow().runtime().depth(), 0);
+        eval(
+            "(define g (make-generator (lambda () (deep 50))))",
+            &mut env,
+        )
+        .unwrap();
+        eval("((generator-next g) (define g nil))", &mut env).unwrap();
+        assert_eq!(env.borrow().runtime().depth(), 0);
+    }
+
+    #[test]
+    #[cfg(not(feature = "sync"))]
+    fn test_many_generators_with_a_deep_limit() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        env.borrow().runtime().set_max_depth(20_000);
+        let result = eval(
+            "(
+                (define count (lambda (n) (make-generator (lambda () (begin (yield n) n)))))
+                (define gens (map count (range 0 1000)))
+                (reduce + 0 (map generator-next gens))
+            )",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(result.to_string(), "(499500)");
+
+        // A body deeper than its stack allows fails instead of overflowing.
+        let err = eval(
+            "(
+                (define deep (lambda (n) (+ 1 (deep n))))
+                (generator-next (make-generator (lambda () (deep 0))))
+            )",
+            &mut env,
+        )
+        .unwrap_err();
+        assert!(matches!(err, crate::eval::EvalError::StackOverflow(_)));
+        assert_eq!(env.borrow().runtime().depth(), 0);
+    }
+}
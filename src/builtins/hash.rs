@@ -0,0 +1,249 @@
+use super::{check_arity, check_arity_range};
+use crate::{
+    env::Env,
+    eval::{self, EvalError},
+    hash_table::{HashKey, HashTable},
+    object::Object,
+    runtime::list_size,
+    sync::{Rc, RefCell},
+};
+
+/// Approximate heap size of one entry.
+fn entry_size() -> usize {
+    list_size(2)
+}
+
+fn table_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a Rc<RefCell<HashTable>>, EvalError> {
+    match arg {
+        Object::HashTable(table) => Ok(table),
+        _ => Err(format!("{} expects a hash table but found {}", name, arg).into()),
+    }
+}
+
+fn key_arg(name: &str, arg: &Object) -> Result<HashKey, EvalError> {
+    match HashKey::from_object(arg) {
+        Some(key) => Ok(key),
+        None => Err(format!("{}: unhashable key {}", name, arg).into()),
+    }
+}
+
+/// `(hash key value ...)` builds a table from evaluated keys and values.
+pub fn hash(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    if !args.len().is_multiple_of(2) {
+        return Err("hash expects an even number of arguments".into());
+    }
+    let mut table = HashTable::new();
+    for pair in args.chunks(2) {
+        table.insert(key_arg("hash", &pair[0])?, pair[1].clone());
+    }
+    new_table(table, env)
+}
+
+/// Wraps a table that was just built and accounts for its entries.
+pub(crate) fn new_table(table: HashTable, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let size = entry_size() * table.len();
+    let table = Object::HashTable(Rc::new(RefCell::new(table)));
+    env.borrow().runtime().allocate(table, size)
+}
+
+pub fn is_hash(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::HashTable(_))))
+}
+
+pub fn hash_count(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-count", args, 1)?;
+    let table = table_arg("hash-count", &args[0])?;
+    Ok(Object::Integer(table.borrow().len() as i64))
+}
+
+/// `(hash-ref table key)` fails when the key is missing,
+/// `(hash-ref table key default)` returns the default instead.
+pub fn hash_ref(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("hash-ref", args, 2, 3)?;
+    let table = table_arg("hash-ref", &args[0])?.borrow();
+    let key = key_arg("hash-ref", &args[1])?;
+    match (table.get(&key), args.get(2)) {
+        (Some(value), _) => Ok(value.clone()),
+        (None, Some(default)) => Ok(default.clone()),
+        (None, None) => Err(format!("hash-ref: key not found: {}", args[1]).into()),
+    }
+}
+
+pub fn hash_contains(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-contains?", args, 2)?;
+    let table = table_arg("hash-contains?", &args[0])?.borrow();
+    let key = key_arg("hash-contains?", &args[1])?;
+    Ok(Object::Bool(table.get(&key).is_some()))
+}
+
+pub fn hash_set(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-set!", args, 3)?;
+    let key = key_arg("hash-set!", &args[1])?;
+    let mut table = table_arg("hash-set!", &args[0])?.borrow_mut();
+    let added = table.insert(key, args[2].clone()).is_none();
+    drop(table);
+    let runtime = env.borrow().runtime();
+    runtime.watch_container(&args[0], &args[2]);
+    if added {
+        runtime.charge(entry_size())?;
+        runtime.hold(&args[0], entry_size());
+    }
+    Ok(Object::Void)
+}
+
+pub fn hash_remove(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-remove!", args, 2)?;
+    let key = key_arg("hash-remove!", &args[1])?;
+    let mut table = table_arg("hash-remove!", &args[0])?.borrow_mut();
+    if table.remove(&key).is_some() {
+        env.borrow().runtime().shrink(&args[0], entry_size());
+    }
+    Ok(Object::Void)
+}
+
+pub fn hash_keys(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-keys", args, 1)?;
+    let table = table_arg("hash-keys", &args[0])?.borrow();
+    let keys = table
+        .iter()
+        .map(|(key, _)| key.to_object())
+        .collect::<Vec<_>>();
+    let size = list_size(keys.len());
+    env.borrow()
+        .runtime()
+        .allocate(Object::ListData(keys.into()), size)
+}
+
+pub fn hash_values(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-values", args, 1)?;
+    let table = table_arg("hash-values", &args[0])?.borrow();
+    let values = table
+        .iter()
+        .map(|(_, value)| value.clone())
+        .collect::<Vec<_>>();
+    let size = list_size(values.len());
+    env.borrow()
+        .runtime()
+        .allocate(Object::ListData(values.into()), size)
+}
+
+/// Lists the entries as `(key value)` pairs.
+pub fn hash_to_list(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash->list", args, 1)?;
+    let table = table_arg("hash->list", &args[0])?.borrow();
+    let runtime = env.borrow().runtime();
+    runtime.charge(list_size(table.len()) + entry_size() * table.len())?;
+    let entries = table
+        .iter()
+        .map(|(key, value)| {
+            let entry = Object::ListData(Rc::new(vec![key.to_object(), value.clone()]));
+            runtime.hold(&entry, entry_size());
+            entry
+        })
+        .collect::<Vec<_>>();
+    let entries = Object::ListData(entries.into());
+    runtime.hold(&entries, list_size(table.len()));
+    Ok(entries)
+}
+
+/// `(hash-for-each table f)` calls `(f key value)` for every entry.
+pub fn hash_for_each(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("hash-for-each", args, 2)?;
+    if !eval::is_procedure(&args[1]) {
+        return Err(format!("hash-for-each expects a procedure but found {}", args[1]).into());
+    }
+    // The procedure may modify the table, so iterate over a snapshot.
+    let entries = table_arg("hash-for-each", &args[0])?
+        .borrow()
+        .iter()
+        .map(|(key, value)| (key.to_object(), value.clone()))
+        .collect::<Vec<_>>();
+    for (key, value) in entries {
+        eval::apply(&args[1], vec![key, value], env)?;
+    }
+    Ok(Object::Void)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::object::Object;
+    use crate::sync::{Rc, RefCell};
+
+    #[test]
+    fn test_hash_tables() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define h {:a 1 :b 2})
+            (hash-set! h \"c\" 3)
+            (hash-set! h :a 10)
+            (hash-remove! h :b)
+            (hash-ref h :a)
+            (hash-ref h :b 0)
+            (hash-contains? h \"c\")
+            (hash-count h)
+            (hash-keys h)
+            (hash-values h)
+            (hash->list (hash (list 1 2) #\\x))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Integer(10),
+                Object::Integer(0),
+                Object::Bool(true),
+                Object::Integer(2),
+                Object::ListData(Rc::new(vec![
+                    Object::KeywordLiteral("a".to_string()),
+                    Object::String("c".into()),
+                ])),
+                Object::ListData(Rc::new(vec![Object::Integer(10), Object::Integer(3)])),
+                Object::ListData(Rc::new(vec![Object::ListData(Rc::new(vec![
+                    Object::ListData(Rc::new(vec![Object::Integer(1), Object::Integer(2)])),
+                    Object::Char('x'),
+                ]))])),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_hash_for_each_and_sharing() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define counts (hash))
+            (define copy counts)
+            (define sum {:x 0})
+            (hash-set! copy :one 1)
+            (hash-set! copy :two 2)
+            (hash-for-each counts (lambda (k v) (hash-set! sum :x (+ v (hash-ref sum :x)))))
+            (hash-ref sum :x)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(3)])));
+    }
+
+    #[test]
+    fn test_hash_literal_values_are_evaluated() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (let ((x 1)) {:a x :b (+ 1 2) :c {:d x}})
+            (define fresh (lambda () {:n 0}))
+            (hash-set! (fresh) :n 1)
+            (hash-ref (fresh) :n)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result.to_string(), "({:a 1 :b 3 :c {:d 1}} 0)");
+    }
+
+    #[test]
+    fn test_hash_errors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(hash-ref {:a 1} :b)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "hash-ref: key not found: :b");
+        let err = eval("(hash-set! {} #(1) 1)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "hash-set!: unhashable key #(1)");
+    }
+}
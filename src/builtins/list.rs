@@ -0,0 +1,401 @@
+//! Builtins on `Object::ListData`.
+//!
+//! Argument order follows one rule: a procedure argument comes first, as in
+//! `map`, `filter` and `reduce`, and otherwise the list comes first, as in
+//! `(nth list n)` or `(member list x)`.
+
+use super::{check_arity, check_arity_range, integer_arg, list_arg, procedure_arg};
+use crate::{
+    env::Env,
+    eval::{self, EvalError},
+    object::Object,
+    runtime::list_size,
+    sync::{Rc, RefCell},
+};
+
+fn new_list(list: Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let size = list_size(list.len());
+    env.borrow()
+        .runtime()
+        .allocate(Object::ListData(list.into()), size)
+}
+
+fn count_arg(name: &str, arg: &Object) -> Result<usize, EvalError> {
+    let n = integer_arg(name, arg)?;
+    usize::try_from(n).map_err(|_| format!("{}: invalid count {}", name, n).into())
+}
+
+/// Calls a predicate and insists on a boolean result.
+fn test(
+    name: &str,
+    pred: &Object,
+    args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<bool, EvalError> {
+    match eval::apply(pred, args, env)? {
+        Object::Bool(b) => Ok(b),
+        result => Err(format!(
+            "{}: predicate returned {} instead of a boolean",
+            name, result
+        )
+        .into()),
+    }
+}
+
+pub fn length(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("length", args, 1)?;
+    let list = list_arg("length", &args[0])?;
+    Ok(Object::Integer(list.len() as i64))
+}
+
+pub fn is_empty(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("empty?", args, 1)?;
+    let list = list_arg("empty?", &args[0])?;
+    Ok(Object::Bool(list.is_empty()))
+}
+
+pub fn is_nil(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("nil?", args, 1)?;
+    Ok(Object::Bool(args[0] == Object::Void))
+}
+
+pub fn append(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let mut result = Vec::new();
+    for arg in args {
+        result.extend_from_slice(list_arg("append", arg)?);
+    }
+    new_list(result, env)
+}
+
+pub fn reverse(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("reverse", args, 1)?;
+    let list = list_arg("reverse", &args[0])?;
+    new_list(list.iter().rev().cloned().collect(), env)
+}
+
+pub fn nth(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("nth", args, 2)?;
+    let list = list_arg("nth", &args[0])?;
+    let index = integer_arg("nth", &args[1])?;
+    match usize::try_from(index).ok().and_then(|i| list.get(i)) {
+        Some(item) => Ok(item.clone()),
+        None => Err(format!(
+            "nth: index {} out of range for length {}",
+            index,
+            list.len()
+        )
+        .into()),
+    }
+}
+
+pub fn first(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("first", args, 1)?;
+    match list_arg("first", &args[0])?.first() {
+        Some(item) => Ok(item.clone()),
+        None => Err("first: empty list".into()),
+    }
+}
+
+pub fn rest(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("rest", args, 1)?;
+    match list_arg("rest", &args[0])?.split_first() {
+        Some((_, rest)) => new_list(rest.to_vec(), env),
+        None => Err("rest: empty list".into()),
+    }
+}
+
+/// The first `n` elements, or the whole list if it is shorter.
+pub fn take(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("take", args, 2)?;
+    let list = list_arg("take", &args[0])?;
+    let n = count_arg("take", &args[1])?;
+    new_list(list.iter().take(n).cloned().collect(), env)
+}
+
+/// All but the first `n` elements, or nothing if the list is shorter.
+pub fn drop(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("drop", args, 2)?;
+    let list = list_arg("drop", &args[0])?;
+    let n = count_arg("drop", &args[1])?;
+    new_list(list.iter().skip(n).cloned().collect(), env)
+}
+
+/// `(member list x)`: the rest of the list from the first element equal to
+/// `x`, or nil if there is none.
+pub fn member(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("member", args, 2)?;
+    let list = list_arg("member", &args[0])?;
+    match list.iter().position(|x| *x == args[1]) {
+        Some(i) => new_list(list[i..].to_vec(), env),
+        None => Ok(Object::Void),
+    }
+}
+
+/// `(alist-ref alist key)`: the first `(key value ...)` entry of an
+/// association list whose key equals `key`, or nil.
+///
+/// This lookup shipped as `assoc` and was renamed when `assoc` came to mean
+/// only updating a persistent collection, see `persistent::assoc`.
+pub fn alist_ref(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("alist-ref", args, 2)?;
+    let list = list_arg("alist-ref", &args[0])?;
+    for entry in list {
+        match entry {
+            Object::ListData(pair) if !pair.is_empty() => {
+                if pair[0] == args[1] {
+                    return Ok(entry.clone());
+                }
+            }
+            _ => {
+                return Err(format!("alist-ref expects a list of lists but found {}", entry).into())
+            }
+        }
+    }
+    Ok(Object::Void)
+}
+
+/// `(range end)`, `(range start end)` or `(range start end step)`, counting
+/// up to but not including `end`. `(range)` is the infinite stream of
+/// integers from 0.
+pub fn range(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("range", args, 0, 3)?;
+    if args.is_empty() {
+        return Ok(super::stream::count_from(0));
+    }
+    let numbers = args
+        .iter()
+        .map(|arg| integer_arg("range", arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let (start, end, step) = match numbers[..] {
+        [end] => (0, end, 1),
+        [start, end] => (start, end, 1),
+        [start, end, step] => (start, end, step),
+        _ => unreachable!(),
+    };
+    if step == 0 {
+        return Err("range: step must not be zero".into());
+    }
+    let len = if (step > 0 && start < end) || (step < 0 && start > end) {
+        ((end as i128 - start as i128).unsigned_abs() - 1) / step.unsigned_abs() as u128 + 1
+    } else {
+        0
+    };
+    // Charge before building the list so that a huge range fails cleanly.
+    let size = list_size(usize::try_from(len).unwrap_or(usize::MAX));
+    let runtime = env.borrow().runtime();
+    runtime.charge(size)?;
+    let mut result = Vec::with_capacity(len as usize);
+    let mut n = start;
+    for _ in 0..len {
+        result.push(Object::Integer(n));
+        n = n.wrapping_add(step);
+    }
+    let result = Object::ListData(result.into());
+    runtime.hold(&result, size);
+    Ok(result)
+}
+
+/// `(zip list ...)`: lists of the elements at each position, as long as the
+/// shortest list.
+pub fn zip(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let lists = args
+        .iter()
+        .map(|arg| list_arg("zip", arg))
+        .collect::<Result<Vec<_>, _>>()?;
+    let len = lists.iter().map(|list| list.len()).min().unwrap_or(0);
+    let runtime = env.borrow().runtime();
+    runtime.charge(list_size(len) + list_size(len * lists.len()))?;
+    let zipped = (0..len)
+        .map(|i| {
+            let tuple = Object::ListData(
+                lists
+                    .iter()
+                    .map(|list| list[i].clone())
+                    .collect::<Vec<_>>()
+                    .into(),
+            );
+            runtime.hold(&tuple, list_size(lists.len()));
+            tuple
+        })
+        .collect::<Vec<_>>();
+    let zipped = Object::ListData(zipped.into());
+    runtime.hold(&zipped, list_size(len));
+    Ok(zipped)
+}
+
+/// `(any pred list)`: whether `pred` holds for some element.
+pub fn any(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("any", args, 2)?;
+    let pred = procedure_arg("any", &args[0])?;
+    for item in list_arg("any", &args[1])? {
+        if test("any", pred, vec![item.clone()], env)? {
+            return Ok(Object::Bool(true));
+        }
+    }
+    Ok(Object::Bool(false))
+}
+
+/// `(every pred list)`: whether `pred` holds for all elements.
+pub fn every(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("every", args, 2)?;
+    let pred = procedure_arg("every", &args[0])?;
+    for item in list_arg("every", &args[1])? {
+        if !test("every", pred, vec![item.clone()], env)? {
+            return Ok(Object::Bool(false));
+        }
+    }
+    Ok(Object::Bool(true))
+}
+
+/// `(fold-left f init list)` computes `(f (f init x1) x2) ...`.
+pub fn fold_left(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("fold-left", args, 3)?;
+    let func = procedure_arg("fold-left", &args[0])?;
+    let mut acc = args[1].clone();
+    for item in list_arg("fold-left", &args[2])? {
+        acc = eval::apply(func, vec![acc, item.clone()], env)?;
+    }
+    Ok(acc)
+}
+
+/// `(fold-right f init list)` computes `(f x1 (f x2 ... (f xn init)))`.
+pub fn fold_right(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("fold-right", args, 3)?;
+    let func = procedure_arg("fold-right", &args[0])?;
+    let mut acc = args[1].clone();
+    for item in list_arg("fold-right", &args[2])?.iter().rev() {
+        acc = eval::apply(func, vec![item.clone(), acc], env)?;
+    }
+    Ok(acc)
+}
+
+/// `(sort less? list)`: a stable merge sort, where `(less? a b)` says
+/// whether `a` goes before `b`.
+pub fn sort(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("sort", args, 2)?;
+    let less = procedure_arg("sort", &args[0])?;
+    let list = list_arg("sort", &args[1])?.to_vec();
+    let size = list_size(list.len());
+    let sorted = Object::ListData(merge_sort(list, less, env)?.into());
+    env.borrow().runtime().allocate(sorted, size)
+}
+
+fn merge_sort(
+    mut list: Vec<Object>,
+    less: &Object,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Vec<Object>, EvalError> {
+    if list.len() <= 1 {
+        return Ok(list);
+    }
+    let right = list.split_off(list.len() / 2);
+    let mut left = merge_sort(list, less, env)?.into_iter().peekable();
+    let mut right = merge_sort(right, less, env)?.into_iter().peekable();
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    while let (Some(l), Some(r)) = (left.peek(), right.peek()) {
+        // Taking from the right only when it is strictly less keeps the
+        // sort stable.
+        if test("sort", less, vec![r.clone(), l.clone()], env)? {
+            merged.push(right.next().unwrap());
+        } else {
+            merged.push(left.next().unwrap());
+        }
+    }
+    merged.extend(left);
+    merged.extend(right);
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::sync::{Rc, RefCell};
+
+    fn run(program: &str) -> String {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval(program, &mut env).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_list_access() {
+        let result = run("(
+            (define l (list 1 2 3 4 5))
+            (length l)
+            (nth l 1)
+            (first l)
+            (rest l)
+            (take l 2)
+            (drop l 3)
+            (take l 9)
+            (drop l 9)
+            (empty? (list))
+            (nil? (alist-ref (list (list :a 1)) :b))
+            (nil? (member l 6))
+        )");
+        assert_eq!(
+            result,
+            "(5 2 1 (2 3 4 5) (1 2) (4 5) (1 2 3 4 5) () true true true)"
+        );
+    }
+
+    #[test]
+    fn test_list_building() {
+        let result = run("(
+            (append (list 1) (list) (list 2 3))
+            (reverse (list 1 2 3))
+            (range 4)
+            (range 2 5)
+            (range 10 0 -3)
+            (range 3 3)
+            (zip (list 1 2 3) (list :a :b))
+            (member (list 1 (list 2) 3) (list 2))
+            (alist-ref (list (list :a 1) (list :b 2)) :b)
+        )");
+        assert_eq!(
+            result,
+            "((1 2 3) (3 2 1) (0 1 2 3) (2 3 4) (10 7 4 1) () ((1 :a) (2 :b)) ((2) 3) (:b 2))"
+        );
+    }
+
+    #[test]
+    fn test_higher_order() {
+        let result = run("(
+            (define total (make-vector 1 0))
+            (for-each (lambda (x) (vector-set! total 0 (+ x (vector-ref total 0)))) (list 1 2 3))
+            (vector-ref total 0)
+            (any (lambda (x) (> x 2)) (list 1 2 3))
+            (every (lambda (x) (> x 2)) (list 1 2 3))
+            (fold-left (lambda (acc x) (- acc x)) 10 (list 1 2 3))
+            (fold-right (lambda (x acc) (- x acc)) 0 (list 1 2 3))
+            (sort (lambda (a b) (< a b)) (list 3 1 2 5 4))
+            (sort (lambda (a b) (< (first a) (first b)))
+                  (list (list 2 :x) (list 1 :y) (list 2 :z) (list 1 :w)))
+        )");
+        assert_eq!(
+            result,
+            "(6 true false 4 2 (1 2 3 4 5) ((1 :y) (1 :w) (2 :x) (2 :z)))"
+        );
+    }
+
+    #[test]
+    fn test_list_errors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let cases = [
+            ("(nth (list 1) 1)", "nth: index 1 out of range for length 1"),
+            ("(first (list))", "first: empty list"),
+            ("(take (list 1) -1)", "take: invalid count -1"),
+            ("(range 0 1 0)", "range: step must not be zero"),
+            ("(sort 1 (list))", "sort expects a procedure but found 1"),
+            (
+                "(any (lambda (x) x) (list 1))",
+                "any: predicate returned 1 instead of a boolean",
+            ),
+            ("(length 1)", "length expects a list but found 1"),
+        ];
+        for (program, message) in cases {
+            let err = eval(program, &mut env).unwrap_err();
+            assert_eq!(err.to_string(), message);
+        }
+    }
+}
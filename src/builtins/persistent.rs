@@ -0,0 +1,240 @@
+use super::{check_arity, check_arity_range, integer_arg};
+use crate::{
+    env::Env,
+    eval::EvalError,
+    hash_table::HashKey,
+    object::Object,
+    persistent::PersistentMap,
+    runtime::list_size,
+    sync::{Rc, RefCell},
+};
+
+// Only growth is charged against the memory limit: the nodes copied by an
+// update are shared with the previous version or freed along with it.
+
+fn key_arg(name: &str, arg: &Object) -> Result<HashKey, EvalError> {
+    match HashKey::from_object(arg) {
+        Some(key) => Ok(key),
+        None => Err(format!("{}: unhashable key {}", name, arg).into()),
+    }
+}
+
+fn index_arg(name: &str, arg: &Object) -> Result<usize, EvalError> {
+    let index = integer_arg(name, arg)?;
+    usize::try_from(index).map_err(|_| format!("{}: invalid index {}", name, index).into())
+}
+
+pub fn persistent_vector(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    env.borrow().runtime().charge(list_size(args.len()))?;
+    Ok(Object::PersistentVector(args.iter().cloned().collect()))
+}
+
+/// `(persistent-map key value ...)`.
+pub fn persistent_map(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    if !args.len().is_multiple_of(2) {
+        return Err("persistent-map expects an even number of arguments".into());
+    }
+    env.borrow().runtime().charge(list_size(args.len()))?;
+    let mut map = PersistentMap::new();
+    for pair in args.chunks(2) {
+        map = map.insert(key_arg("persistent-map", &pair[0])?, pair[1].clone());
+    }
+    Ok(Object::PersistentMap(map))
+}
+
+pub fn is_persistent_vector(
+    args: &[Object],
+    _env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    check_arity("persistent-vector?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::PersistentVector(_))))
+}
+
+pub fn is_persistent_map(
+    args: &[Object],
+    _env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    check_arity("persistent-map?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::PersistentMap(_))))
+}
+
+/// `(conj vector x)` appends `x`, `(conj map (key value))` adds an entry.
+pub fn conj(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("conj", args, 2)?;
+    match (&args[0], &args[1]) {
+        (Object::PersistentVector(vector), item) => {
+            env.borrow().runtime().charge(list_size(1))?;
+            Ok(Object::PersistentVector(vector.push(item.clone())))
+        }
+        (Object::PersistentMap(map), Object::ListData(pair)) if pair.len() == 2 => {
+            let key = key_arg("conj", &pair[0])?;
+            env.borrow().runtime().charge(list_size(2))?;
+            Ok(Object::PersistentMap(map.insert(key, pair[1].clone())))
+        }
+        (Object::PersistentMap(_), item) => Err(format!(
+            "conj expects a (key value) pair for a map but found {}",
+            item
+        )
+        .into()),
+        (coll, _) => Err(format!("conj expects a persistent collection but found {}", coll).into()),
+    }
+}
+
+/// `(assoc vector index x)` replaces an element or appends at the end,
+/// `(assoc map key value)` binds a key.
+pub fn assoc(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("assoc", args, 3)?;
+    match &args[0] {
+        Object::PersistentVector(vector) => {
+            let index = index_arg("assoc", &args[1])?;
+            if index == vector.len() {
+                env.borrow().runtime().charge(list_size(1))?;
+                return Ok(Object::PersistentVector(vector.push(args[2].clone())));
+            }
+            match vector.set(index, args[2].clone()) {
+                Some(vector) => Ok(Object::PersistentVector(vector)),
+                None => Err(format!(
+                    "assoc: index {} out of range for length {}",
+                    index,
+                    vector.len()
+                )
+                .into()),
+            }
+        }
+        Object::PersistentMap(map) => {
+            let key = key_arg("assoc", &args[1])?;
+            env.borrow().runtime().charge(list_size(2))?;
+            Ok(Object::PersistentMap(map.insert(key, args[2].clone())))
+        }
+        coll => Err(format!("assoc expects a persistent collection but found {}", coll).into()),
+    }
+}
+
+pub fn dissoc(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("dissoc", args, 2)?;
+    match &args[0] {
+        Object::PersistentMap(map) => {
+            let key = key_arg("dissoc", &args[1])?;
+            Ok(Object::PersistentMap(
+                map.remove(&key).unwrap_or_else(|| map.clone()),
+            ))
+        }
+        coll => Err(format!("dissoc expects a persistent map but found {}", coll).into()),
+    }
+}
+
+/// `(get coll key)` returns nil for a missing key or index,
+/// `(get coll key default)` the default.
+pub fn get(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("get", args, 2, 3)?;
+    let found = match &args[0] {
+        Object::PersistentVector(vector) => match &args[1] {
+            Object::Integer(index) => usize::try_from(*index)
+                .ok()
+                .and_then(|index| vector.get(index))
+                .cloned(),
+            index => return Err(format!("get expects an integer index but found {}", index).into()),
+        },
+        Object::PersistentMap(map) => map.get(&key_arg("get", &args[1])?).cloned(),
+        coll => {
+            return Err(format!("get expects a persistent collection but found {}", coll).into())
+        }
+    };
+    Ok(found.unwrap_or_else(|| args.get(2).cloned().unwrap_or(Object::Void)))
+}
+
+/// Number of elements or entries of any collection.
+pub fn count(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("count", args, 1)?;
+    let len = match &args[0] {
+        Object::ListData(list) => list.len(),
+        Object::Vector(vector) => vector.borrow().len(),
+        Object::HashTable(table) => table.borrow().len(),
+        Object::PersistentVector(vector) => vector.len(),
+        Object::PersistentMap(map) => map.len(),
+        coll => return Err(format!("count expects a collection but found {}", coll).into()),
+    };
+    Ok(Object::Integer(len as i64))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::object::Object;
+    use crate::sync::{Rc, RefCell};
+
+    fn run(program: &str) -> Object {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval(program, &mut env).unwrap()
+    }
+
+    #[test]
+    fn test_persistent_vector() {
+        let result = run("(
+            (define v (persistent-vector 1 2 3))
+            (define w (conj (assoc v 0 10) 4))
+            v
+            w
+            (get w 3)
+            (get w 9 :none)
+            (count w)
+            (= (count v) 3)
+        )");
+        assert_eq!(result.to_string(), "([1 2 3] [10 2 3 4] 4 :none 4 true)");
+
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(assoc (persistent-vector 1) 0)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for assoc: expected 3, found 2"
+        );
+    }
+
+    #[test]
+    fn test_persistent_map() {
+        let result = run("(
+            (define m (persistent-map :a 1 :b 2))
+            (define n (dissoc (assoc m :c 3) :a))
+            (get m :c)
+            (get n :c)
+            (get n :a 0)
+            (count n)
+            (persistent-map? (conj n (list :d 4)))
+        )");
+        assert_eq!(result.to_string(), "(3 0 2 true)");
+    }
+
+    #[test]
+    fn test_value_equality() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let a = eval("(assoc (persistent-map :a 1) :b 2)", &mut env).unwrap();
+        let b = eval("(persistent-map :b 2 :a 1)", &mut env).unwrap();
+        assert_eq!(a, b);
+        let a = eval("(conj (persistent-vector 1) 2)", &mut env).unwrap();
+        let b = eval("(persistent-vector 1 2)", &mut env).unwrap();
+        assert_eq!(a, b);
+
+        let result = run("(
+            (= (conj (persistent-vector 1 2 3) 4) (persistent-vector 1 2 3 4))
+            (= (persistent-vector 1 2) (persistent-vector 2 1))
+            (= (assoc (persistent-map :a 1) :b 2) (persistent-map :b 2 :a 1))
+            (!= (dissoc (persistent-map :a 1 :b 2) :b) (persistent-map :a 1))
+            (!= (persistent-map :a 1) (persistent-map :a 2))
+        )");
+        assert_eq!(result.to_string(), "(true false true false true)");
+    }
+
+    #[test]
+    fn test_sequence_functions() {
+        let result = run("(
+            (define v (persistent-vector 1 2 3 4))
+            (map (lambda (x) (* x x)) v)
+            (filter (lambda (x) (> x 2)) v)
+            (reduce (lambda (acc x) (+ acc x)) 0 v)
+            (filter (lambda (entry) (= (reduce (lambda (a x) x) 0 entry) 2))
+                    (persistent-map :a 1 :b 2))
+        )");
+        assert_eq!(result.to_string(), "([1 4 9 16] [3 4] 10 {:b 2})");
+    }
+}
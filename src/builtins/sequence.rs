@@ -0,0 +1,218 @@
+use super::{check_arity, check_min_arity, procedure_arg};
+use crate::{
+    env::Env,
+    eval::{self, EvalError},
+    hash_table::{HashKey, HashTable},
+    object::Object,
+    persistent::PersistentMap,
+    runtime::list_size,
+    sequence::{as_sequence, Sequence},
+    sync::{Rc, RefCell},
+};
+
+fn sequence_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a dyn Sequence, EvalError> {
+    match as_sequence(arg) {
+        Some(seq) => Ok(seq),
+        None => Err(format!("{} expects a sequence but found {}", name, arg).into()),
+    }
+}
+
+/// Steps through several sequences at once, stopping at the end of the
+/// shortest one.
+struct Lockstep<'a> {
+    iters: Vec<Box<dyn Iterator<Item = Result<Object, EvalError>> + 'a>>,
+}
+
+impl<'a> Lockstep<'a> {
+    fn new(name: &str, seqs: &'a [Object]) -> Result<Self, EvalError> {
+        let iters = seqs
+            .iter()
+            .map(|seq| Ok(sequence_arg(name, seq)?.elements()))
+            .collect::<Result<Vec<_>, EvalError>>()?;
+        Ok(Lockstep { iters })
+    }
+}
+
+impl Iterator for Lockstep<'_> {
+    type Item = Result<Vec<Object>, EvalError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let items = self
+            .iters
+            .iter_mut()
+            .map(|iter| iter.next())
+            .collect::<Option<Vec<_>>>()?;
+        Some(items.into_iter().collect())
+    }
+}
+
+/// `(map f seq ...)` calls `f` with one element of each sequence at a time.
+/// Mapping over vectors gives a vector of the same kind, anything else a
+/// list.
+pub fn map(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_min_arity("map", args, 2)?;
+    let func = procedure_arg("map", &args[0])?;
+    let mut results = Vec::new();
+    for items in Lockstep::new("map", &args[1..])? {
+        results.push(eval::apply(func, items?, env)?);
+    }
+    let size = list_size(results.len());
+    let result = match &args[1] {
+        Object::Vector(_) => Object::Vector(Rc::new(RefCell::new(results))),
+        Object::PersistentVector(_) => Object::PersistentVector(results.into_iter().collect()),
+        _ => Object::ListData(results.into()),
+    };
+    env.borrow().runtime().allocate(result, size)
+}
+
+/// `(filter pred seq)` keeps the elements for which `pred` returns true, in
+/// a collection of the same kind as `seq`.
+pub fn filter(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("filter", args, 2)?;
+    let pred = procedure_arg("filter", &args[0])?;
+    let mut kept = Vec::new();
+    for item in sequence_arg("filter", &args[1])?.elements() {
+        let item = item?;
+        match eval::apply(pred, vec![item.clone()], env)? {
+            Object::Bool(true) => kept.push(item),
+            Object::Bool(false) => {}
+            result => {
+                return Err(
+                    format!("filter: predicate returned {} instead of a boolean", result).into(),
+                )
+            }
+        }
+    }
+    let size = list_size(kept.len());
+    env.borrow()
+        .runtime()
+        .allocate(rebuild(&args[1], kept), size)
+}
+
+/// A collection of the same kind as `seq` holding `items`, which came from
+/// iterating over `seq`.
+fn rebuild(seq: &Object, items: Vec<Object>) -> Object {
+    let entries = || {
+        items.iter().filter_map(|entry| match entry {
+            Object::ListData(pair) => Some((HashKey::from_object(&pair[0])?, pair[1].clone())),
+            _ => None,
+        })
+    };
+    match seq {
+        Object::String(_) => Object::String(
+            items
+                .iter()
+                .filter_map(|c| match c {
+                    Object::Char(c) => Some(*c),
+                    _ => None,
+                })
+                .collect::<String>()
+                .into(),
+        ),
+        Object::Vector(_) => Object::Vector(Rc::new(RefCell::new(items))),
+        Object::HashTable(_) => {
+            let mut table = HashTable::new();
+            for (key, value) in entries() {
+                table.insert(key, value);
+            }
+            Object::HashTable(Rc::new(RefCell::new(table)))
+        }
+        Object::PersistentVector(_) => Object::PersistentVector(items.into_iter().collect()),
+        Object::PersistentMap(_) => {
+            Object::PersistentMap(entries().fold(PersistentMap::new(), |map, (key, value)| {
+                map.insert(key, value)
+            }))
+        }
+        _ => Object::ListData(items.into()),
+    }
+}
+
+/// `(reduce f init seq ...)` computes `(f (f init x1 y1 ...) x2 y2 ...)`.
+pub fn reduce(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_min_arity("reduce", args, 3)?;
+    let func = procedure_arg("reduce", &args[0])?;
+    let mut acc = args[1].clone();
+    for items in Lockstep::new("reduce", &args[2..])? {
+        let items = items?;
+        let mut call_args = Vec::with_capacity(items.len() + 1);
+        call_args.push(acc);
+        call_args.extend(items);
+        acc = eval::apply(func, call_args, env)?;
+    }
+    Ok(acc)
+}
+
+/// `(for-each f seq ...)` calls `f` like `map` does, for its side effects.
+pub fn for_each(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_min_arity("for-each", args, 2)?;
+    let func = procedure_arg("for-each", &args[0])?;
+    for items in Lockstep::new("for-each", &args[1..])? {
+        eval::apply(func, items?, env)?;
+    }
+    Ok(Object::Void)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::sync::{Rc, RefCell};
+
+    fn run(program: &str) -> String {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval(program, &mut env).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_map_over_several_sequences() {
+        let result = run("(
+            (map + (list 1 2 3) (list 10 20))
+            (map char-upcase \"abc\")
+            (map (lambda (x y z) (list x y z)) #(1 2) \"xy\" (persistent-vector :a :b))
+            (map (lambda (x) (* x 2)) #(1 2))
+            (map (lambda (entry) (first entry)) {:a 1})
+            (apply map (list + (list 1) (list 2)))
+        )");
+        assert_eq!(
+            result,
+            "((11 22) (A B C) #((1 x :a) (2 y :b)) #(2 4) (:a) (3))"
+        );
+    }
+
+    #[test]
+    fn test_filter_keeps_the_kind() {
+        let result = run("(
+            (filter char-alphabetic? \"a1b2\")
+            (filter (lambda (x) (> x 1)) #(1 2 3))
+            (filter (lambda (e) (= (nth e 1) 2)) {:a 1 :b 2})
+            (filter (lambda (x) (> x 1)) (range 4))
+        )");
+        assert_eq!(result, "(ab #(2 3) {:b 2} (2 3))");
+    }
+
+    #[test]
+    fn test_reduce_and_for_each() {
+        let result = run("(
+            (reduce + 0 (range 5))
+            (reduce (lambda (acc x y) (+ acc (* x y))) 0 (list 1 2 3) #(4 5 6))
+            (define out (hash))
+            (for-each (lambda (k v) (hash-set! out k v)) (list :a :b) (list 1 2))
+            (hash-ref out :b)
+        )");
+        assert_eq!(result, "(10 32 2)");
+    }
+
+    #[test]
+    fn test_sequence_errors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(map 1 (list 1))", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "map expects a procedure but found 1");
+        let err = eval("(map - 1)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "map expects a sequence but found 1");
+        let err = eval("(map + (list 1))", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for +: expected 2, found 1"
+        );
+    }
+}
@@ -0,0 +1,334 @@
+use super::{check_arity, check_arity_range, check_min_arity, integer_arg, procedure_arg};
+use crate::{
+    env::Env,
+    eval::{self, EvalError},
+    lazy::{Promise, Stream},
+    object::Object,
+    runtime::list_size,
+    sync::{Rc, RefCell},
+};
+
+// A stream is a chain of `Object::Stream` cells ending in the empty list.
+// Lazy operations compute the head of a cell eagerly and the rest of the
+// stream when its tail is forced, so infinite streams are fine as long as
+// only a finite prefix is ever looked at.
+
+fn empty() -> Object {
+    Object::ListData(Rc::new(Vec::new()))
+}
+
+fn cell(head: Object, tail: Promise) -> Object {
+    Object::Stream(Rc::new(Stream { head, tail }))
+}
+
+/// The first cell of a stream, or `None` for the empty stream.
+fn stream_arg(name: &str, arg: &Object) -> Result<Option<Rc<Stream>>, EvalError> {
+    match arg {
+        Object::Stream(stream) => Ok(Some(stream.clone())),
+        Object::ListData(list) if list.is_empty() => Ok(None),
+        _ => Err(format!("{} expects a stream but found {}", name, arg).into()),
+    }
+}
+
+pub fn force(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("force", args, 1)?;
+    match &args[0] {
+        Object::Promise(promise) => promise.force(env),
+        // Forcing a value that is not a promise gives the value itself.
+        value => Ok(value.clone()),
+    }
+}
+
+pub fn make_promise(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("make-promise", args, 1)?;
+    match &args[0] {
+        Object::Promise(_) => Ok(args[0].clone()),
+        value => Ok(Object::Promise(Promise::ready(value.clone()))),
+    }
+}
+
+pub fn is_promise(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("promise?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::Promise(_))))
+}
+
+/// `(stream x ...)` builds a finite stream.
+pub fn stream(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    Ok(args.iter().rev().fold(empty(), |rest, head| {
+        cell(head.clone(), Promise::ready(rest))
+    }))
+}
+
+pub fn is_stream(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("stream?", args, 1)?;
+    Ok(Object::Bool(stream_arg("stream?", &args[0]).is_ok()))
+}
+
+pub fn is_stream_null(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("stream-null?", args, 1)?;
+    Ok(Object::Bool(
+        stream_arg("stream-null?", &args[0])?.is_none(),
+    ))
+}
+
+pub fn stream_car(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("stream-car", args, 1)?;
+    match stream_arg("stream-car", &args[0])? {
+        Some(stream) => Ok(stream.head.clone()),
+        None => Err("stream-car: empty stream".into()),
+    }
+}
+
+pub fn stream_cdr(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("stream-cdr", args, 1)?;
+    match stream_arg("stream-cdr", &args[0])? {
+        Some(stream) => stream.tail.force(env),
+        None => Err("stream-cdr: empty stream".into()),
+    }
+}
+
+/// `(stream-map f stream ...)`, ending with the shortest stream.
+pub fn stream_map(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_min_arity("stream-map", args, 2)?;
+    let func = procedure_arg("stream-map", &args[0])?.clone();
+    map_from(func, args[1..].to_vec(), env)
+}
+
+fn map_from(
+    func: Object,
+    streams: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    let mut cells = Vec::with_capacity(streams.len());
+    for stream in &streams {
+        match stream_arg("stream-map", stream)? {
+            Some(cell) => cells.push(cell),
+            None => return Ok(empty()),
+        }
+    }
+    let heads = cells.iter().map(|cell| cell.head.clone()).collect();
+    let head = eval::apply(&func, heads, env)?;
+    let tail = Promise::native(Rc::new(move |env| {
+        let rests = cells
+            .iter()
+            .map(|cell| cell.tail.force(env))
+            .collect::<Result<Vec<_>, _>>()?;
+        map_from(func.clone(), rests, env)
+    }));
+    Ok(cell(head, tail))
+}
+
+/// `(stream-filter pred stream)`. Looks ahead only as far as the next
+/// element that satisfies `pred`.
+pub fn stream_filter(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("stream-filter", args, 2)?;
+    let pred = procedure_arg("stream-filter", &args[0])?.clone();
+    filter_from(pred, args[1].clone(), env)
+}
+
+fn filter_from(
+    pred: Object,
+    mut stream: Object,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    let runtime = env.borrow().runtime();
+    loop {
+        let cell = match stream_arg("stream-filter", &stream)? {
+            Some(cell) => cell,
+            None => return Ok(empty()),
+        };
+        match eval::apply(&pred, vec![cell.head.clone()], env)? {
+            Object::Bool(true) => {
+                let head = cell.head.clone();
+                let tail = Promise::native(Rc::new(move |env| {
+                    let rest = cell.tail.force(env)?;
+                    filter_from(pred.clone(), rest, env)
+                }));
+                return Ok(self::cell(head, tail));
+            }
+            Object::Bool(false) => {}
+            result => {
+                return Err(format!(
+                    "stream-filter: predicate returned {} instead of a boolean",
+                    result
+                )
+                .into())
+            }
+        }
+        // Skipping an infinite run of rejected elements must stay
+        // interruptible.
+        runtime.tick()?;
+        stream = cell.tail.force(env)?;
+    }
+}
+
+/// `(stream-take stream n)`: a stream of at most the first `n` elements.
+pub fn stream_take(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("stream-take", args, 2)?;
+    let n = integer_arg("stream-take", &args[1])?;
+    if n < 0 {
+        return Err(format!("stream-take: invalid count {}", n).into());
+    }
+    take_from(args[0].clone(), n)
+}
+
+fn take_from(stream: Object, n: i64) -> Result<Object, EvalError> {
+    if n == 0 {
+        return Ok(empty());
+    }
+    match stream_arg("stream-take", &stream)? {
+        Some(cell) => {
+            let head = cell.head.clone();
+            let tail = Promise::native(Rc::new(move |env| {
+                let rest = cell.tail.force(env)?;
+                take_from(rest, n - 1)
+            }));
+            Ok(self::cell(head, tail))
+        }
+        None => Ok(empty()),
+    }
+}
+
+/// `(stream->list stream)` or `(stream->list stream n)` for at most `n`
+/// elements.
+pub fn stream_to_list(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("stream->list", args, 1, 2)?;
+    let limit = match args.get(1) {
+        Some(n) => Some(integer_arg("stream->list", n)?),
+        None => None,
+    };
+    let runtime = env.borrow().runtime();
+    let mut list = Vec::new();
+    let taken = take_stream(args[0].clone(), limit, &mut list, env);
+    let size = list_size(list.len());
+    if let Err(err) = taken {
+        runtime.release(size);
+        return Err(err);
+    }
+    let list = Object::ListData(list.into());
+    runtime.hold(&list, size);
+    Ok(list)
+}
+
+/// Pushes the elements of `stream` onto `list`, charging for each one, until
+/// the stream ends or `list` holds `limit` elements.
+fn take_stream(
+    mut stream: Object,
+    limit: Option<i64>,
+    list: &mut Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<(), EvalError> {
+    let runtime = env.borrow().runtime();
+    while limit.is_none_or(|n| (list.len() as i64) < n) {
+        let cell = match stream_arg("stream->list", &stream)? {
+            Some(cell) => cell,
+            None => break,
+        };
+        runtime.tick()?;
+        runtime.charge(list_size(1))?;
+        list.push(cell.head.clone());
+        stream = cell.tail.force(env)?;
+    }
+    Ok(())
+}
+
+/// `(iterate f x)`: the infinite stream `x`, `(f x)`, `(f (f x))`, ...
+pub fn iterate(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("iterate", args, 2)?;
+    let func = procedure_arg("iterate", &args[0])?.clone();
+    Ok(iterate_from(func, args[1].clone()))
+}
+
+fn iterate_from(func: Object, value: Object) -> Object {
+    let current = value.clone();
+    let tail = Promise::native(Rc::new(move |env| {
+        let next = eval::apply(&func, vec![current.clone()], env)?;
+        Ok(iterate_from(func.clone(), next))
+    }));
+    cell(value, tail)
+}
+
+/// The infinite stream `n`, `n + 1`, ..., returned by `(range)`.
+pub fn count_from(n: i64) -> Object {
+    cell(
+        Object::Integer(n),
+        Promise::native(Rc::new(move |_| Ok(count_from(n.wrapping_add(1))))),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::runtime::Limits;
+    use crate::sync::{Rc, RefCell};
+
+    fn run(program: &str) -> String {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval(program, &mut env).unwrap().to_string()
+    }
+
+    #[test]
+    fn test_delay_and_force_memoise() {
+        let result = run("(
+            (define count (make-vector 1 0))
+            (define p (delay (begin (vector-set! count 0 (+ 1 (vector-ref count 0))) 42)))
+            (promise? p)
+            (force p)
+            (force p)
+            (vector-ref count 0)
+            (force (make-promise 7))
+            (force 8)
+        )");
+        assert_eq!(result, "(true 42 42 1 7 8)");
+    }
+
+    #[test]
+    fn test_cons_stream() {
+        let result = run("(
+            (define integers-from (lambda (n) (cons-stream n (integers-from (+ n 1)))))
+            (define evens (stream-filter (lambda (x) (= (% x 2) 0)) (integers-from 1)))
+            (stream->list evens 4)
+            (stream-car (stream-cdr (stream 1 2)))
+            (stream-null? (stream-cdr (stream 1)))
+        )");
+        assert_eq!(result, "((2 4 6 8) 2 true)");
+    }
+
+    #[test]
+    fn test_lazy_operations_on_infinite_streams() {
+        let result = run("(
+            (stream->list (stream-take (stream-map * (range) (range)) 5))
+            (stream->list (iterate (lambda (x) (* x 2)) 1) 6)
+            (stream->list (stream-map + (range) (stream 10 20)))
+            (stream->list (stream-take (stream 1 2) 5))
+        )");
+        assert_eq!(result, "((0 1 4 9 16) (1 2 4 8 16 32) (10 21) (1 2))");
+    }
+
+    #[test]
+    fn test_stream_map_is_lazy() {
+        let result = run("(
+            (define calls (make-vector 1 0))
+            (define s (stream-map
+                        (lambda (x) (begin (vector-set! calls 0 (+ 1 (vector-ref calls 0))) x))
+                        (range)))
+            (stream-car (stream-cdr (stream-cdr s)))
+            (stream-car (stream-cdr s))
+            (vector-ref calls 0)
+        )");
+        assert_eq!(result, "(2 1 3)");
+    }
+
+    #[test]
+    fn test_infinite_walk_runs_out_of_fuel() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let limits = Limits {
+            fuel: Some(10_000),
+            ..Limits::default()
+        };
+        let program = "(stream->list (stream-filter (lambda (x) (< x 0)) (range)))";
+        let err = crate::eval::eval_with_limits(program, &mut env, limits).unwrap_err();
+        assert_eq!(err, crate::eval::EvalError::OutOfFuel);
+    }
+}
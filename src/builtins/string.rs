@@ -0,0 +1,362 @@
+use super::{char_arg, check_arity, check_arity_range, integer_arg, list_arg, string_arg};
+use crate::{
+    env::Env,
+    eval::EvalError,
+    object::Object,
+    runtime::list_size,
+    symbol::Symbol,
+    sync::{Rc, RefCell},
+};
+
+// All positions and lengths count characters, not bytes.
+
+fn new_string(s: String, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let size = s.len();
+    env.borrow()
+        .runtime()
+        .allocate(Object::String(s.into()), size)
+}
+
+fn new_list(list: Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let size = list_size(list.len());
+    env.borrow()
+        .runtime()
+        .allocate(Object::ListData(list.into()), size)
+}
+
+/// A string or a character to search for.
+fn needle_arg(name: &str, arg: &Object) -> Result<String, EvalError> {
+    match arg {
+        Object::String(s) => Ok(s.to_string()),
+        Object::Char(c) => Ok(c.to_string()),
+        _ => Err(format!("{} expects a string or a character but found {}", name, arg).into()),
+    }
+}
+
+fn char_index(name: &str, arg: &Object, len: usize) -> Result<usize, EvalError> {
+    let index = integer_arg(name, arg)?;
+    match usize::try_from(index) {
+        Ok(index) if index <= len => Ok(index),
+        _ => Err(format!("{}: index {} out of range", name, index).into()),
+    }
+}
+
+pub fn string_length(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-length", args, 1)?;
+    let s = string_arg("string-length", &args[0])?;
+    Ok(Object::Integer(s.chars().count() as i64))
+}
+
+/// `(substring s start)` or `(substring s start end)`.
+pub fn substring(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("substring", args, 2, 3)?;
+    let s = string_arg("substring", &args[0])?;
+    let len = s.chars().count();
+    let start = char_index("substring", &args[1], len)?;
+    let end = match args.get(2) {
+        Some(end) => char_index("substring", end, len)?,
+        None => len,
+    };
+    if start > end {
+        return Err(format!("substring: start {} is after end {}", start, end).into());
+    }
+    new_string(s.chars().skip(start).take(end - start).collect(), env)
+}
+
+/// `(string-split s)` splits on whitespace, `(string-split s sep)` on `sep`.
+pub fn string_split(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("string-split", args, 1, 2)?;
+    let s = string_arg("string-split", &args[0])?;
+    let parts: Vec<Object> = match args.get(1) {
+        Some(sep) => {
+            let sep = needle_arg("string-split", sep)?;
+            if sep.is_empty() {
+                return Err("string-split: separator must not be empty".into());
+            }
+            s.split(sep.as_str())
+                .map(|part| Object::String(part.into()))
+                .collect()
+        }
+        None => s
+            .split_whitespace()
+            .map(|part| Object::String(part.into()))
+            .collect(),
+    };
+    new_list(parts, env)
+}
+
+/// `(string-join list)` joins with a space, `(string-join list sep)` with `sep`.
+pub fn string_join(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("string-join", args, 1, 2)?;
+    let list = list_arg("string-join", &args[0])?;
+    let sep = match args.get(1) {
+        Some(sep) => needle_arg("string-join", sep)?,
+        None => " ".to_string(),
+    };
+    let parts = list
+        .iter()
+        .map(|part| string_arg("string-join", part))
+        .collect::<Result<Vec<_>, _>>()?;
+    new_string(parts.join(&sep), env)
+}
+
+pub fn string_trim(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-trim", args, 1)?;
+    let s = string_arg("string-trim", &args[0])?;
+    new_string(s.trim().to_string(), env)
+}
+
+pub fn string_trim_left(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-trim-left", args, 1)?;
+    let s = string_arg("string-trim-left", &args[0])?;
+    new_string(s.trim_start().to_string(), env)
+}
+
+pub fn string_trim_right(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-trim-right", args, 1)?;
+    let s = string_arg("string-trim-right", &args[0])?;
+    new_string(s.trim_end().to_string(), env)
+}
+
+pub fn string_contains(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-contains", args, 2)?;
+    let s = string_arg("string-contains", &args[0])?;
+    let needle = needle_arg("string-contains", &args[1])?;
+    Ok(Object::Bool(s.contains(needle.as_str())))
+}
+
+/// Character position of the first occurrence of a string or character, or
+/// nil if there is none.
+pub fn string_index(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-index", args, 2)?;
+    let s = string_arg("string-index", &args[0])?;
+    let needle = needle_arg("string-index", &args[1])?;
+    match s.find(needle.as_str()) {
+        Some(byte_index) => Ok(Object::Integer(s[..byte_index].chars().count() as i64)),
+        None => Ok(Object::Void),
+    }
+}
+
+/// Replaces every occurrence of `from` with `to`.
+pub fn string_replace(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-replace", args, 3)?;
+    let s = string_arg("string-replace", &args[0])?;
+    let from = needle_arg("string-replace", &args[1])?;
+    let to = needle_arg("string-replace", &args[2])?;
+    if from.is_empty() {
+        return Err("string-replace: pattern must not be empty".into());
+    }
+    new_string(s.replace(from.as_str(), &to), env)
+}
+
+pub fn string_upcase(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-upcase", args, 1)?;
+    let s = string_arg("string-upcase", &args[0])?;
+    new_string(s.to_uppercase(), env)
+}
+
+pub fn string_downcase(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-downcase", args, 1)?;
+    let s = string_arg("string-downcase", &args[0])?;
+    new_string(s.to_lowercase(), env)
+}
+
+pub fn string_to_symbol(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string->symbol", args, 1)?;
+    let s = string_arg("string->symbol", &args[0])?;
+    Ok(Object::Symbol(Symbol::intern(s)))
+}
+
+pub fn symbol_to_string(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("symbol->string", args, 1)?;
+    match &args[0] {
+        Object::Symbol(s) => new_string(s.to_string(), env),
+        arg => Err(format!("symbol->string expects a symbol but found {}", arg).into()),
+    }
+}
+
+/// The external representation used by `~s`: strings are quoted and
+/// characters written as `#\x`.
+fn write_string(obj: &Object) -> String {
+    match obj {
+        Object::String(s) => format!("{:?}", s),
+        Object::Char(' ') => "#\\space".to_string(),
+        Object::Char('\n') => "#\\newline".to_string(),
+        Object::Char('\t') => "#\\tab".to_string(),
+        Object::Char(c) => format!("#\\{}", c),
+        Object::ListData(list) => {
+            let items = list.iter().map(write_string).collect::<Vec<_>>();
+            format!("({})", items.join(" "))
+        }
+        _ => obj.to_string(),
+    }
+}
+
+/// `(format template args ...)` with the directives `~a` (display), `~s`
+/// (write), `~%` (newline) and `~~` (a literal tilde).
+pub fn format(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    if args.is_empty() {
+        return Err("format expects a template string".into());
+    }
+    let template = string_arg("format", &args[0])?;
+    let mut values = args[1..].iter();
+    let mut out = String::new();
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        if c != '~' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(d @ ('a' | 's' | 'A' | 'S')) => {
+                let value = match values.next() {
+                    Some(value) => value,
+                    None => return Err("format: not enough arguments for template".into()),
+                };
+                if d.eq_ignore_ascii_case(&'s') {
+                    out.push_str(&write_string(value));
+                } else {
+                    out.push_str(&value.to_string());
+                }
+            }
+            Some('%') => out.push('\n'),
+            Some('~') => out.push('~'),
+            Some(d) => return Err(format!("format: unknown directive ~{}", d).into()),
+            None => return Err("format: template ends with ~".into()),
+        }
+    }
+    if values.next().is_some() {
+        return Err("format: too many arguments for template".into());
+    }
+    new_string(out, env)
+}
+
+/// Strings are indexed by character, not by byte.
+pub fn string_ref(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string-ref", args, 2)?;
+    let s = string_arg("string-ref", &args[0])?;
+    let index = integer_arg("string-ref", &args[1])?;
+    let c = usize::try_from(index).ok().and_then(|i| s.chars().nth(i));
+    match c {
+        Some(c) => Ok(Object::Char(c)),
+        None => Err(format!("string-ref: index {} out of range for \"{}\"", index, s).into()),
+    }
+}
+
+pub fn string_to_list(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("string->list", args, 1)?;
+    let s = string_arg("string->list", &args[0])?;
+    new_list(s.chars().map(Object::Char).collect(), env)
+}
+
+pub fn list_to_string(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("list->string", args, 1)?;
+    let list = list_arg("list->string", &args[0])?;
+    let s = list
+        .iter()
+        .map(|c| char_arg("list->string", c))
+        .collect::<Result<String, _>>()?;
+    new_string(s, env)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::object::Object;
+    use crate::sync::{Rc, RefCell};
+
+    fn strings(items: &[&str]) -> Object {
+        Object::ListData(
+            items
+                .iter()
+                .map(|s| Object::String((*s).into()))
+                .collect::<Vec<_>>()
+                .into(),
+        )
+    }
+
+    #[test]
+    fn test_string_functions() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (string-length \"héllo\")
+            (substring \"héllo wörld\" 6)
+            (substring \"héllo\" 1 3)
+            (string-split \" a  b c \")
+            (string-split \"a,b,,c\" \",\")
+            (string-join (list \"a\" \"b\"))
+            (string-join (list \"a\" \"b\") \", \")
+            (string-trim \"  x  \")
+            (string-contains \"hello\" \"ell\")
+            (string-index \"héllo\" #\\l)
+            (string-replace \"a-b-c\" \"-\" \"+\")
+            (string-upcase \"straße\")
+            (string-downcase \"ÀB\")
+            (symbol->string (string->symbol \"foo\"))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Integer(5),
+                Object::String("wörld".into()),
+                Object::String("él".into()),
+                strings(&["a", "b", "c"]),
+                strings(&["a", "b", "", "c"]),
+                Object::String("a b".into()),
+                Object::String("a, b".into()),
+                Object::String("x".into()),
+                Object::Bool(true),
+                Object::Integer(2),
+                Object::String("a+b+c".into()),
+                Object::String("STRASSE".into()),
+                Object::String("àb".into()),
+                Object::String("foo".into()),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(format \"~a and ~s, ~a ~s~%~~\" \"x\" \"y\" #\\a #\\a)";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result, Object::String("x and \"y\", a #\\a\n~".into()));
+
+        let err = eval("(format \"~a ~a\" 1)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "format: not enough arguments for template");
+    }
+
+    #[test]
+    fn test_substring_out_of_range() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(substring \"abc\" 2 4)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "substring: index 4 out of range");
+    }
+
+    #[test]
+    fn test_strings_and_chars() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (string-ref \"héllo\" 1)
+            (string->list \"ab\")
+            (list->string (list #\\x41 #\\newline))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Char('é'),
+                Object::ListData(Rc::new(vec![Object::Char('a'), Object::Char('b')])),
+                Object::String("A\n".into()),
+            ]))
+        );
+
+        let err = eval("(string-ref \"abc\" 3)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "string-ref: index 3 out of range for \"abc\""
+        );
+    }
+}
@@ -0,0 +1,188 @@
+use super::{check_arity, check_arity_range, integer_arg, list_arg};
+use crate::{
+    env::Env,
+    eval::{self, EvalError},
+    object::Object,
+    runtime::list_size,
+    sync::{Rc, RefCell},
+};
+
+pub(crate) fn new_vector(
+    items: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    let size = list_size(items.len());
+    let vector = Object::Vector(Rc::new(RefCell::new(items)));
+    env.borrow().runtime().allocate(vector, size)
+}
+
+fn vector_arg<'a>(name: &str, arg: &'a Object) -> Result<&'a Rc<RefCell<Vec<Object>>>, EvalError> {
+    match arg {
+        Object::Vector(vector) => Ok(vector),
+        _ => Err(format!("{} expects a vector but found {}", name, arg).into()),
+    }
+}
+
+fn vector_index(name: &str, arg: &Object, len: usize) -> Result<usize, EvalError> {
+    let index = integer_arg(name, arg)?;
+    match usize::try_from(index) {
+        Ok(index) if index < len => Ok(index),
+        _ => Err(format!("{}: index {} out of range for length {}", name, index, len).into()),
+    }
+}
+
+pub fn is_vector(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("vector?", args, 1)?;
+    Ok(Object::Bool(matches!(args[0], Object::Vector(_))))
+}
+
+pub fn vector(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    new_vector(args.to_vec(), env)
+}
+
+/// `(make-vector n)` filled with nil, or `(make-vector n fill)`.
+pub fn make_vector(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity_range("make-vector", args, 1, 2)?;
+    let len = integer_arg("make-vector", &args[0])?;
+    let len = match usize::try_from(len) {
+        Ok(len) => len,
+        Err(_) => return Err(format!("make-vector: invalid length {}", len).into()),
+    };
+    // Charge before allocating so an oversized request fails cleanly.
+    let runtime = env.borrow().runtime();
+    runtime.charge(list_size(len))?;
+    let fill = args.get(1).cloned().unwrap_or(Object::Void);
+    let vector = Object::Vector(Rc::new(RefCell::new(vec![fill; len])));
+    runtime.hold(&vector, list_size(len));
+    Ok(vector)
+}
+
+pub fn vector_length(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("vector-length", args, 1)?;
+    let vector = vector_arg("vector-length", &args[0])?;
+    Ok(Object::Integer(vector.borrow().len() as i64))
+}
+
+pub fn vector_ref(args: &[Object], _env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("vector-ref", args, 2)?;
+    let vector = vector_arg("vector-ref", &args[0])?.borrow();
+    let index = vector_index("vector-ref", &args[1], vector.len())?;
+    Ok(vector[index].clone())
+}
+
+pub fn vector_set(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("vector-set!", args, 3)?;
+    let mut vector = vector_arg("vector-set!", &args[0])?.borrow_mut();
+    let index = vector_index("vector-set!", &args[1], vector.len())?;
+    vector[index] = args[2].clone();
+    drop(vector);
+    env.borrow().runtime().watch_container(&args[0], &args[2]);
+    Ok(Object::Void)
+}
+
+/// Calls a procedure on every element and collects the results in a new
+/// vector.
+pub fn vector_map(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("vector-map", args, 2)?;
+    if !eval::is_procedure(&args[0]) {
+        return Err(format!("vector-map expects a procedure but found {}", args[0]).into());
+    }
+    // The procedure may mutate the vector, so work on a snapshot.
+    let items = vector_arg("vector-map", &args[1])?.borrow().clone();
+    let mut result = Vec::with_capacity(items.len());
+    for item in items {
+        result.push(eval::apply(&args[0], vec![item], env)?);
+    }
+    new_vector(result, env)
+}
+
+pub fn vector_to_list(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("vector->list", args, 1)?;
+    let items = vector_arg("vector->list", &args[0])?.borrow().clone();
+    let size = list_size(items.len());
+    env.borrow()
+        .runtime()
+        .allocate(Object::ListData(items.into()), size)
+}
+
+pub fn list_to_vector(args: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    check_arity("list->vector", args, 1)?;
+    let list = list_arg("list->vector", &args[0])?;
+    new_vector(list.to_vec(), env)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::object::Object;
+    use crate::sync::{Rc, RefCell};
+
+    fn integers(items: &[i64]) -> Rc<Vec<Object>> {
+        Rc::new(items.iter().map(|n| Object::Integer(*n)).collect())
+    }
+
+    #[test]
+    fn test_vectors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define v #(1 2 3))
+            (vector-length v)
+            (vector-ref v 2)
+            (vector->list (vector-map (lambda (x) (* x x)) v))
+            (vector-length (make-vector 4 0))
+            (vector? (list->vector (list 1 2)))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Integer(3),
+                Object::Integer(3),
+                Object::ListData(integers(&[1, 4, 9])),
+                Object::Integer(4),
+                Object::Bool(true),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_vector_set_is_shared() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define v (make-vector 3 0))
+            (define w v)
+            (define fill! (lambda (vec x) (vector-set! vec 1 x)))
+            (fill! w 7)
+            (vector->list v)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![Object::ListData(integers(&[0, 7, 0]))]))
+        );
+    }
+
+    #[test]
+    fn test_vector_literal_elements_are_evaluated() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (let ((x 1)) #(x (+ 1 2) #(x)))
+            (define fresh (lambda () #(0)))
+            (vector-set! (fresh) 0 1)
+            (fresh)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result.to_string(), "(#(1 3 #(1)) #(0))");
+    }
+
+    #[test]
+    fn test_vector_index_out_of_range() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval("(vector-ref #(1 2) 2)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "vector-ref: index 2 out of range for length 2"
+        );
+    }
+}
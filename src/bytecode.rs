@@ -0,0 +1,368 @@
+//! Compiles resolved code to bytecode for the stack machine in `vm`.
+//!
+//! Each procedure clause is compiled to its own `Chunk` the first time the
+//! VM runs it, and the chunk is kept in the clause. Operands that do not fit
+//! in an instruction, e.g. constants and lambdas, are kept in tables of the
+//! chunk and referred to by index.
+//!
+//! Code in tail position ends with `Return` or `TailCall`, so the VM runs
+//! tail calls without growing its stack of calls, like `eval_expr` does.
+//! Default values of parameters and the code of promises are left to
+//! `eval_expr`.
+
+use crate::{
+    env::Cell,
+    eval::EvalError,
+    hash_table::HashKey,
+    object::Object,
+    resolve::{Body, CondClause, Expr, Lambda, LetValues, VarRef},
+    symbol::Symbol,
+    sync::Rc,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    /// Pushes `constants[i]`.
+    Const(u32),
+    /// Pushes a local, failing with "Unbound symbol" if it is not bound yet.
+    Local {
+        depth: u32,
+        index: u32,
+        name: Symbol,
+    },
+    /// Pushes the value of `globals[i]`.
+    Global(u32),
+    /// Like `Local` and `Global` for the head of a call, which must be a
+    /// procedure.
+    LocalFunction {
+        depth: u32,
+        index: u32,
+        name: Symbol,
+    },
+    GlobalFunction(u32),
+    /// Pops a value into a local and pushes `Void`.
+    DefineLocal {
+        depth: u32,
+        index: u32,
+    },
+    /// Pops a value into `globals[i]` and pushes `Void`.
+    DefineGlobal(u32),
+    Pop,
+    Jump(u32),
+    /// Pops a boolean and jumps if it is false.
+    Branch(u32),
+    /// Pops the result of the running procedure.
+    Return,
+    /// Pushes a closure of `lambdas[i]`.
+    Closure(u32),
+    CaseClosure(u32),
+    /// Calls the procedure below the top `n` values with them as arguments.
+    Call(u32),
+    TailCall(u32),
+    /// Like `Call` if the value below the top `n` values is a procedure.
+    /// Otherwise collects the values that are not `Void` into a list.
+    Combine(u32),
+    TailCombine(u32),
+    /// Applies `operators[i]` to the top two values.
+    BinaryOp(u32),
+    /// Collects the top `n` values into a list.
+    List(u32),
+    /// Collects the top `n` values into a new vector.
+    Vector(u32),
+    /// Collects the values of the keys in `tables[i]`, pushed in that
+    /// order, into a new hash table.
+    HashTable(u32),
+    /// Pushes a promise of `exprs[i]`.
+    Delay(u32),
+    /// Pops the head of a stream whose tail is a promise of `exprs[i]`.
+    ConsStream(u32),
+    /// Pops `count` values into the first slots of a new frame of `size`
+    /// slots, as `let` does.
+    EnterLet {
+        size: u32,
+        count: u32,
+    },
+    /// Pops the values of the bindings of `let_values[i]` and binds them in
+    /// a new frame.
+    EnterLetValues(u32),
+    /// Goes back to the frame the innermost `let` was entered from.
+    LeaveFrame,
+    /// Fails with `errors[i]`.
+    Fail(u32),
+}
+
+/// Compiled code with the tables its instructions refer to.
+#[derive(Debug, Default)]
+pub struct Chunk {
+    pub code: Vec<Op>,
+    pub constants: Vec<Object>,
+    pub globals: Vec<(Cell, Symbol)>,
+    pub lambdas: Vec<Rc<Lambda>>,
+    pub exprs: Vec<Rc<Expr>>,
+    pub let_values: Vec<Rc<LetValues>>,
+    pub operators: Vec<String>,
+    pub tables: Vec<Vec<HashKey>>,
+    pub errors: Vec<EvalError>,
+}
+
+/// Compiles `expr` to a chunk that returns its value.
+pub fn compile(expr: &Expr) -> Chunk {
+    let mut chunk = Chunk::default();
+    chunk.tail(expr);
+    chunk
+}
+
+/// Compiles the body of a procedure clause.
+pub fn compile_body(body: &Body) -> Chunk {
+    let mut chunk = Chunk::default();
+    chunk.body(body, true);
+    chunk
+}
+
+fn index(i: usize) -> u32 {
+    u32::try_from(i).expect("chunk too large")
+}
+
+impl Chunk {
+    fn emit(&mut self, op: Op) -> usize {
+        self.code.push(op);
+        self.code.len() - 1
+    }
+
+    /// Points the jump at `at` to the next instruction.
+    fn patch(&mut self, at: usize) {
+        let target = index(self.code.len());
+        match &mut self.code[at] {
+            Op::Jump(to) | Op::Branch(to) => *to = target,
+            op => unreachable!("patching {:?}", op),
+        }
+    }
+
+    fn constant(&mut self, val: Object) -> u32 {
+        self.constants.push(val);
+        index(self.constants.len() - 1)
+    }
+
+    fn global(&mut self, cell: &Cell, name: Symbol) -> u32 {
+        match self.globals.iter().position(|(c, _)| Rc::ptr_eq(c, cell)) {
+            Some(i) => index(i),
+            None => {
+                self.globals.push((cell.clone(), name));
+                index(self.globals.len() - 1)
+            }
+        }
+    }
+
+    /// Compiles `expr` in tail position.
+    fn tail(&mut self, expr: &Expr) {
+        self.expr(expr, true);
+    }
+
+    /// Compiles `expr` to push its value, or in tail position to return it.
+    fn expr(&mut self, expr: &Expr, tail: bool) {
+        match expr {
+            Expr::Const(val) => {
+                let i = self.constant(val.clone());
+                self.emit(Op::Const(i));
+            }
+            Expr::Var(VarRef::Local { depth, index: i }, name) => {
+                self.emit(Op::Local {
+                    depth: index(*depth),
+                    index: index(*i),
+                    name: *name,
+                });
+            }
+            Expr::Var(VarRef::Global(cell), name) => {
+                let i = self.global(cell, *name);
+                self.emit(Op::Global(i));
+            }
+            Expr::Define(var, name, value) => {
+                self.expr(value, false);
+                match var {
+                    VarRef::Local { depth, index: i } => self.emit(Op::DefineLocal {
+                        depth: index(*depth),
+                        index: index(*i),
+                    }),
+                    VarRef::Global(cell) => {
+                        let i = self.global(cell, *name);
+                        self.emit(Op::DefineGlobal(i))
+                    }
+                };
+            }
+            Expr::If(cond, then, otherwise) => {
+                self.expr(cond, false);
+                let branch = self.emit(Op::Branch(0));
+                self.expr(then, tail);
+                if tail {
+                    self.patch(branch);
+                    self.expr(otherwise, true);
+                    return;
+                }
+                let jump = self.emit(Op::Jump(0));
+                self.patch(branch);
+                self.expr(otherwise, false);
+                self.patch(jump);
+                return;
+            }
+            Expr::Begin(body) => {
+                self.body(body, tail);
+                return;
+            }
+            Expr::Cond(clauses) => {
+                self.cond(clauses, tail);
+                return;
+            }
+            Expr::Let(let_) => {
+                for init in let_.inits.iter() {
+                    self.expr(init, false);
+                }
+                self.emit(Op::EnterLet {
+                    size: index(let_.size),
+                    count: index(let_.inits.len()),
+                });
+                self.scope(&let_.body, tail);
+                return;
+            }
+            Expr::LetValues(let_values) => {
+                for (_, init) in let_values.bindings.iter() {
+                    self.expr(init, false);
+                }
+                self.let_values.push(let_values.clone());
+                self.emit(Op::EnterLetValues(index(self.let_values.len() - 1)));
+                self.scope(&let_values.body, tail);
+                return;
+            }
+            Expr::Lambda(lambda) | Expr::CaseLambda(lambda) => {
+                self.lambdas.push(lambda.clone());
+                let i = index(self.lambdas.len() - 1);
+                match expr {
+                    Expr::Lambda(_) => self.emit(Op::Closure(i)),
+                    _ => self.emit(Op::CaseClosure(i)),
+                };
+            }
+            Expr::Call(var, name, args) => {
+                match var {
+                    VarRef::Local { depth, index: i } => self.emit(Op::LocalFunction {
+                        depth: index(*depth),
+                        index: index(*i),
+                        name: *name,
+                    }),
+                    VarRef::Global(cell) => {
+                        let i = self.global(cell, *name);
+                        self.emit(Op::GlobalFunction(i))
+                    }
+                };
+                for arg in args {
+                    self.expr(arg, false);
+                }
+                let argc = index(args.len());
+                self.emit(if tail {
+                    Op::TailCall(argc)
+                } else {
+                    Op::Call(argc)
+                });
+                return;
+            }
+            Expr::Combination(head, args) => {
+                self.expr(head, false);
+                for arg in args {
+                    self.expr(arg, false);
+                }
+                let argc = index(args.len());
+                self.emit(if tail {
+                    Op::TailCombine(argc)
+                } else {
+                    Op::Combine(argc)
+                });
+                return;
+            }
+            Expr::BinaryOp(op, left, right) => {
+                self.expr(left, false);
+                self.expr(right, false);
+                let i = match self.operators.iter().position(|o| o == op) {
+                    Some(i) => i,
+                    None => {
+                        self.operators.push(op.clone());
+                        self.operators.len() - 1
+                    }
+                };
+                self.emit(Op::BinaryOp(index(i)));
+            }
+            Expr::List(items) => {
+                for item in items {
+                    self.expr(item, false);
+                }
+                self.emit(Op::List(index(items.len())));
+            }
+            Expr::Vector(items) => {
+                for item in items {
+                    self.expr(item, false);
+                }
+                self.emit(Op::Vector(index(items.len())));
+            }
+            Expr::HashTable(entries) => {
+                for (_, value) in entries {
+                    self.expr(value, false);
+                }
+                self.tables
+                    .push(entries.iter().map(|(key, _)| key.clone()).collect());
+                self.emit(Op::HashTable(index(self.tables.len() - 1)));
+            }
+            Expr::Delay(expr) => {
+                self.exprs.push(expr.clone());
+                self.emit(Op::Delay(index(self.exprs.len() - 1)));
+            }
+            Expr::ConsStream(head, tail) => {
+                self.expr(head, false);
+                self.exprs.push(tail.clone());
+                self.emit(Op::ConsStream(index(self.exprs.len() - 1)));
+            }
+            Expr::Error(err) => {
+                self.errors.push(err.clone());
+                self.emit(Op::Fail(index(self.errors.len() - 1)));
+            }
+        }
+        if tail {
+            self.emit(Op::Return);
+        }
+    }
+
+    fn body(&mut self, body: &Body, tail: bool) {
+        for expr in body.init.iter() {
+            self.expr(expr, false);
+            self.emit(Op::Pop);
+        }
+        self.expr(&body.last, tail);
+    }
+
+    /// Compiles the body of a `let` whose frame was just entered. In tail
+    /// position the frame is left by returning.
+    fn scope(&mut self, body: &Body, tail: bool) {
+        self.body(body, tail);
+        if !tail {
+            self.emit(Op::LeaveFrame);
+        }
+    }
+
+    /// A clause without expressions yields its test, which is `#t` when the
+    /// clause is taken.
+    fn cond(&mut self, clauses: &[CondClause], tail: bool) {
+        let mut ends = Vec::new();
+        for clause in clauses {
+            self.expr(&clause.test, false);
+            let branch = self.emit(Op::Branch(0));
+            match &clause.body {
+                Some(body) => self.body(body, tail),
+                None => self.expr(&Expr::Const(Object::Bool(true)), tail),
+            }
+            if !tail {
+                ends.push(self.emit(Op::Jump(0)));
+            }
+            self.patch(branch);
+        }
+        self.expr(&Expr::Const(Object::Void), tail);
+        for end in ends {
+            self.patch(end);
+        }
+    }
+}
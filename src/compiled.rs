@@ -0,0 +1,439 @@
+//! Precompiled programs: the `.rispc` files written by `risp compile`.
+//!
+//! A file holds the parsed code of a program, so loading it skips lexing
+//! and parsing. Bytecode is not stored: it refers to the global cells of
+//! the environment it was resolved against, so it is produced again when
+//! the loaded code is evaluated.
+//!
+//! The layout is, with integers in little endian:
+//!
+//! ```text
+//! magic     8 bytes  "RISPC\0\r\n"
+//! version   u16      FORMAT_VERSION
+//! flags     u16      0, reserved
+//! length    u32      length of the payload
+//! checksum  u64      FNV-1a hash of the payload
+//! payload   pool     u32 count, then each entry as u32 length and UTF-8
+//!           code     u32 count, then each top-level form as a tagged object
+//! ```
+//!
+//! Strings, symbols, keywords and operators are kept once in the constant
+//! pool and referred to by index. Files of another version, and files whose
+//! checksum does not match, are rejected.
+
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+
+use crate::{
+    hash_table::{HashKey, HashTable},
+    object::Object,
+    parser::{parse_program, ParseError},
+    symbol::Symbol,
+    sync::{Rc, RefCell},
+};
+
+/// Starts every compiled file. The line ending catches files mangled by a
+/// text mode transfer.
+pub const MAGIC: &[u8; 8] = b"RISPC\0\r\n";
+
+/// Version of the layout and of the encoding of objects. Bumped on any
+/// change, as older readers cannot load newer files and the other way
+/// around.
+pub const FORMAT_VERSION: u16 = 2;
+
+const HEADER_LEN: usize = MAGIC.len() + 2 + 2 + 4 + 8;
+
+/// Deepest nesting of lists accepted when loading, so a crafted file
+/// cannot exhaust the stack.
+const MAX_NESTING: usize = 4096;
+
+const TAG_VOID: u8 = 0;
+const TAG_INTEGER: u8 = 1;
+const TAG_FLOAT: u8 = 2;
+const TAG_BOOL: u8 = 3;
+const TAG_CHAR: u8 = 4;
+const TAG_STRING: u8 = 5;
+const TAG_SYMBOL: u8 = 6;
+const TAG_KEYWORD: u8 = 7;
+const TAG_KEYWORD_LITERAL: u8 = 8;
+const TAG_IF: u8 = 9;
+const TAG_BINARY_OP: u8 = 10;
+const TAG_LIST: u8 = 11;
+const TAG_VECTOR: u8 = 12;
+const TAG_HASH_TABLE: u8 = 13;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadError {
+    /// The data does not start with `MAGIC`.
+    NotCompiled,
+    /// The file was written with another `FORMAT_VERSION`.
+    Version(u16),
+    /// The file is truncated, its checksum does not match or it does not
+    /// decode.
+    Corrupt(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotCompiled => write!(f, "Load error: not a compiled risp file"),
+            LoadError::Version(version) => write!(
+                f,
+                "Load error: compiled with format version {}, expected {}",
+                version, FORMAT_VERSION
+            ),
+            LoadError::Corrupt(err) => write!(f, "Load error: corrupt file: {}", err),
+        }
+    }
+}
+
+impl Error for LoadError {}
+
+/// Parses `program` and encodes it in the compiled format.
+pub fn compile(program: &str) -> Result<Vec<u8>, ParseError> {
+    let forms = parse_program(program)?;
+    let mut writer = Writer::default();
+    writer.code.extend(len_u32(forms.len()).to_le_bytes());
+    for form in forms.iter() {
+        writer.object(form);
+    }
+
+    let mut payload = Vec::new();
+    payload.extend(len_u32(writer.pool.len()).to_le_bytes());
+    for entry in writer.pool.iter() {
+        payload.extend(len_u32(entry.len()).to_le_bytes());
+        payload.extend(entry.as_bytes());
+    }
+    payload.extend(writer.code);
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend(MAGIC);
+    bytes.extend(FORMAT_VERSION.to_le_bytes());
+    bytes.extend(0u16.to_le_bytes());
+    bytes.extend(len_u32(payload.len()).to_le_bytes());
+    bytes.extend(checksum(&payload).to_le_bytes());
+    bytes.extend(payload);
+    Ok(bytes)
+}
+
+/// Checks and decodes a compiled file back to the top-level forms of the
+/// program.
+pub fn load(bytes: &[u8]) -> Result<Vec<Object>, LoadError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(LoadError::NotCompiled);
+    }
+    let mut header = Reader {
+        bytes: &bytes[MAGIC.len()..],
+        pool: Vec::new(),
+    };
+    let version = u16::from_le_bytes(header.array()?);
+    if version != FORMAT_VERSION {
+        return Err(LoadError::Version(version));
+    }
+    let flags = u16::from_le_bytes(header.array()?);
+    if flags != 0 {
+        return Err(LoadError::Corrupt(format!("unknown flags {:#x}", flags)));
+    }
+    let len = u32::from_le_bytes(header.array()?) as usize;
+    let expected = u64::from_le_bytes(header.array()?);
+    let payload = header.bytes;
+    if payload.len() != len {
+        return Err(LoadError::Corrupt(format!(
+            "expected {} bytes of code, found {}",
+            len,
+            payload.len()
+        )));
+    }
+    if checksum(payload) != expected {
+        return Err(LoadError::Corrupt("checksum mismatch".to_string()));
+    }
+
+    let mut reader = Reader {
+        bytes: payload,
+        pool: Vec::new(),
+    };
+    let count = reader.u32()?;
+    for _ in 0..count {
+        let len = reader.u32()? as usize;
+        let entry = std::str::from_utf8(reader.take(len)?)
+            .map_err(|_| LoadError::Corrupt("invalid UTF-8 in constant pool".to_string()))?;
+        reader.pool.push(entry.to_string());
+    }
+    let count = reader.u32()?;
+    let mut forms = Vec::new();
+    for _ in 0..count {
+        forms.push(reader.object(0)?);
+    }
+    if !reader.bytes.is_empty() {
+        return Err(LoadError::Corrupt("trailing bytes after code".to_string()));
+    }
+    Ok(forms)
+}
+
+/// 64-bit FNV-1a.
+fn checksum(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+fn len_u32(len: usize) -> u32 {
+    u32::try_from(len).expect("program too large to compile")
+}
+
+#[derive(Default)]
+struct Writer {
+    pool: Vec<String>,
+    indices: HashMap<String, u32>,
+    code: Vec<u8>,
+}
+
+impl Writer {
+    fn constant(&mut self, s: &str) {
+        let i = match self.indices.get(s) {
+            Some(i) => *i,
+            None => {
+                let i = len_u32(self.pool.len());
+                self.pool.push(s.to_string());
+                self.indices.insert(s.to_string(), i);
+                i
+            }
+        };
+        self.code.extend(i.to_le_bytes());
+    }
+
+    fn items<'a>(&mut self, tag: u8, items: impl ExactSizeIterator<Item = &'a Object>) {
+        self.code.push(tag);
+        self.code.extend(len_u32(items.len()).to_le_bytes());
+        for item in items {
+            self.object(item);
+        }
+    }
+
+    /// Encodes parsed code. Values that only exist at run time, e.g.
+    /// procedures, never come out of the parser.
+    fn object(&mut self, obj: &Object) {
+        match obj {
+            Object::Void => self.code.push(TAG_VOID),
+            Object::Integer(n) => {
+                self.code.push(TAG_INTEGER);
+                self.code.extend(n.to_le_bytes());
+            }
+            Object::Float(f) => {
+                self.code.push(TAG_FLOAT);
+                self.code.extend(f.to_bits().to_le_bytes());
+            }
+            Object::Bool(b) => {
+                self.code.push(TAG_BOOL);
+                self.code.push(u8::from(*b));
+            }
+            Object::Char(c) => {
+                self.code.push(TAG_CHAR);
+                self.code.extend(u32::from(*c).to_le_bytes());
+            }
+            Object::String(s) => {
+                self.code.push(TAG_STRING);
+                self.constant(s);
+            }
+            Object::Symbol(s) => {
+                self.code.push(TAG_SYMBOL);
+                self.constant(s.as_str());
+            }
+            Object::Keyword(s) => {
+                self.code.push(TAG_KEYWORD);
+                self.constant(s);
+            }
+            Object::KeywordLiteral(s) => {
+                self.code.push(TAG_KEYWORD_LITERAL);
+                self.constant(s);
+            }
+            Object::If => self.code.push(TAG_IF),
+            Object::BinaryOp(op) => {
+                self.code.push(TAG_BINARY_OP);
+                self.constant(op);
+            }
+            Object::List(list) => self.items(TAG_LIST, list.iter()),
+            Object::Vector(vector) => self.items(TAG_VECTOR, vector.borrow().iter()),
+            Object::HashTable(table) => {
+                let table = table.borrow();
+                // Keys and values alternate, as in the literal.
+                self.code.push(TAG_HASH_TABLE);
+                self.code.extend(len_u32(table.len() * 2).to_le_bytes());
+                for (key, value) in table.iter() {
+                    self.object(&key.to_object());
+                    self.object(value);
+                }
+            }
+            _ => unreachable!("not parsed code: {}", obj),
+        }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pool: Vec<String>,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], LoadError> {
+        if self.bytes.len() < len {
+            return Err(LoadError::Corrupt("unexpected end of file".to_string()));
+        }
+        let (taken, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(taken)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], LoadError> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn u32(&mut self) -> Result<u32, LoadError> {
+        Ok(u32::from_le_bytes(self.array()?))
+    }
+
+    fn constant(&mut self) -> Result<&str, LoadError> {
+        let i = self.u32()? as usize;
+        match self.pool.get(i) {
+            Some(entry) => Ok(entry),
+            None => Err(LoadError::Corrupt(format!("constant {} out of range", i))),
+        }
+    }
+
+    fn items(&mut self, nesting: usize) -> Result<Vec<Object>, LoadError> {
+        if nesting >= MAX_NESTING {
+            return Err(LoadError::Corrupt("code nested too deeply".to_string()));
+        }
+        let len = self.u32()? as usize;
+        // Every item takes at least one byte, which bounds the allocation.
+        let mut items = Vec::with_capacity(len.min(self.bytes.len()));
+        for _ in 0..len {
+            items.push(self.object(nesting + 1)?);
+        }
+        Ok(items)
+    }
+
+    fn object(&mut self, nesting: usize) -> Result<Object, LoadError> {
+        let tag = self.array::<1>()?[0];
+        Ok(match tag {
+            TAG_VOID => Object::Void,
+            TAG_INTEGER => Object::Integer(i64::from_le_bytes(self.array()?)),
+            TAG_FLOAT => Object::Float(f64::from_bits(u64::from_le_bytes(self.array()?))),
+            TAG_BOOL => match self.array::<1>()?[0] {
+                0 => Object::Bool(false),
+                1 => Object::Bool(true),
+                b => return Err(LoadError::Corrupt(format!("invalid boolean {}", b))),
+            },
+            TAG_CHAR => {
+                let c = self.u32()?;
+                match char::from_u32(c) {
+                    Some(c) => Object::Char(c),
+                    None => return Err(LoadError::Corrupt(format!("invalid character {:#x}", c))),
+                }
+            }
+            TAG_STRING => Object::String(self.constant()?.into()),
+            TAG_SYMBOL => Object::Symbol(Symbol::intern(self.constant()?)),
+            TAG_KEYWORD => Object::Keyword(self.constant()?.to_string()),
+            TAG_KEYWORD_LITERAL => Object::KeywordLiteral(self.constant()?.to_string()),
+            TAG_IF => Object::If,
+            TAG_BINARY_OP => Object::BinaryOp(self.constant()?.to_string()),
+            TAG_LIST => Object::List(Rc::new(self.items(nesting)?)),
+            TAG_VECTOR => Object::Vector(Rc::new(RefCell::new(self.items(nesting)?))),
+            TAG_HASH_TABLE => {
+                let items = self.items(nesting)?;
+                let mut table = HashTable::new();
+                for pair in items.chunks(2) {
+                    let (key, value) = match pair {
+                        [key, value] => (key, value),
+                        _ => return Err(LoadError::Corrupt("odd hash table".to_string())),
+                    };
+                    let hash_key = HashKey::from_object(key)
+                        .ok_or_else(|| LoadError::Corrupt(format!("unhashable key {}", key)))?;
+                    table.insert(hash_key, value.clone());
+                }
+                Object::HashTable(Rc::new(RefCell::new(table)))
+            }
+            tag => return Err(LoadError::Corrupt(format!("unknown tag {}", tag))),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::env::Env;
+    use crate::eval::eval_compiled;
+
+    const PROGRAM: &str = r#"(
+        (define greet (lambda (name &key (greeting "Hello"))
+            (string-join (list greeting name) ", ")))
+        (greet "world" :greeting "Hi")
+        (if (> 2.5 1) #\a false)
+        #(1 "two" :three)
+        (hash-ref {:a 1 "b" (1 2)} "b")
+    )"#;
+
+    #[test]
+    fn test_round_trip() {
+        let bytes = compile(PROGRAM).unwrap();
+        assert!(bytes.starts_with(MAGIC));
+        assert_eq!(load(&bytes).unwrap(), parse_program(PROGRAM).unwrap());
+
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let result = eval_compiled(&bytes, &mut env).unwrap();
+        assert_eq!(result.to_string(), "(Hi, world a #(1 two :three) (1 2))");
+    }
+
+    #[test]
+    fn test_multiple_top_level_forms() {
+        let bytes = compile("(define x 1) (+ x 41)").unwrap();
+        assert_eq!(load(&bytes).unwrap().len(), 2);
+
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let result = eval_compiled(&bytes, &mut env).unwrap();
+        assert_eq!(result, Object::Integer(42));
+
+        assert!(compile("((define x 1) (+ x 41)").is_err());
+    }
+
+    #[test]
+    fn test_constants_are_pooled() {
+        let once = compile("(define x \"some long string\")").unwrap();
+        let twice =
+            compile("((define x \"some long string\") (define y \"some long string\"))").unwrap();
+        assert!(twice.len() < 2 * once.len());
+    }
+
+    #[test]
+    fn test_rejects_incompatible_files() {
+        let bytes = compile(PROGRAM).unwrap();
+        assert_eq!(load(b"(+ 1 2)"), Err(LoadError::NotCompiled));
+
+        let mut newer = bytes.clone();
+        newer[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&3u16.to_le_bytes());
+        assert_eq!(load(&newer), Err(LoadError::Version(3)));
+        assert_eq!(
+            load(&newer).unwrap_err().to_string(),
+            "Load error: compiled with format version 3, expected 2"
+        );
+
+        let mut corrupted = bytes.clone();
+        *corrupted.last_mut().unwrap() ^= 1;
+        assert_eq!(
+            load(&corrupted),
+            Err(LoadError::Corrupt("checksum mismatch".to_string()))
+        );
+
+        for len in [MAGIC.len() + 3, HEADER_LEN, bytes.len() - 1] {
+            assert!(matches!(load(&bytes[..len]), Err(LoadError::Corrupt(_))));
+        }
+
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let err = eval_compiled(&corrupted, &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Load error: corrupt file: checksum mismatch"
+        );
+    }
+}
@@ -0,0 +1,60 @@
+//! Escape-only continuations captured by `call/cc`.
+//!
+//! Invoking a continuation returns an `EvalError::Escape` that unwinds the
+//! Rust stack up to the `call/cc` that captured it, which then returns the
+//! passed value. `dynamic-wind` sees the escape like any other error on its
+//! way out. Once its `call/cc` has returned, a continuation can no longer be
+//! invoked: re-entering it would need the unwound Rust frames back.
+
+use std::fmt;
+
+use crate::eval::EvalError;
+use crate::object::Object;
+use crate::sync::{Cell, Rc};
+
+pub struct Continuation {
+    live: Cell<bool>,
+}
+
+impl Continuation {
+    pub fn new() -> Rc<Self> {
+        Rc::new(Continuation {
+            live: Cell::new(true),
+        })
+    }
+
+    /// Whether the `call/cc` that captured this continuation is still running.
+    pub fn is_live(&self) -> bool {
+        self.live.get()
+    }
+
+    /// Called by `call/cc` when it returns, normally or not.
+    pub fn end(&self) {
+        self.live.set(false);
+    }
+
+    /// The error that carries `args` back to the capturing `call/cc`.
+    pub fn invoke(self: &Rc<Self>, args: Vec<Object>) -> EvalError {
+        if !self.is_live() {
+            return "Continuation called after its call/cc returned: only escaping continuations are supported".into();
+        }
+        // Several arguments become several values of the `call/cc`.
+        let value = match args.len() {
+            0 => Object::Void,
+            _ => Object::from_values(args),
+        };
+        EvalError::Escape(self.clone(), Box::new(value))
+    }
+}
+
+impl PartialEq for Continuation {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Continuation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Continuation")
+    }
+}
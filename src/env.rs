@@ -1,40 +1,159 @@
+use crate::builtins::{self, Capability};
 use crate::object::Object;
-use std::cell::RefCell;
-use std::collections::HashMap;
-use std::rc::Rc;
+use crate::runtime::{var_size, Runtime};
+use crate::symbol::{Symbol, SymbolMap};
+use crate::sync::{Rc, RefCell};
+use std::mem::size_of;
 
+/// Storage of one variable, shared between the frame or environment that
+/// binds it and the code and closures that refer to it. Empty until the
+/// variable is defined.
+pub type Cell = Rc<RefCell<Option<Object>>>;
+
+/// The global environment: builtins and top-level definitions.
+///
+/// Resolved code refers to globals by their cell, so a cell is created as
+/// soon as a name is referred to and stays empty until it is defined.
 #[derive(Debug, PartialEq, Default)]
 pub struct Env {
-    parent: Option<Rc<RefCell<Env>>>,
-    vars: HashMap<String, Object>,
+    vars: SymbolMap<Cell>,
+    runtime: Rc<Runtime>,
+    size: usize,
 }
 
 impl Env {
+    /// Creates a global environment with the builtins of every capability.
     pub fn new() -> Self {
-        Default::default()
+        Env::with_capabilities(Capability::ALL)
+    }
+
+    /// Creates a global environment with only the builtins of the given
+    /// capabilities, e.g. to run untrusted scripts without filesystem access.
+    pub fn with_capabilities(capabilities: &[Capability]) -> Self {
+        let mut env = Env::default();
+        builtins::install(&mut env, capabilities);
+        env
+    }
+
+    pub fn runtime(&self) -> Rc<Runtime> {
+        self.runtime.clone()
+    }
+
+    pub fn get(&self, name: impl Into<Symbol>) -> Option<Object> {
+        self.vars
+            .get(&name.into())
+            .and_then(|cell| cell.borrow().clone())
+    }
+
+    pub fn set(&mut self, name: impl Into<Symbol>, val: Object) {
+        let cell = self.cell(name.into());
+        if cell.borrow_mut().replace(val).is_none() {
+            let size = var_size();
+            self.runtime.account(size);
+            self.size += size;
+        }
     }
 
-    pub fn extend(parent: Rc<RefCell<Self>>) -> Env {
-        let mut vars = HashMap::new();
-        vars.extend(parent.borrow().vars.iter().map(|(k, v)| (k.clone(), v.clone())));
+    /// The cell of a global, created empty if the name is not defined yet.
+    pub fn cell(&mut self, name: Symbol) -> Cell {
+        self.vars.entry(name).or_default().clone()
+    }
+}
 
-        Env {
-            vars: vars,
-            parent: None,
+impl Drop for Env {
+    /// Also empties the globals, which resolved code refers to by their
+    /// cells: procedures that refer to each other through globals would
+    /// otherwise keep each other alive.
+    fn drop(&mut self) {
+        self.runtime.release(self.size);
+        for cell in self.vars.values() {
+            if let Ok(mut val) = cell.try_borrow_mut() {
+                val.take();
+            }
         }
     }
+}
 
-    pub fn get(&self, name: &str) -> Option<Object> {
-        match self.vars.get(name) {
-            Some(value) => Some(value.clone()),
-            None => self
+/// The variables of one procedure call or `let`, addressed by index.
+///
+/// Frames of nested `let`s within a procedure are chained through `parent`.
+/// The outermost frame of a call has the closure's captured variables as its
+/// parent, so every local is reached by a `(depth, index)` pair.
+#[derive(Debug)]
+pub struct Frame {
+    slots: Vec<Cell>,
+    parent: Option<Rc<Frame>>,
+    runtime: Rc<Runtime>,
+}
+
+impl Frame {
+    /// A frame of `size` empty variables.
+    pub fn new(size: usize, parent: Option<Rc<Frame>>, runtime: Rc<Runtime>) -> Rc<Frame> {
+        let slots = (0..size).map(|_| Cell::default()).collect();
+        Frame::with_slots(slots, parent, runtime)
+    }
+
+    /// A frame sharing existing cells, e.g. the variables a closure
+    /// captures.
+    pub fn with_slots(
+        slots: Vec<Cell>,
+        parent: Option<Rc<Frame>>,
+        runtime: Rc<Runtime>,
+    ) -> Rc<Frame> {
+        runtime.account(Frame::size(slots.len()));
+        runtime.frame_created();
+        Rc::new(Frame {
+            slots,
+            parent,
+            runtime,
+        })
+    }
+
+    fn size(len: usize) -> usize {
+        size_of::<Frame>() + len * var_size()
+    }
+
+    pub fn runtime(&self) -> &Rc<Runtime> {
+        &self.runtime
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The cell of the variable `index` in the frame `depth` levels up.
+    pub fn cell(&self, depth: usize, index: usize) -> &Cell {
+        let mut frame = self;
+        for _ in 0..depth {
+            frame = frame
                 .parent
-                .as_ref()
-                .and_then(|o| o.borrow().get(name).clone()),
+                .as_deref()
+                .expect("resolved variable outside of its frames");
         }
+        &frame.slots[index]
     }
 
-    pub fn set(&mut self, name: &str, val: Object) {
-        self.vars.insert(name.to_string(), val);
+    pub fn slots(&self) -> &[Cell] {
+        &self.slots
     }
-}
\ No newline at end of file
+
+    /// The frame of the enclosing `let` or procedure call, if any.
+    pub fn parent(&self) -> Option<&Rc<Frame>> {
+        self.parent.as_ref()
+    }
+
+    pub fn set(&self, index: usize, val: Object) {
+        *self.slots[index].borrow_mut() = Some(val);
+    }
+}
+
+impl Drop for Frame {
+    fn drop(&mut self) {
+        self.runtime.release(Frame::size(self.slots.len()));
+        self.runtime.frame_dropped();
+    }
+}
@@ -1,440 +1,685 @@
-use std::cell::RefCell;
-use std::clone;
 use std::cmp::Ordering;
-use std::rc::Rc;
+use std::error::Error;
+use std::fmt;
 
-use crate::{env::Env, object::Object, parser::parse};
+use crate::{
+    builtins::{self, hash, vector, Capability},
+    bytecode, compiled,
+    continuation::Continuation,
+    env::{Env, Frame},
+    hash_table::HashTable,
+    lazy::{Promise, Stream},
+    object::Object,
+    parser::parse_program,
+    resolve::{resolve, Body, Closure, CondClause, Expr, Formals, Lambda, LetValues, VarRef},
+    runtime::{list_size, Engine, Limits},
+    symbol::Symbol,
+    sync::{Rc, RefCell},
+    vm,
+};
 
-fn eval_obj(obj: &Object, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let mut current_obj = Box::new(obj.clone());
-    let mut current_env = env.clone();
-    loop {
-        match *current_obj {
-            Object::List(list) => {
-                let head = &list[0];
-                match head {
-                    Object::BinaryOp(_) => {
-                        return eval_binary_op(&list, &mut current_env);
-                    }
-                    Object::Keyword(_) => {
-                        return eval_keyword(&list, &mut current_env);
-                    }
-                    Object::If => {
-                        if list.len() != 4 {
-                            return Err(format!("Invalid number of arguments for if statement"));
-                        }
-                        let cond_obj = eval_obj(&list[1], &mut current_env)?;
-                        let cond = match cond_obj {
-                            Object::Bool(b) => b,
-                            _ => return Err(format!("Condition must be a boolean")),
-                        };
-                        if cond {
-                            current_obj = Box::new(list[2].clone());
-                        } else {
-                            current_obj = Box::new(list[3].clone())
-                        }
-                        continue;
-                    }
-                    Object::Symbol(s) => {
-                        let lambda = current_env.borrow_mut().get(s);
-                        if lambda.is_none() {
-                            return Err(format!("Unbound function: {}", s));
-                        }
-                        let func = lambda.unwrap();
-                        match func {
-                            Object::Lambda(params, body, lambda_env) => {
-                                let new_env =
-                                    Rc::new(RefCell::new(Env::extend(lambda_env.clone())));
-                                for (i, param) in params.iter().enumerate() {
-                                    let val = eval_obj(&list[i + 1], &mut current_env)?;
-                                    new_env.borrow_mut().set(param, val);
-                                }
-                                current_obj = Box::new(Object::List(body));
-                                current_env = new_env.clone();
-                                continue;
-                            }
-                            _ => return Err(format!("Not a lambda: {}", s)),
-                        }
-                    }
-                    _ => {
-                        let mut new_list = Vec::new();
-                        for obj in list {
-                            let result = eval_obj(&obj, &mut current_env)?;
-                            match result {
-                                Object::Void => {}
-                                _ => new_list.push(result),
-                            }
-                        }
-                        return Ok(Object::List(new_list));
-                    }
-                }
-            }
-            Object::Symbol(s) => {
-                return eval_symbol(&s, &mut current_env);
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    /// An error in the program itself, e.g. an unbound symbol or a type mismatch.
+    Runtime(String),
+    /// Evaluation went as deep as `Runtime::max_depth`, or as deep as the
+    /// native stack allows, at the given depth.
+    StackOverflow(usize),
+    /// The step budget given in `Limits::fuel` ran out.
+    OutOfFuel,
+    /// `Limits::deadline` passed before evaluation finished.
+    Timeout,
+    /// The flag in `Limits::cancel` was set.
+    Cancelled,
+    /// The values and environments alive at once would take more than
+    /// `Limits::max_memory`.
+    OutOfMemory(usize),
+    /// A builtin was used whose capability the environment was not given.
+    MissingCapability(Capability, String),
+    /// A continuation was invoked with this value. Caught by the `call/cc`
+    /// that captured it.
+    Escape(Rc<Continuation>, Box<Object>),
+}
+
+impl fmt::Display for EvalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EvalError::Runtime(err) => write!(f, "{}", err),
+            EvalError::StackOverflow(depth) => {
+                write!(f, "Stack overflow at evaluation depth {}", depth)
             }
-            Object::Void => return Ok(Object::Void),
-            Object::Lambda(_, _, _) => return Ok(obj.clone()),
-            Object::Bool(_) => return Ok(obj.clone()),
-            Object::Integer(n) => return Ok(Object::Integer(n)),
-            Object::Float(n) => return Ok(Object::Float(n)),
-            Object::String(s) => return Ok(Object::String(s.to_string())),
-            Object::ListData(l) => return Ok(Object::ListData(l.to_vec())),
-            _ => return Err(format!("Invalid object: {:?},", obj)),
+            EvalError::OutOfFuel => write!(f, "Out of fuel: step budget exhausted"),
+            EvalError::Timeout => write!(f, "Timeout: deadline passed"),
+            EvalError::Cancelled => write!(f, "Evaluation cancelled"),
+            EvalError::MissingCapability(capability, name) => write!(
+                f,
+                "Missing capability: {} needs the {} capability",
+                name, capability
+            ),
+            EvalError::OutOfMemory(max_memory) => write!(
+                f,
+                "Out of memory: allocation limit of {} bytes exceeded",
+                max_memory
+            ),
+            EvalError::Escape(_, _) => write!(f, "Continuation invoked outside of its call/cc"),
         }
     }
-    /*
-    match obj {
-        Object::Symbol(s) => eval_symbol(s, env),
-        Object::List(list) => eval_list(list, env),
-        _ => Ok(obj.clone())
+}
+
+impl Error for EvalError {}
+
+impl From<String> for EvalError {
+    fn from(err: String) -> Self {
+        EvalError::Runtime(err)
     }
-     */
 }
 
-fn eval_symbol(s: &str, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let val = match s {
-        "true" => return Ok(Object::Bool(true)),
-        "false" => return Ok(Object::Bool(false)),
-        "nil" => return Ok(Object::Void),
-        _ => env.borrow_mut().get(s),
-    };
-    if val.is_none() {
-        return Err(format!("Unbound symbol: {}", s));
-    } else {
-        Ok(val.unwrap())
+impl From<&str> for EvalError {
+    fn from(err: &str) -> Self {
+        EvalError::Runtime(err.to_string())
     }
 }
 
-pub fn eval(program: &str, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let parsed_list = parse(program);
-    if parsed_list.is_err() {
-        return Err(format!("{}", parsed_list.err().unwrap()));
+/// Evaluates resolved code, with `frame` holding the locals it refers to.
+pub(crate) fn eval_expr(
+    expr: &Expr,
+    frame: &Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    // Constants and variables are looked up without taking a step or a
+    // level of depth.
+    match expr {
+        Expr::Const(val) => return Ok(val.clone()),
+        Expr::Var(var, name) => return lookup(var, *name, frame, "Unbound symbol"),
+        _ => {}
+    }
+    let runtime = env.borrow().runtime();
+    let _depth = runtime.enter()?;
+    runtime.tick()?;
+    let mut next = step(expr, frame, env)?;
+    loop {
+        match next {
+            Call::Return(val) => return Ok(val),
+            Call::Tail(expr, frame) => {
+                runtime.tick()?;
+                next = step(&expr, &frame, env)?;
+            }
+        }
     }
-    eval_obj(&parsed_list.unwrap(), env)
 }
 
-fn eval_define(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    if list.len() != 3 {
-        return Err(format!("Invalid number of arguments for define"));
+/// Evaluates `expr` up to the expression in its tail position, which is left
+/// to the loop in `eval_expr`.
+fn step(expr: &Expr, frame: &Rc<Frame>, env: &mut Rc<RefCell<Env>>) -> Result<Call, EvalError> {
+    match expr {
+        Expr::Const(val) => Ok(Call::Return(val.clone())),
+        Expr::Var(var, name) => Ok(Call::Return(lookup(var, *name, frame, "Unbound symbol")?)),
+        Expr::Define(var, name, value) => {
+            let val = eval_expr(value, frame, env)?;
+            match var {
+                VarRef::Local { depth, index } => {
+                    *frame.cell(*depth, *index).borrow_mut() = Some(val);
+                }
+                VarRef::Global(_) => env.borrow_mut().set(*name, val),
+            }
+            Ok(Call::Return(Object::Void))
+        }
+        Expr::If(cond, then, otherwise) => match eval_expr(cond, frame, env)? {
+            Object::Bool(true) => Ok(Call::Tail(then.clone(), frame.clone())),
+            Object::Bool(false) => Ok(Call::Tail(otherwise.clone(), frame.clone())),
+            _ => Err("Condition must be a boolean".into()),
+        },
+        Expr::Begin(body) => eval_body(body, frame, env),
+        Expr::Cond(clauses) => eval_cond(clauses, frame, env),
+        Expr::Let(let_) => {
+            let runtime = env.borrow().runtime();
+            let new_frame = Frame::new(let_.size, Some(frame.clone()), runtime);
+            for (i, init) in let_.inits.iter().enumerate() {
+                new_frame.set(i, eval_expr(init, frame, env)?);
+            }
+            eval_body(&let_.body, &new_frame, env)
+        }
+        Expr::LetValues(let_values) => eval_let_values(let_values, frame, env),
+        Expr::Lambda(lambda) => Ok(Call::Return(Object::Lambda(close(lambda, frame, env)))),
+        Expr::CaseLambda(lambda) => Ok(Call::Return(Object::CaseLambda(close(lambda, frame, env)))),
+        Expr::Call(var, name, args) => {
+            let func = lookup(var, *name, frame, "Unbound function")?;
+            if !is_procedure(&func) {
+                return Err(format!("Not a lambda: {}", name).into());
+            }
+            let args = eval_args(args, frame, env)?;
+            enter_procedure(&func, args, env)
+        }
+        Expr::Combination(head, args) => {
+            let head_val = eval_expr(head, frame, env)?;
+            if is_procedure(&head_val) {
+                let args = eval_args(args, frame, env)?;
+                return enter_procedure(&head_val, args, env);
+            }
+
+            let mut new_list = Vec::new();
+            if head_val != Object::Void {
+                new_list.push(head_val);
+            }
+            for arg in args {
+                match eval_expr(arg, frame, env)? {
+                    Object::Void => {}
+                    result => new_list.push(result),
+                }
+            }
+            let size = list_size(new_list.len());
+            let list = Object::List(new_list.into());
+            Ok(Call::Return(env.borrow().runtime().allocate(list, size)?))
+        }
+        Expr::BinaryOp(op, left, right) => {
+            let left = eval_expr(left, frame, env)?;
+            let right = eval_expr(right, frame, env)?;
+            Ok(Call::Return(binary_op(op, &left, &right, env)?))
+        }
+        Expr::List(items) => {
+            let elms = eval_args(items, frame, env)?;
+            let list = Object::ListData(elms.into());
+            Ok(Call::Return(
+                env.borrow()
+                    .runtime()
+                    .allocate(list, list_size(items.len()))?,
+            ))
+        }
+        Expr::Vector(items) => {
+            let elms = eval_args(items, frame, env)?;
+            Ok(Call::Return(vector::new_vector(elms, env)?))
+        }
+        Expr::HashTable(entries) => {
+            let mut table = HashTable::new();
+            for (key, value) in entries {
+                table.insert(key.clone(), eval_expr(value, frame, env)?);
+            }
+            Ok(Call::Return(hash::new_table(table, env)?))
+        }
+        // `(delay expr)` returns a promise to evaluate `expr` when forced.
+        Expr::Delay(expr) => Ok(Call::Return(Object::Promise(Promise::delay(
+            expr.clone(),
+            frame.clone(),
+        )))),
+        // `(cons-stream head tail)` evaluates `head` now and delays `tail`.
+        Expr::ConsStream(head, tail) => {
+            let head = eval_expr(head, frame, env)?;
+            let tail = Promise::delay(tail.clone(), frame.clone());
+            Ok(Call::Return(Object::Stream(Rc::new(Stream { head, tail }))))
+        }
+        Expr::Error(err) => Err(err.clone()),
     }
-    let sym = match &list[1] {
-        Object::Symbol(s) => s.clone(),
-        _ => return Err(format!("Invalid define")),
+}
+
+fn lookup(var: &VarRef, name: Symbol, frame: &Frame, err: &str) -> Result<Object, EvalError> {
+    let val = match var {
+        VarRef::Local { depth, index } => frame.cell(*depth, *index).borrow().clone(),
+        VarRef::Global(cell) => cell.borrow().clone(),
     };
-    let val = eval_obj(&list[2], env)?;
-    env.borrow_mut().set(&sym, val);
-    Ok(Object::Void)
+    val.ok_or_else(|| unbound(err, name.as_str()))
+}
+
+/// Creates a closure of `lambda`, capturing the variables it uses from
+/// `frame`.
+pub(crate) fn close(lambda: &Rc<Lambda>, frame: &Frame, env: &Rc<RefCell<Env>>) -> Rc<Closure> {
+    let captured = lambda
+        .captures
+        .iter()
+        .map(|(depth, index)| frame.cell(*depth, *index).clone())
+        .collect();
+    let runtime = env.borrow().runtime();
+    let closure = Rc::new(Closure {
+        lambda: lambda.clone(),
+        captured: Frame::with_slots(captured, None, runtime.clone()),
+    });
+    runtime.watch_closure(&closure);
+    closure
 }
 
-fn eval_list_data(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let mut elms = Vec::new();
-    for obj in list[1..].iter() {
-        elms.push(eval_obj(obj, env)?);
+/// Evaluates every argument expression of a call from left to right.
+fn eval_args(
+    args: &[Expr],
+    frame: &Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Vec<Object>, EvalError> {
+    let mut vals = Vec::with_capacity(args.len());
+    for arg in args {
+        vals.push(eval_expr(arg, frame, env)?);
     }
-    Ok(Object::ListData(elms))
+    Ok(vals)
 }
 
-fn eval_map(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    if list.len() != 3 {
+/// Binds arguments to the slots of `formals` in `frame`. Defaults of
+/// optional parameters are evaluated in it, so they can refer to earlier
+/// parameters.
+pub(crate) fn bind_params(
+    formals: &Formals,
+    args: Vec<Object>,
+    frame: &Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<(), EvalError> {
+    let params = &formals.params;
+    if !params.accepts(args.len()) {
         return Err(format!(
-            "Invalid arity for map function. map accepts only two args."
-        ));
-    }
-    let lambda = eval_obj(&list[1], env)?;
-    let coll = eval_obj(&list[2], env)?;
-    let (params, body, lambda_env) = match lambda {
-        Object::Lambda(p, b, e) => {
-            if p.len() != 1 {
-                return Err(format!(
-                    "Invalid number of parameters for map lambda function {:?}",
-                    p
-                ));
-            }
-            (p, b, e)
+            "Invalid number of arguments for lambda ({}): expected {}, found {}",
+            params,
+            params.arity(),
+            args.len()
+        )
+        .into());
+    }
+    let mut args = args.into_iter();
+    for i in 0..params.required.len() {
+        frame.set(formals.start + i, args.next().unwrap());
+    }
+    for i in 0..params.optional.len() {
+        let val = match args.next() {
+            Some(val) => val,
+            None => eval_expr(&formals.defaults[i], frame, env)?,
+        };
+        frame.set(formals.optional_slot(i), val);
+    }
+    let rest_args = args.collect::<Vec<_>>();
+    if !params.keys.is_empty() {
+        bind_keys(formals, &rest_args, frame, env)?;
+    }
+    if params.rest.is_some() {
+        let size = list_size(rest_args.len());
+        let rest = Object::ListData(rest_args.into());
+        frame.set(
+            formals.rest_slot(),
+            env.borrow().runtime().allocate(rest, size)?,
+        );
+    }
+    Ok(())
+}
+
+/// Binds `:name value` pairs to the keyword parameters of a lambda. Keywords
+/// that are not passed get their default value.
+fn bind_keys(
+    formals: &Formals,
+    args: &[Object],
+    frame: &Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<(), EvalError> {
+    let params = &formals.params;
+    let mut given: Vec<(Symbol, &Object)> = Vec::new();
+    for pair in args.chunks(2) {
+        let name = match &pair[0] {
+            Object::KeywordLiteral(name) => Symbol::intern(name),
+            arg => return Err(format!("Expected a keyword argument but found {}", arg).into()),
+        };
+        if !params.keys.iter().any(|(key, _)| *key == name) {
+            return Err(
+                format!("Unknown keyword argument :{} for lambda ({})", name, params).into(),
+            );
         }
-        _ => return Err(format!("Not a lambda while evaluating map: {}", lambda)),
-    };
+        if given.iter().any(|(key, _)| *key == name) {
+            return Err(format!("Keyword argument :{} given twice", name).into());
+        }
+        match pair.get(1) {
+            Some(val) => given.push((name, val)),
+            None => return Err(format!("Missing value for keyword argument :{}", name).into()),
+        }
+    }
+    for (i, (name, _)) in params.keys.iter().enumerate() {
+        let val = match given.iter().find(|(key, _)| key == name) {
+            Some((_, val)) => (*val).clone(),
+            None => eval_expr(&formals.defaults[params.optional.len() + i], frame, env)?,
+        };
+        frame.set(formals.key_slot(i), val);
+    }
+    Ok(())
+}
 
-    let items = match coll {
-        Object::ListData(list) => list,
-        _ => return Err(format!("Invalid map arguments: {:?}", list)),
-    };
+/// Evaluates all but the last expression of a body and leaves the last one
+/// to be evaluated in tail position.
+fn eval_body(
+    body: &Body,
+    frame: &Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Call, EvalError> {
+    for expr in body.init.iter() {
+        eval_expr(expr, frame, env)?;
+    }
+    Ok(Call::Tail(body.last.clone(), frame.clone()))
+}
 
-    let first_arg = &params[0];
-    let mut result_list = Vec::new();
-    for item in items.iter() {
-        let val = eval_obj(&item, env)?;
-        let mut new_env = Rc::new(RefCell::new(Env::extend(lambda_env.clone())));
-        new_env.borrow_mut().set(&first_arg, val);
-        let new_body = body.clone();
-        let result = eval_obj(&Object::List(new_body), &mut new_env)?;
-        result_list.push(result);
-    }
-    Ok(Object::ListData(result_list))
+pub fn is_procedure(obj: &Object) -> bool {
+    matches!(
+        obj,
+        Object::Lambda(_) | Object::CaseLambda(_) | Object::Builtin(_) | Object::Continuation(_)
+    )
 }
 
-fn eval_filter(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    if list.len() != 3 {
-        return Err(format!(
-            "Invalid arity for filter function. filter accepts only 2 args"
-        ));
-    }
-    let lambda = eval_obj(&list[1], env)?;
-    let coll = eval_obj(&list[2], env)?;
-    let (params, body, lambda_env) = match lambda {
-        Object::Lambda(p, b, e) => {
-            if p.len() != 1 {
-                return Err(format!(
-                    "Invalid number of parameters for filter lambda function {:?}",
-                    p
-                ));
-            }
-            (p, b, e)
-        }
-        _ => return Err(format!("Not a lambda while evaluating filter {:?}", lambda)),
-    };
+/// Result of calling a procedure or evaluating a step of an expression:
+/// either a value, or the expression left in tail position together with
+/// the frame it must be evaluated in.
+enum Call {
+    Return(Object),
+    Tail(Rc<Expr>, Rc<Frame>),
+}
 
-    let items = match coll {
-        Object::ListData(list) => list,
-        _ => {
-            return Err(format!(
-                "Invalid filter arguments. Second argument must be a list but found : {:?}",
-                list
-            ))
-        }
-    };
+/// Result of entering a procedure: the value of a builtin, or the clause
+/// of a closure whose body is left to run in a frame with its arguments
+/// bound.
+pub(crate) enum Entry {
+    Return(Object),
+    Clause(Rc<Closure>, usize, Rc<Frame>),
+}
 
-    let first_arg = &params[0];
-    let mut result_list = Vec::new();
-    for item in items.iter() {
-        let val = eval_obj(&item, env)?;
-        let mut new_env = Rc::new(RefCell::new(Env::extend(lambda_env.clone())));
-        new_env.borrow_mut().set(&first_arg, val.clone());
-        let new_body = body.clone();
-        match eval_obj(&Object::List(new_body), &mut new_env)? {
-            Object::Bool(b) => {
-                if b {
-                    result_list.push(val);
-                }
-            }
-            _ => continue,
-        }
+fn enter_procedure(
+    func: &Object,
+    args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Call, EvalError> {
+    match enter(func, args, env)? {
+        Entry::Return(val) => Ok(Call::Return(val)),
+        Entry::Clause(closure, i, frame) => eval_body(&closure.lambda.clauses[i].body, &frame, env),
     }
-    Ok(Object::ListData(result_list))
 }
 
-fn eval_reduce(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    if list.len() != 4 {
-        return Err(format!(
-            "Invalid arity for reduce function. reduce accepts only 3 args"
-        ));
-    }
-    let lambda = eval_obj(&list[1], env)?;
-    let coll = eval_obj(&list[3], env)?;
-    let (params, body, lambda_env) = match lambda {
-        Object::Lambda(p, b, e) => {
-            if p.len() != 2 {
-                return Err(format!(
-                    "Invalid number of parameters for reduce lambda function {:?}",
-                    p
-                ));
+/// Calls `func` up to the body of the clause it runs. Shared by both
+/// engines, so they agree on how arguments are bound.
+pub(crate) fn enter(
+    func: &Object,
+    args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Entry, EvalError> {
+    match func {
+        Object::Lambda(closure) => enter_clause(closure, 0, args, env),
+        Object::CaseLambda(closure) => {
+            let clause = closure
+                .lambda
+                .clauses
+                .iter()
+                .position(|clause| clause.formals.params.accepts(args.len()));
+            match clause {
+                Some(i) => enter_clause(closure, i, args, env),
+                None => Err(format!(
+                    "No case-lambda clause accepts {} arguments: {}",
+                    args.len(),
+                    func
+                )
+                .into()),
             }
-            (p, b, e)
         }
-        _ => return Err(format!("Not a lambda whle evaluating reduce {:?}", lambda)),
-    };
-    let items = match coll {
-        Object::ListData(data) => data,
-        _ => {
-            return Err(format!(
-                "Invalid reduce argumetns. Second arguments must be a list but found {:?}",
-                coll
-            ))
+        // apply, funcall and call-with-values call their procedure in tail
+        // position.
+        Object::Builtin(builtin) if builtin.name == "apply" => {
+            let (func, args) = builtins::spread_args(args)?;
+            enter(&func, args, env)
         }
-    };
-    let arg_a = &params[0];
-    let arg_b = &params[1];
-    let mut a = eval_obj(&list[2], env)?;
-    for i in 0..items.len() {
-        let b = eval_obj(&items[i], env)?;
-        let new_env = Rc::new(RefCell::new(Env::extend(lambda_env.clone())));
-        new_env.borrow_mut().set(arg_a, a);
-        new_env.borrow_mut().set(arg_b, b);
-        a = eval_obj(&Object::List(body.clone()), &mut new_env.clone())?;
-    }
-    Ok(a)
+        Object::Builtin(builtin) if builtin.name == "funcall" => {
+            let (func, args) = builtins::funcall_args(args)?;
+            enter(&func, args, env)
+        }
+        Object::Builtin(builtin) if builtin.name == "call-with-values" => {
+            let (func, args) = builtins::produce_values(args, env)?;
+            enter(&func, args, env)
+        }
+        Object::Builtin(builtin) => Ok(Entry::Return((builtin.func)(&args, env)?)),
+        Object::Continuation(k) => Err(k.invoke(args)),
+        _ => Err(format!("Not a lambda: {}", func).into()),
+    }
 }
 
-fn eval_binary_op(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let operator = list[0].clone();
-    let left = &eval_obj(&list[1].clone(), env)?;
-    let right = &eval_obj(&list[2].clone(), env)?;
-    match operator {
-        Object::BinaryOp(s) => match s.as_str() {
-            "+" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Integer(l + r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 + r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l + r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l + *r as f64)),
-                (Object::String(l), Object::String(r)) => Ok(Object::String(l.to_owned() + r)),
-                _ => Err(format!("Invalid types for + operator {} {}", left, right)),
-            },
-            "-" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Integer(l - r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l - r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 - r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l - *r as f64)),
-                _ => Err(format!("Invalid types for - operator {} {}", left, right)),
-            },
-            "*" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Integer(l * r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l * r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 * r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l * (*r) as f64)),
-                _ => Err(format!("Invalid types for * operator {} {}", left, right)),
-            },
-            "/" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Integer(l / r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l / r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 / r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l / (*r) as f64)),
-                _ => Err(format!("Invalid types for / operator {} {}", left, right)),
-            },
-            "%" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Integer(l % r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l % r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 % r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l % (*r) as f64)),
-                _ => Err(format!("Invalid types for % operator {} {}", left, right)),
-            },
-            "<" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l < r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Bool(l < r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Bool((*l as f64) < *r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Bool(l < &(*r as f64))),
-                (Object::String(l), Object::String(r)) => {
-                    Ok(Object::Bool(l.cmp(&r) == Ordering::Less))
-                }
-                _ => Err(format!("Invalid types for < operator {} {}", left, right)),
-            },
-            ">" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l > r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Bool(l > r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Bool(*l as f64 > *r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Bool(l > &(*r as f64))),
-                (Object::String(l), Object::String(r)) => {
-                    Ok(Object::Bool(l.cmp(&r) == Ordering::Greater))
-                }
-                _ => Err(format!("Invalid types for > operator {} {}", left, right)),
-            },
-            "=" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l == r)),
-                (Object::String(l), Object::String(r)) => Ok(Object::Bool(l == r)),
-                _ => Err(format!("Invalid types for == operator {} {}", left, right)),
-            },
-            "!=" => match (left, right) {
-                (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l != r)),
-                (Object::Float(l), Object::Float(r)) => Ok(Object::Bool(l != r)),
-                (Object::Integer(l), Object::Float(r)) => Ok(Object::Bool(*l as f64 != *r)),
-                (Object::Float(l), Object::Integer(r)) => Ok(Object::Bool(*l != (*r) as f64)),
-                (Object::String(l), Object::String(r)) => {
-                    Ok(Object::Bool(l.cmp(&r) != Ordering::Equal))
-                }
-                _ => Err(format!("Invalid types for != operator {} {}", left, right)),
-            },
-            "&" => match (left, right) {
-                (Object::Bool(l), Object::Bool(r)) => Ok(Object::Bool(*l && *r)),
-                _ => Err(format!("Invalid types for & operator {} {}", left, right)),
-            },
-            "|" => match (left, right) {
-                (Object::Bool(l), Object::Bool(r)) => Ok(Object::Bool(*l || *r)),
-                _ => Err(format!("Invalid types for | operator {} {}", left, right)),
-            },
-            _ => Err(format!("Invalid infix operator: {}", s)),
-        },
-        _ => Err(format!("Operator must be a symbol")),
+/// Binds the arguments of a call to clause `i` of `closure` in a new frame.
+fn enter_clause(
+    closure: &Rc<Closure>,
+    i: usize,
+    args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Entry, EvalError> {
+    let clause = &closure.lambda.clauses[i];
+    let runtime = env.borrow().runtime();
+    let frame = Frame::new(clause.size, Some(closure.captured.clone()), runtime);
+    bind_params(&clause.formals, args, &frame, env)?;
+    Ok(Entry::Clause(closure.clone(), i, frame))
+}
+
+/// Calls a procedure from Rust code, e.g. from `map`, with the engine
+/// selected in the runtime. Tail calls inside a lambda body are still
+/// handled by the engine.
+pub fn apply(
+    func: &Object,
+    args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    if env.borrow().runtime().engine() == Engine::Bytecode {
+        return vm::apply(func, args, env);
+    }
+    match enter_procedure(func, args, env)? {
+        Call::Return(val) => Ok(val),
+        Call::Tail(body, frame) => eval_expr(&body, &frame, env),
+    }
+}
+
+/// Error for a name that is not bound. Names of builtins left out of the
+/// environment are reported as a missing capability instead.
+pub(crate) fn unbound(err: &str, name: &str) -> EvalError {
+    match builtins::find(name) {
+        Some(builtin) => EvalError::MissingCapability(builtin.capability, name.to_string()),
+        None => format!("{}: {}", err, name).into(),
     }
 }
 
-fn eval_function_definition(
-    list: &Vec<Object>,
+/// Evaluates the top-level forms of `program` in order and returns the value
+/// of the last one.
+pub fn eval(program: &str, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let forms = parse_program(program).map_err(|e| e.to_string())?;
+    eval_forms(&forms, env)
+}
+
+/// Evaluates a program compiled by `compiled::compile`, e.g. the contents
+/// of a `.rispc` file.
+pub fn eval_compiled(bytes: &[u8], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let forms = compiled::load(bytes).map_err(|e| e.to_string())?;
+    eval_forms(&forms, env)
+}
+
+fn eval_forms(forms: &[Object], env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let mut result = Object::Void;
+    for form in forms {
+        result = eval_parsed(form, env)?;
+    }
+    Ok(result)
+}
+
+fn eval_parsed(parsed_list: &Object, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+    let expr = resolve(parsed_list, env);
+    let runtime = env.borrow().runtime();
+    let frame = Frame::new(0, None, runtime.clone());
+    match runtime.engine() {
+        Engine::TreeWalker => eval_expr(&expr, &frame, env),
+        Engine::Bytecode => vm::run(Rc::new(bytecode::compile(&expr)), frame, env),
+    }
+}
+
+/// Evaluates `program` like `eval`, but aborts once any of `limits` is hit.
+/// The limits only apply to this call, and `Limits::max_memory` to the memory
+/// it holds on top of what was held before. `Runtime::peak_memory`
+/// afterwards, less `Runtime::memory_usage` before, tells how much this call
+/// used.
+pub fn eval_with_limits(
+    program: &str,
     env: &mut Rc<RefCell<Env>>,
-) -> Result<Object, String> {
-    let params = match &list[1] {
-        Object::List(list) => {
-            let mut params = Vec::new();
-            for param in list {
-                match param {
-                    Object::Symbol(s) => params.push(s.clone()),
-                    _ => return Err(format!("Invalid lambda parameter")),
-                }
-            }
-            params
+    limits: Limits,
+) -> Result<Object, EvalError> {
+    let runtime = env.borrow().runtime();
+    let previous = runtime.set_limits(limits);
+    runtime.reset_peak_memory();
+    let result = eval(program, env);
+    runtime.set_limits(previous);
+    result
+}
+
+/// Applies an integer operator, failing instead of overflowing or dividing
+/// by zero.
+fn integer_op(
+    s: &str,
+    l: i64,
+    r: i64,
+    op: fn(i64, i64) -> Option<i64>,
+) -> Result<Object, EvalError> {
+    match op(l, r) {
+        Some(n) => Ok(Object::Integer(n)),
+        None if r == 0 && (s == "/" || s == "%") => {
+            Err(format!("Division by zero in {} operator {} {}", s, l, r).into())
         }
-        _ => return Err(format!("Invalid lambda")),
-    };
-    let body = match &list[2] {
-        Object::List(list) => list.clone(),
-        _ => return Err(format!("Invalid lambda")),
-    };
-    Ok(Object::Lambda(params, body, env.clone()))
+        None => Err(format!("Integer overflow in {} operator {} {}", s, l, r).into()),
+    }
 }
 
-fn eval_function_call(
-    name: &str,
-    list: &Vec<Object>,
+/// Finds the first `cond` clause whose test is true and leaves its last
+/// expression to be evaluated in tail position. A clause without
+/// expressions yields its test value.
+fn eval_cond(
+    clauses: &[CondClause],
+    frame: &Rc<Frame>,
     env: &mut Rc<RefCell<Env>>,
-) -> Result<Object, String> {
-    let lambda = env.borrow_mut().get(name);
-    if lambda.is_none() {
-        return Err(format!("Unbound symbol: {}", name));
-    }
-    let func = lambda.unwrap();
-    return if let Object::Lambda(params, body, closure_env) = func {
-        let mut new_env = Rc::new(RefCell::new(Env::extend(closure_env.clone())));
-        for (i, param) in params.iter().enumerate() {
-            let val = eval_obj(&list[i + 1], env)?;
-            new_env.borrow_mut().set(param, val);
+) -> Result<Call, EvalError> {
+    for clause in clauses {
+        let test = eval_expr(&clause.test, frame, env)?;
+        match (test, &clause.body) {
+            (Object::Bool(false), _) => continue,
+            (test @ Object::Bool(true), None) => return Ok(Call::Return(test)),
+            (Object::Bool(true), Some(body)) => return eval_body(body, frame, env),
+            _ => return Err("Condition must be a boolean".into()),
         }
-        eval_obj(&Object::List(body), &mut new_env)
-    } else {
-        Err(format!("Not a lambda: {}", name))
-    };
+    }
+    Ok(Call::Return(Object::Void))
 }
 
-fn eval_keyword(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let head = &list[0];
-    match head {
-        Object::Keyword(s) => match s.as_str() {
-            "define" => eval_define(&list, env),
-            "list" => eval_list_data(&list, env),
-            "print" => print_list(&list, env),
-            "lambda" => eval_function_definition(&list, env),
-            "map" => eval_map(&list, env),
-            "filter" => eval_filter(&list, env),
-            "reduce" => eval_reduce(&list, env),
-            _ => Err(format!("Invalid keyword: {}", head)),
-        },
-        _ => Err(format!("Invalid keyword: {}", head)),
+/// Binds `(let-values ((formals expr) ...) body ...)`. The values of each
+/// expression are bound to its formals like arguments to a lambda with
+/// that parameter list.
+fn eval_let_values(
+    let_values: &LetValues,
+    frame: &Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Call, EvalError> {
+    // All expressions are evaluated before anything is bound, as in `let`.
+    let mut bound = Vec::with_capacity(let_values.bindings.len());
+    for (_, init) in let_values.bindings.iter() {
+        bound.push(eval_expr(init, frame, env)?.into_values());
     }
+    let runtime = env.borrow().runtime();
+    let new_frame = Frame::new(let_values.size, Some(frame.clone()), runtime);
+    for ((formals, _), values) in let_values.bindings.iter().zip(bound) {
+        bind_params(formals, values, &new_frame, env)?;
+    }
+    eval_body(&let_values.body, &new_frame, env)
 }
 
-fn print_list(list: &Vec<Object>, env: &mut Rc<RefCell<Env>>) -> Result<Object, String> {
-    let mut new_list = Vec::new();
-
-    for obj in list[1..].iter() {
-        new_list.push(eval_obj(obj, env)?);
-    }
-    for obj in new_list.iter() {
-        print!("{} ", obj);
+/// Applies a binary operator to two evaluated operands. Also backs the
+/// builtins that make the operators usable as values, e.g. `(apply + xs)`.
+pub fn binary_op(
+    s: &str,
+    left: &Object,
+    right: &Object,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    match s {
+        "+" => match (left, right) {
+            (Object::Integer(l), Object::Integer(r)) => integer_op(s, *l, *r, i64::checked_add),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 + r)),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l + r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l + *r as f64)),
+            (Object::String(l), Object::String(r)) => {
+                let s = Object::String(format!("{}{}", l, r).into());
+                env.borrow().runtime().allocate(s, l.len() + r.len())
+            }
+            _ => Err(format!("Invalid types for + operator {} {}", left, right).into()),
+        },
+        "-" => match (left, right) {
+            (Object::Integer(l), Object::Integer(r)) => integer_op(s, *l, *r, i64::checked_sub),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l - r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 - r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l - *r as f64)),
+            _ => Err(format!("Invalid types for - operator {} {}", left, right).into()),
+        },
+        "*" => match (left, right) {
+            (Object::Integer(l), Object::Integer(r)) => integer_op(s, *l, *r, i64::checked_mul),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l * r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 * r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l * (*r) as f64)),
+            _ => Err(format!("Invalid types for * operator {} {}", left, right).into()),
+        },
+        "/" => match (left, right) {
+            (Object::Integer(l), Object::Integer(r)) => integer_op(s, *l, *r, i64::checked_div),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l / r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 / r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l / (*r) as f64)),
+            _ => Err(format!("Invalid types for / operator {} {}", left, right).into()),
+        },
+        "%" => match (left, right) {
+            (Object::Integer(l), Object::Integer(r)) => integer_op(s, *l, *r, i64::checked_rem),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Float(l % r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Float(*l as f64 % r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Float(l % (*r) as f64)),
+            _ => Err(format!("Invalid types for % operator {} {}", left, right).into()),
+        },
+        "<" => match (left, right) {
+            (Object::Char(l), Object::Char(r)) => Ok(Object::Bool(l < r)),
+            (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l < r)),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Bool(l < r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Bool((*l as f64) < *r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Bool(l < &(*r as f64))),
+            (Object::String(l), Object::String(r)) => Ok(Object::Bool(l.cmp(r) == Ordering::Less)),
+            _ => Err(format!("Invalid types for < operator {} {}", left, right).into()),
+        },
+        ">" => match (left, right) {
+            (Object::Char(l), Object::Char(r)) => Ok(Object::Bool(l > r)),
+            (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l > r)),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Bool(l > r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Bool(*l as f64 > *r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Bool(l > &(*r as f64))),
+            (Object::String(l), Object::String(r)) => {
+                Ok(Object::Bool(l.cmp(r) == Ordering::Greater))
+            }
+            _ => Err(format!("Invalid types for > operator {} {}", left, right).into()),
+        },
+        "=" => match (left, right) {
+            (Object::Char(l), Object::Char(r)) => Ok(Object::Bool(l == r)),
+            (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l == r)),
+            (Object::String(l), Object::String(r)) => Ok(Object::Bool(l == r)),
+            (Object::KeywordLiteral(l), Object::KeywordLiteral(r)) => Ok(Object::Bool(l == r)),
+            // Persistent collections are values, equal when their contents are.
+            (Object::PersistentVector(l), Object::PersistentVector(r)) => Ok(Object::Bool(l == r)),
+            (Object::PersistentMap(l), Object::PersistentMap(r)) => Ok(Object::Bool(l == r)),
+            _ => Err(format!("Invalid types for == operator {} {}", left, right).into()),
+        },
+        "!=" => match (left, right) {
+            (Object::Char(l), Object::Char(r)) => Ok(Object::Bool(l != r)),
+            (Object::Integer(l), Object::Integer(r)) => Ok(Object::Bool(l != r)),
+            (Object::Float(l), Object::Float(r)) => Ok(Object::Bool(l != r)),
+            (Object::Integer(l), Object::Float(r)) => Ok(Object::Bool(*l as f64 != *r)),
+            (Object::Float(l), Object::Integer(r)) => Ok(Object::Bool(*l != (*r) as f64)),
+            (Object::String(l), Object::String(r)) => Ok(Object::Bool(l.cmp(r) != Ordering::Equal)),
+            (Object::KeywordLiteral(l), Object::KeywordLiteral(r)) => Ok(Object::Bool(l != r)),
+            (Object::PersistentVector(l), Object::PersistentVector(r)) => Ok(Object::Bool(l != r)),
+            (Object::PersistentMap(l), Object::PersistentMap(r)) => Ok(Object::Bool(l != r)),
+            _ => Err(format!("Invalid types for != operator {} {}", left, right).into()),
+        },
+        "&" => match (left, right) {
+            (Object::Bool(l), Object::Bool(r)) => Ok(Object::Bool(*l && *r)),
+            _ => Err(format!("Invalid types for & operator {} {}", left, right).into()),
+        },
+        "|" => match (left, right) {
+            (Object::Bool(l), Object::Bool(r)) => Ok(Object::Bool(*l || *r)),
+            _ => Err(format!("Invalid types for | operator {} {}", left, right).into()),
+        },
+        _ => Err(format!("Invalid infix operator: {}", s).into()),
     }
-    println!("");
-    Ok(Object::Void)
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
 
     #[test]
     fn test_simple_add() {
@@ -443,6 +688,21 @@ mod tests {
         assert_eq!(result, Object::Integer(3))
     }
 
+    #[test]
+    fn test_multiple_top_level_forms() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let result = eval("(define x 1) (+ x 41)", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(42));
+
+        let result = eval("((define x 1) (+ x 41)", &mut env);
+        assert_eq!(
+            result,
+            Err(EvalError::Runtime(
+                "Parse error: Unterminated list".to_string()
+            ))
+        );
+    }
+
     #[test]
     fn test_area_of_a_circle() {
         let mut env = Rc::new(RefCell::new(Env::new()));
@@ -450,7 +710,7 @@ mod tests {
         let result = eval(program, &mut env).unwrap();
         assert_eq!(
             result,
-            Object::List(vec![Object::Integer((314 * 10 * 10) as i64)])
+            Object::List(Rc::new(vec![Object::Integer(314 * 10 * 10)]))
         )
     }
 
@@ -459,7 +719,7 @@ mod tests {
         let mut env = Rc::new(RefCell::new(Env::new()));
         let program = "( (define sqr (lambda (r) (* r r))) (sqr 10) )";
         let result = eval(program, &mut env).unwrap();
-        assert_eq!(result, Object::List(vec![Object::Integer(100)]));
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(100)])));
     }
 
     #[test]
@@ -473,7 +733,7 @@ mod tests {
         ";
 
         let result = eval(program, &mut env).unwrap();
-        assert_eq!(result, Object::List(vec![Object::Integer((120) as i64)]));
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(120)])));
     }
 
     #[test]
@@ -489,13 +749,13 @@ mod tests {
         let result = eval(program, &mut env).unwrap();
         assert_eq!(
             result,
-            Object::List(vec![Object::ListData(vec![
+            Object::List(Rc::new(vec![Object::ListData(Rc::new(vec![
                 Object::Integer(1),
                 Object::Integer(4),
                 Object::Integer(9),
                 Object::Integer(16),
                 Object::Integer(25),
-            ])])
+            ]))]))
         )
     }
 
@@ -508,7 +768,7 @@ mod tests {
             (reduce add 0 coll)
         )";
         let result = eval(program, &mut env).unwrap();
-        assert_eq!(result, Object::List(vec![Object::Integer(15)]))
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(15)])))
     }
 
     #[test]
@@ -524,7 +784,7 @@ mod tests {
             (sum-n 100 0)
         )";
         let result = eval(program, &mut env).unwrap();
-        assert_eq!(result, Object::List(vec![Object::Integer(5050)]))
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(5050)])))
     }
 
     #[test]
@@ -536,7 +796,446 @@ mod tests {
                 (lambda (b) (+ a b))))
             (add 10 20)
         )";
+        // Extra arguments are an error since procedures check their arity.
+        let err = eval(program, &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for lambda (a): expected 1, found 2"
+        );
+    }
+
+    #[test]
+    fn test_curried_closure() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define add
+              (lambda (a)
+                (lambda (b) (+ a b))))
+            ((add 10) 20)
+        )";
         let result = eval(program, &mut env).unwrap();
-        assert_eq!(result, Object::List(vec![Object::Integer(30)]))
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(30)])))
+    }
+
+    #[test]
+    fn test_mutual_recursion_tail_calls() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define my-even? (lambda (n) (if (= n 0) true (my-odd? (- n 1)))))
+            (define my-odd? (lambda (n) (if (= n 0) false (my-even? (- n 1)))))
+            (my-even? 1000000)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Bool(true)])))
+    }
+
+    #[test]
+    fn test_tail_calls_in_begin_let_and_cond() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define count-down
+              (lambda (n)
+                (cond
+                  ((= n 0) (begin (define done 1) done))
+                  (else (let ((m (- n 1))) (count-down m))))))
+            (count-down 100000)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(1)])))
+    }
+
+    #[test]
+    fn test_tail_calls_in_head_lambda_and_map() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define loop (lambda (n a) (if (= n 0) a ((lambda (m) (loop m (+ a 1))) (- n 1)))))
+            (map (lambda (n) (loop n 0)) (list 100000 1))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![Object::ListData(Rc::new(vec![
+                Object::Integer(100000),
+                Object::Integer(1),
+            ]))]))
+        )
+    }
+
+    #[test]
+    fn test_stack_overflow_is_an_error() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        env.borrow().runtime().set_max_depth(100);
+        let program = "(
+            (define f (lambda (n) (+ 1 (f n))))
+            (f 1)
+        )";
+        let err = eval(program, &mut env).unwrap_err();
+        assert_eq!(err, EvalError::StackOverflow(100));
+
+        let result = eval("(+ 1 2)", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(3));
+    }
+
+    #[test]
+    fn test_stack_overflow_on_a_small_thread() {
+        // 2MB is the default for `std::thread` and most runtimes, and too
+        // small for the default depth.
+        let thread = std::thread::Builder::new().stack_size(2 << 20);
+        let handle = thread.spawn(|| {
+            let programs = [
+                "((define f (lambda (n) (+ 1 (f n)))) (f 1))",
+                "((define f (lambda (n) (map (lambda (x) (f x)) (list n)))) (f 1))",
+                "((define f (lambda (n) (sort (lambda (a b) (f a)) (list n n)))) (f 1))",
+                "((define f (lambda (n) (force (delay (f n))))) (f 1))",
+                "((define f (lambda (n) (call/cc (lambda (k) (+ 1 (f n)))))) (f 1))",
+                "((define f (lambda (n) (vector-map (lambda (x) (f x)) #(1)))) (f 1))",
+            ];
+            for program in programs {
+                for engine in [Engine::TreeWalker, Engine::Bytecode] {
+                    let mut env = Rc::new(RefCell::new(Env::new()));
+                    env.borrow().runtime().set_engine(engine);
+                    let err = eval(program, &mut env).unwrap_err();
+                    assert!(matches!(err, EvalError::StackOverflow(_)), "{}", program);
+                }
+            }
+        });
+        handle.unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn test_tail_calls_do_not_count_towards_max_depth() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        env.borrow().runtime().set_max_depth(100);
+        let program = "(
+            (define sum-n (lambda (n a) (if (= n 0) a (sum-n (- n 1) (+ n a)))))
+            (sum-n 1000 0)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(500500)])))
+    }
+
+    #[test]
+    fn test_fuel_limit() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define forever (lambda (n) (forever (+ n 1))))
+            (forever 0)
+        )";
+        let limits = Limits {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+        let err = eval_with_limits(program, &mut env, limits).unwrap_err();
+        assert_eq!(err, EvalError::OutOfFuel);
+
+        let limits = Limits {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+        let result = eval_with_limits("(+ 1 2)", &mut env, limits).unwrap();
+        assert_eq!(result, Object::Integer(3));
+    }
+
+    #[test]
+    fn test_deadline_limit() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define forever (lambda (n) (forever (+ n 1))))
+            (forever 0)
+        )";
+        let limits = Limits {
+            deadline: Some(Instant::now() + Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let err = eval_with_limits(program, &mut env, limits).unwrap_err();
+        assert_eq!(err, EvalError::Timeout);
+    }
+
+    #[test]
+    fn test_cancellation() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define forever (lambda (n) (forever (+ n 1))))
+            (forever 0)
+        )";
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let canceller = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(50));
+            flag.store(true, AtomicOrdering::Relaxed);
+        });
+        let limits = Limits {
+            cancel: Some(cancel),
+            ..Default::default()
+        };
+        let err = eval_with_limits(program, &mut env, limits).unwrap_err();
+        canceller.join().unwrap();
+        assert_eq!(err, EvalError::Cancelled);
+
+        // Limits only apply to the call they were given to.
+        let result = eval("(+ 1 2)", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(3));
+    }
+
+    #[test]
+    fn test_memory_limit() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define grow (lambda (s n) (if (= n 0) s (grow (+ s s) (- n 1)))))
+            (grow \"ab\" 64)
+        )";
+        let limits = Limits {
+            max_memory: Some(1 << 20),
+            ..Default::default()
+        };
+        let err = eval_with_limits(program, &mut env, limits).unwrap_err();
+        assert_eq!(err, EvalError::OutOfMemory(1 << 20));
+    }
+
+    #[test]
+    fn test_dropped_values_release_memory() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        // Each list is dropped before the next one is built, so only one
+        // is alive at a time although together they go over the limit.
+        let program = "(
+            (define loop (lambda (n) (if (= n 0) 0 (begin (range 0 10000) (loop (- n 1))))))
+            (loop 100)
+        )";
+        let limits = Limits {
+            max_memory: Some(1 << 20),
+            ..Default::default()
+        };
+        let result = eval_with_limits(program, &mut env, limits).unwrap();
+        assert_eq!(result, Object::List(Rc::new(vec![Object::Integer(0)])));
+
+        let runtime = env.borrow().runtime();
+        eval("(define xs (range 0 1000))", &mut env).unwrap();
+        let usage = runtime.memory_usage();
+        assert!(usage >= list_size(1000));
+        eval("(define xs 0)", &mut env).unwrap();
+        assert!(runtime.memory_usage() <= usage - list_size(1000));
+    }
+
+    #[test]
+    fn test_memory_limit_counts_from_the_memory_held_before() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval(
+            "(define kept (list (range 0 10000) (range 0 10000)))",
+            &mut env,
+        )
+        .unwrap();
+        let runtime = env.borrow().runtime();
+        let before = runtime.memory_usage();
+        assert!(before >= 2 * list_size(10000));
+
+        let limits = || Limits {
+            max_memory: Some(3 * list_size(10000)),
+            ..Default::default()
+        };
+        let program = "(define more (list (range 0 10000) (range 0 10000)))";
+        eval_with_limits(program, &mut env, limits()).unwrap();
+        assert!(runtime.memory_usage() >= before + 2 * list_size(10000));
+        assert!(runtime.peak_memory() - before >= 2 * list_size(10000));
+
+        let program = "(define too-much (list (range 0 20000) (range 0 20000)))";
+        let err = eval_with_limits(program, &mut env, limits()).unwrap_err();
+        assert_eq!(err, EvalError::OutOfMemory(3 * list_size(10000)));
+    }
+
+    #[test]
+    fn test_integer_arithmetic_errors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let cases = [
+            ("(/ 1 0)", "Division by zero in / operator 1 0"),
+            ("(% 1 0)", "Division by zero in % operator 1 0"),
+            (
+                "(+ 9223372036854775807 1)",
+                "Integer overflow in + operator 9223372036854775807 1",
+            ),
+            (
+                "(* 9223372036854775807 2)",
+                "Integer overflow in * operator 9223372036854775807 2",
+            ),
+            (
+                "(/ (- (- 0 9223372036854775807) 1) -1)",
+                "Integer overflow in / operator -9223372036854775808 -1",
+            ),
+            ("(apply / (list 1 0))", "Division by zero in / operator 1 0"),
+        ];
+        for (program, message) in cases {
+            let err = eval(program, &mut env).unwrap_err();
+            assert_eq!(err.to_string(), message, "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_peak_memory() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define sqr (lambda (r) (* r r)))
+            (map sqr (list 1 2 3 4 5))
+        )";
+        eval_with_limits(program, &mut env, Limits::default()).unwrap();
+        let runtime = env.borrow().runtime();
+        assert!(runtime.peak_memory() >= list_size(10));
+        assert!(runtime.peak_memory() >= runtime.memory_usage());
+    }
+
+    #[test]
+    fn test_rest_parameters() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            ((lambda (a . rest) rest) 1 2 3)
+            ((lambda args args) 1 2)
+            ((lambda (a &rest rest) rest) 1)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::ListData(Rc::new(vec![Object::Integer(2), Object::Integer(3)])),
+                Object::ListData(Rc::new(vec![Object::Integer(1), Object::Integer(2)])),
+                Object::ListData(Rc::new(vec![])),
+            ]))
+        )
+    }
+
+    #[test]
+    fn test_optional_parameters() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define add (lambda (a &optional (b (* a 10)) c) (+ a b)))
+            (add 1)
+            (add 1 2)
+            (add 1 2 3)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Integer(11),
+                Object::Integer(3),
+                Object::Integer(3),
+            ]))
+        )
+    }
+
+    #[test]
+    fn test_arity_errors() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval("(define sqr (lambda (r) (* r r)))", &mut env).unwrap();
+        let err = eval("(sqr 1 2)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for lambda (r): expected 1, found 2"
+        );
+        let err = eval("(sqr)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for lambda (r): expected 1, found 0"
+        );
+
+        eval("(define f (lambda (a &optional b . c) a))", &mut env).unwrap();
+        let err = eval("(f)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for lambda (a &optional (b Void) . c): expected at least 1, found 0"
+        );
+    }
+
+    #[test]
+    fn test_case_lambda() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define area
+              (case-lambda
+                ((r) (* r r))
+                ((w h) (* w h))
+                ((w h . more) (list w h more))))
+            (area 3)
+            (area 2 5)
+            (area 1 2 3)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::Integer(9),
+                Object::Integer(10),
+                Object::ListData(Rc::new(vec![
+                    Object::Integer(1),
+                    Object::Integer(2),
+                    Object::ListData(Rc::new(vec![Object::Integer(3)])),
+                ])),
+            ]))
+        );
+        let err = eval("(area)", &mut env).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("No case-lambda clause accepts 0 arguments"));
+    }
+
+    #[test]
+    fn test_keyword_arguments() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define connect (lambda (&key (host \"localhost\") (port 80)) (list host port)))
+            (connect :port 8080)
+            (connect :port 1 :host \"x\")
+            (define greet (lambda (name #!key (greeting \"hi\")) (+ greeting name)))
+            (greet \"bob\" :greeting \"bye \")
+            :done
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result,
+            Object::List(Rc::new(vec![
+                Object::ListData(Rc::new(vec![
+                    Object::String("localhost".into()),
+                    Object::Integer(8080),
+                ])),
+                Object::ListData(Rc::new(vec![
+                    Object::String("x".into()),
+                    Object::Integer(1)
+                ])),
+                Object::String("bye bob".into()),
+                Object::KeywordLiteral("done".to_string()),
+            ]))
+        );
+
+        let err = eval("(connect :user \"me\")", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Unknown keyword argument :user for lambda (&key (host localhost) (port 80))"
+        );
+        let err = eval("(connect :port)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "Missing value for keyword argument :port");
+    }
+
+    #[test]
+    fn test_multiple_values() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define div-mod (lambda (a b) (values (/ a b) (% a b))))
+            (call-with-values (lambda () (div-mod 17 5)) (lambda (q r) (list q r)))
+            (let-values (((q r) (div-mod 7 2)) ((all) (values 1))) (list q r all))
+            (receive (first . rest) (values 1 2 3) (list first rest))
+            (receive all (values) all)
+            (call/cc (lambda (k) (k 1 2)))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result.to_string(), "((3 2) (3 1 1) (1 (2 3)) () 1 2)");
+
+        let result = eval("(div-mod 9 4)", &mut env).unwrap();
+        assert_eq!(result.values(), &[Object::Integer(2), Object::Integer(1)]);
+        let result = eval("(values 5)", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(5));
+        assert_eq!(result.values(), &[Object::Integer(5)]);
+
+        let err = eval("(receive (a b) (values 1) a)", &mut env).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Invalid number of arguments for lambda (a b): expected 2, found 1"
+        );
     }
 }
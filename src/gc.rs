@@ -0,0 +1,543 @@
+//! Cycle collection for the values reference counting cannot free.
+//!
+//! Closures, frames and containers are shared with `Rc`, so a closure
+//! stored in a variable it captures, or a vector holding itself, keeps
+//! itself alive after the program is done with it. The collector finds such
+//! cycles by trial deletion: it walks everything reachable from the objects
+//! it watches, and subtracts the references found along the way from their
+//! strong counts. Whatever is left over is held from outside, e.g. by the
+//! global environment or a call in progress, and keeps everything it
+//! reaches alive. The rest is only referenced by itself, and the collector
+//! breaks it up by emptying its variables, containers and promises.
+//!
+//! Every cycle goes through something that was mutated after it was
+//! created, so the objects watched are closures, which are the only way
+//! into the variables of a frame, promises of `delay` and `cons-stream`,
+//! and vectors and hash tables that had a value with references stored in
+//! them. The runtime keeps weak references to them in a `Heap` and collects
+//! automatically when their number doubles, see `Runtime::collect_garbage`.
+//! References the collector cannot see, e.g. those held by builtins'
+//! closures or suspended generators, only ever make it keep more.
+//!
+//! Globals are referenced by resolved code, so procedures that call each
+//! other through them are freed when their environment is dropped instead.
+//!
+//! With the `sync` feature values can be shared with interpreters on other
+//! threads, which could change them while a collection walks them. There is
+//! no automatic collection then, and `gc` must only be called while no
+//! other thread uses values reachable from this interpreter.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::{
+    env::{Cell, Frame},
+    hash_table::HashTable,
+    lazy::{Promise, Stream, WeakPromise},
+    object::Object,
+    resolve::Closure,
+    sync::{Rc, RefCell, Weak},
+};
+
+/// Number of watched objects at which the first automatic collection runs.
+const MIN_THRESHOLD: usize = 10_000;
+
+/// Whether collections run on their own, see the module docs.
+const AUTO_COLLECT: bool = !cfg!(feature = "sync");
+
+/// Counts of live objects and of the work done by the collector, see
+/// `Runtime::gc_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GcStats {
+    /// Frames of procedure calls and `let`s, including those of calls in
+    /// progress and those captured by closures.
+    pub frames: usize,
+    pub closures: usize,
+    /// Promises of `delay` and `cons-stream`.
+    pub promises: usize,
+    /// Vectors and hash tables the collector watches.
+    pub containers: usize,
+    pub collections: usize,
+    /// Objects freed by all collections so far.
+    pub freed: usize,
+}
+
+/// An object the collector watches without keeping it alive.
+enum Watched {
+    Closure(Weak<Closure>),
+    Promise(WeakPromise),
+    Vector(Weak<RefCell<Vec<Object>>>),
+    HashTable(Weak<RefCell<HashTable>>),
+}
+
+impl Watched {
+    fn upgrade(&self) -> Option<Node> {
+        Some(match self {
+            Watched::Closure(closure) => Node::Closure(closure.upgrade()?),
+            Watched::Promise(promise) => Node::Promise(promise.upgrade()?),
+            Watched::Vector(vector) => Node::Vector(vector.upgrade()?),
+            Watched::HashTable(table) => Node::HashTable(table.upgrade()?),
+        })
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Watched::Closure(closure) => closure.strong_count() > 0,
+            Watched::Promise(promise) => promise.is_alive(),
+            Watched::Vector(vector) => vector.strong_count() > 0,
+            Watched::HashTable(table) => table.strong_count() > 0,
+        }
+    }
+}
+
+/// The objects a runtime's collector watches, by address. A weak reference
+/// keeps its allocation, so an address is not reused while it is watched.
+pub(crate) struct Heap {
+    watched: HashMap<usize, Watched>,
+    threshold: usize,
+    stats: GcStats,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Heap {
+            watched: HashMap::new(),
+            threshold: MIN_THRESHOLD,
+            stats: GcStats::default(),
+        }
+    }
+}
+
+impl fmt::Debug for Heap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Heap({} watched)", self.watched.len())
+    }
+}
+
+impl Heap {
+    pub(crate) fn watch_closure(&mut self, closure: &Rc<Closure>) -> bool {
+        let addr = Rc::as_ptr(closure) as *const () as usize;
+        self.watch(addr, || Watched::Closure(Rc::downgrade(closure)))
+    }
+
+    pub(crate) fn watch_promise(&mut self, promise: &Promise) -> bool {
+        self.watch(promise.addr(), || Watched::Promise(promise.downgrade()))
+    }
+
+    /// Watches a vector or hash table that `value` was stored in, if the
+    /// value can lead back to it.
+    pub(crate) fn watch_container(&mut self, container: &Object, value: &Object) -> bool {
+        if !has_references(value) {
+            return false;
+        }
+        match container {
+            Object::Vector(vector) => {
+                let addr = Rc::as_ptr(vector) as *const () as usize;
+                self.watch(addr, || Watched::Vector(Rc::downgrade(vector)))
+            }
+            Object::HashTable(table) => {
+                let addr = Rc::as_ptr(table) as *const () as usize;
+                self.watch(addr, || Watched::HashTable(Rc::downgrade(table)))
+            }
+            _ => false,
+        }
+    }
+
+    /// Returns whether enough objects are watched for a collection to run.
+    /// Without automatic collection the objects that are gone are dropped
+    /// from the list instead.
+    fn watch(&mut self, addr: usize, watched: impl FnOnce() -> Watched) -> bool {
+        self.watched.entry(addr).or_insert_with(watched);
+        if self.watched.len() < self.threshold {
+            return false;
+        }
+        if !AUTO_COLLECT {
+            self.watched.retain(|_, watched| watched.is_alive());
+            self.threshold = MIN_THRESHOLD.max(self.watched.len() * 2);
+        }
+        AUTO_COLLECT
+    }
+
+    /// Takes out the objects still alive, for a collection to run on.
+    pub(crate) fn start_collection(&mut self) -> Vec<Node> {
+        self.watched.retain(|_, watched| watched.is_alive());
+        self.watched.values().filter_map(Watched::upgrade).collect()
+    }
+
+    pub(crate) fn end_collection(&mut self, freed: usize) {
+        self.watched.retain(|_, watched| watched.is_alive());
+        self.threshold = MIN_THRESHOLD.max(self.watched.len() * 2);
+        self.stats.collections += 1;
+        self.stats.freed += freed;
+    }
+
+    pub(crate) fn stats(&self, frames: usize) -> GcStats {
+        let mut stats = GcStats {
+            frames,
+            ..self.stats
+        };
+        for watched in self.watched.values().filter(|watched| watched.is_alive()) {
+            match watched {
+                Watched::Closure(_) => stats.closures += 1,
+                Watched::Promise(_) => stats.promises += 1,
+                Watched::Vector(_) | Watched::HashTable(_) => stats.containers += 1,
+            }
+        }
+        stats
+    }
+}
+
+/// Whether a value holds references through which a cycle can form.
+fn has_references(value: &Object) -> bool {
+    match value {
+        Object::Lambda(_)
+        | Object::CaseLambda(_)
+        | Object::List(_)
+        | Object::ListData(_)
+        | Object::Vector(_)
+        | Object::HashTable(_)
+        | Object::Promise(_)
+        | Object::Stream(_)
+        | Object::Generator(_) => true,
+        Object::Values(values) => values.iter().any(has_references),
+        _ => false,
+    }
+}
+
+/// An object shared through an `Rc`, which the collector holds one strong
+/// reference to while it runs.
+pub(crate) enum Node {
+    Cell(Cell),
+    Frame(Rc<Frame>),
+    Closure(Rc<Closure>),
+    List(Rc<Vec<Object>>),
+    Vector(Rc<RefCell<Vec<Object>>>),
+    HashTable(Rc<RefCell<HashTable>>),
+    Promise(Promise),
+    Stream(Rc<Stream>),
+}
+
+impl Node {
+    fn addr(&self) -> usize {
+        match self {
+            Node::Cell(cell) => Rc::as_ptr(cell) as *const () as usize,
+            Node::Frame(frame) => Rc::as_ptr(frame) as *const () as usize,
+            Node::Closure(closure) => Rc::as_ptr(closure) as *const () as usize,
+            Node::List(list) => Rc::as_ptr(list) as *const () as usize,
+            Node::Vector(vector) => Rc::as_ptr(vector) as *const () as usize,
+            Node::HashTable(table) => Rc::as_ptr(table) as *const () as usize,
+            Node::Promise(promise) => promise.addr(),
+            Node::Stream(stream) => Rc::as_ptr(stream) as *const () as usize,
+        }
+    }
+
+    fn strong_count(&self) -> usize {
+        match self {
+            Node::Cell(cell) => Rc::strong_count(cell),
+            Node::Frame(frame) => Rc::strong_count(frame),
+            Node::Closure(closure) => Rc::strong_count(closure),
+            Node::List(list) => Rc::strong_count(list),
+            Node::Vector(vector) => Rc::strong_count(vector),
+            Node::HashTable(table) => Rc::strong_count(table),
+            Node::Promise(promise) => promise.strong_count(),
+            Node::Stream(stream) => Rc::strong_count(stream),
+        }
+    }
+
+    /// Finds the objects this one holds a strong reference to. Objects
+    /// that are borrowed right now are left alone.
+    fn trace(&self, tracer: &mut Tracer) {
+        match self {
+            Node::Cell(cell) => {
+                if let Ok(val) = cell.try_borrow() {
+                    if let Some(val) = &*val {
+                        tracer.object(val);
+                    }
+                }
+            }
+            Node::Frame(frame) => {
+                for cell in frame.slots() {
+                    tracer.push(Node::Cell(cell.clone()));
+                }
+                if let Some(parent) = frame.parent() {
+                    tracer.frame(parent);
+                }
+            }
+            Node::Closure(closure) => tracer.frame(&closure.captured),
+            Node::List(list) => list.iter().for_each(|item| tracer.object(item)),
+            Node::Vector(vector) => {
+                if let Ok(items) = vector.try_borrow() {
+                    items.iter().for_each(|item| tracer.object(item));
+                }
+            }
+            Node::HashTable(table) => {
+                if let Ok(table) = table.try_borrow() {
+                    table.iter().for_each(|(_, value)| tracer.object(value));
+                }
+            }
+            Node::Promise(promise) => promise.trace(tracer),
+            Node::Stream(stream) => {
+                tracer.object(&stream.head);
+                tracer.push(Node::Promise(stream.tail.clone()));
+            }
+        }
+    }
+
+    /// Empties a variable, container or promise found to be garbage.
+    fn clear(&self) {
+        match self {
+            Node::Cell(cell) => {
+                if let Ok(mut val) = cell.try_borrow_mut() {
+                    val.take();
+                }
+            }
+            Node::Vector(vector) => {
+                if let Ok(mut items) = vector.try_borrow_mut() {
+                    items.clear();
+                }
+            }
+            Node::HashTable(table) => {
+                if let Ok(mut table) = table.try_borrow_mut() {
+                    *table = HashTable::new();
+                }
+            }
+            Node::Promise(promise) => promise.clear(),
+            Node::Frame(_) | Node::Closure(_) | Node::List(_) | Node::Stream(_) => {}
+        }
+    }
+}
+
+/// Collects the references of the node being traced.
+pub(crate) struct Tracer {
+    found: Vec<Node>,
+}
+
+impl Tracer {
+    fn push(&mut self, node: Node) {
+        self.found.push(node);
+    }
+
+    pub(crate) fn frame(&mut self, frame: &Rc<Frame>) {
+        self.push(Node::Frame(frame.clone()));
+    }
+
+    pub(crate) fn object(&mut self, obj: &Object) {
+        match obj {
+            Object::Lambda(closure) | Object::CaseLambda(closure) => {
+                self.push(Node::Closure(closure.clone()))
+            }
+            Object::List(list) | Object::ListData(list) => self.push(Node::List(list.clone())),
+            Object::Vector(vector) => self.push(Node::Vector(vector.clone())),
+            Object::HashTable(table) => self.push(Node::HashTable(table.clone())),
+            Object::Promise(promise) => self.push(Node::Promise(promise.clone())),
+            Object::Stream(stream) => self.push(Node::Stream(stream.clone())),
+            Object::Values(values) => values.iter().for_each(|val| self.object(val)),
+            _ => {}
+        }
+    }
+}
+
+/// A node with the references to it found while tracing.
+struct Entry {
+    node: Node,
+    /// Strong references held by others than the collector.
+    refs: usize,
+    /// How many of those were found in traced nodes.
+    internal: usize,
+    edges: Vec<usize>,
+    live: bool,
+}
+
+/// Frees the cycles among and below `roots` that nothing else refers to.
+/// Returns the number of objects found to be garbage.
+pub(crate) fn collect(roots: Vec<Node>) -> usize {
+    let mut entries: Vec<Entry> = Vec::new();
+    let mut index: HashMap<usize, usize> = HashMap::new();
+    let mut pending = Vec::new();
+    for node in roots {
+        add(node, &mut entries, &mut index, &mut pending);
+    }
+
+    // Count the references found between the nodes.
+    let mut tracer = Tracer { found: Vec::new() };
+    while let Some(i) = pending.pop() {
+        entries[i].node.trace(&mut tracer);
+        for node in tracer.found.drain(..) {
+            let j = add(node, &mut entries, &mut index, &mut pending);
+            entries[j].internal += 1;
+            entries[i].edges.push(j);
+        }
+    }
+    // Now that the collector holds each node once, by its entry, the rest
+    // of the count is what others hold.
+    for entry in entries.iter_mut() {
+        entry.refs = entry.node.strong_count() - 1;
+    }
+
+    // Nodes referenced from outside keep what they reach alive.
+    let mut live: Vec<usize> = (0..entries.len())
+        .filter(|i| entries[*i].refs > entries[*i].internal)
+        .collect();
+    while let Some(i) = live.pop() {
+        if entries[i].live {
+            continue;
+        }
+        entries[i].live = true;
+        live.extend(entries[i].edges.iter().filter(|j| !entries[**j].live));
+    }
+
+    let garbage: Vec<&Entry> = entries.iter().filter(|entry| !entry.live).collect();
+    for entry in garbage.iter() {
+        entry.node.clear();
+    }
+    garbage.len()
+}
+
+/// Adds a node found while tracing, unless it was found before. Returns
+/// its index.
+fn add(
+    node: Node,
+    entries: &mut Vec<Entry>,
+    index: &mut HashMap<usize, usize>,
+    pending: &mut Vec<usize>,
+) -> usize {
+    let addr = node.addr();
+    if let Some(i) = index.get(&addr) {
+        return *i;
+    }
+    entries.push(Entry {
+        node,
+        refs: 0,
+        internal: 0,
+        edges: Vec::new(),
+        live: false,
+    });
+    let i = entries.len() - 1;
+    index.insert(addr, i);
+    pending.push(i);
+    i
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::env::Env;
+    use crate::eval::eval;
+    use crate::runtime::Engine;
+    use crate::sync::{Rc, RefCell};
+
+    const CYCLES: &str = "(
+        (define make-cycle (lambda ()
+            (let ((n 0))
+                (define self (lambda () self))
+                self)))
+        (define keep (make-cycle))
+        (define drop-cycles (lambda (n)
+            (if (= n 0) 0 (begin (make-cycle) (drop-cycles (- n 1))))))
+        (drop-cycles 10)
+    )";
+
+    #[test]
+    fn test_collects_closure_cycles() {
+        for engine in [Engine::TreeWalker, Engine::Bytecode] {
+            let mut env = Rc::new(RefCell::new(Env::new()));
+            let runtime = env.borrow().runtime();
+            runtime.set_engine(engine);
+            eval(CYCLES, &mut env).unwrap();
+            let before = runtime.gc_stats();
+            // make-cycle, drop-cycles, keep and the ten dropped ones.
+            assert_eq!(before.closures, 13);
+
+            let freed = runtime.collect_garbage();
+            let after = runtime.gc_stats();
+            assert_eq!(after.closures, 3);
+            assert_eq!(after.frames, before.frames - 10);
+            // Each cycle is a closure, the frame of the variables it
+            // captures and the variable holding it.
+            assert_eq!(freed, 30);
+            assert_eq!(after.collections, 1);
+            assert_eq!(after.freed, 30);
+
+            let result = eval("(procedure? (keep))", &mut env).unwrap();
+            assert_eq!(result.to_string(), "true");
+            assert_eq!(runtime.collect_garbage(), 0);
+        }
+    }
+
+    #[test]
+    fn test_collects_container_and_promise_cycles() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let runtime = env.borrow().runtime();
+        let program = "(
+            (define v (vector 1 2))
+            (vector-set! v 0 v)
+            (define h (hash))
+            (hash-set! h :self (list h))
+            (define lazy-self (lambda () (begin (define p (delay p)) p)))
+            (define p (lazy-self))
+            (gc)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result.to_string(), "(0)");
+        assert_eq!(runtime.gc_stats().containers, 2);
+        assert_eq!(runtime.gc_stats().promises, 1);
+
+        let result = eval(
+            "((define v nil) (define h nil) (define p nil) (gc))",
+            &mut env,
+        )
+        .unwrap();
+        // The vector, the table and the list in it, and the promise with
+        // the frame and variable of the call that made it.
+        assert_eq!(result.to_string(), "(6)");
+        let stats = runtime.gc_stats();
+        assert_eq!((stats.containers, stats.promises), (0, 0));
+    }
+
+    #[test]
+    fn test_objects_in_use_are_kept() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define make-cycle (lambda ()
+                (let ((n 0))
+                    (define self (lambda () self))
+                    self)))
+            (define in-use (lambda (f) (begin (gc) (procedure? (f)))))
+            (in-use (make-cycle))
+            (let ((v (vector 1)))
+                (vector-set! v 0 v)
+                (gc)
+                (vector-length (vector-ref v 0)))
+            (hash-ref (gc-stats) :collections)
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(result.to_string(), "(true 1 2)");
+    }
+
+    #[test]
+    #[cfg(not(feature = "sync"))]
+    fn test_collects_automatically() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let runtime = env.borrow().runtime();
+        eval(CYCLES, &mut env).unwrap();
+        eval("(drop-cycles 25000)", &mut env).unwrap();
+        let stats = runtime.gc_stats();
+        assert!(stats.collections >= 2);
+        assert!(stats.closures < 10_000);
+    }
+
+    #[test]
+    fn test_dropping_the_environment_frees_globals() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let runtime = env.borrow().runtime();
+        eval(
+            "((define even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+              (define odd? (lambda (n) (if (= n 0) false (even? (- n 1))))))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(runtime.gc_stats().closures, 2);
+        drop(env);
+        assert_eq!(runtime.gc_stats().closures, 0);
+        assert_eq!(runtime.gc_stats().frames, 0);
+    }
+}
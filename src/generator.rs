@@ -0,0 +1,156 @@
+//! Generators: procedures that can suspend themselves with `yield` and be
+//! resumed later.
+//!
+//! The body of a generator runs on its own stack, so `yield` can be called
+//! from anywhere inside it, including from procedures it calls, e.g. from a
+//! `for-each` walking a tree.
+
+use std::cell::{Cell, RefCell};
+use std::fmt;
+
+use corosensei::stack::{DefaultStack, Stack};
+use corosensei::{Coroutine, CoroutineResult, Yielder};
+
+use crate::env::Env;
+use crate::eval::{self, EvalError};
+use crate::object::Object;
+use crate::runtime::Runtime;
+use crate::sequence::Sequence;
+use crate::stack;
+use crate::sync::Rc;
+
+/// Stack reserved for the body of a generator. Pages are only committed
+/// when used, but the address space is taken for as long as the generator
+/// lives, so this stays small enough for many live generators. A body that
+/// recurses deeper than its stack allows fails with
+/// `EvalError::StackOverflow`, see `stack`.
+const STACK_SIZE: usize = 1 << 20;
+
+type Body = Coroutine<Object, Object, Result<Object, EvalError>>;
+
+thread_local! {
+    /// Yielders of the generator bodies currently running, innermost last.
+    /// A body is on the list while it runs and off it while it is
+    /// suspended, so the last entry always belongs to the body that a
+    /// `yield` is called from.
+    static YIELDERS: RefCell<Vec<*const Yielder<Object, Object>>> = const { RefCell::new(Vec::new()) };
+}
+
+enum State {
+    Suspended(Body),
+    Running,
+    Done,
+}
+
+pub struct Generator {
+    state: RefCell<State>,
+    /// Evaluation depth of the suspended body, taken out of the runtime's
+    /// count while the generator is not running.
+    depth: Cell<usize>,
+    /// Lowest address of the stack the body runs on.
+    stack_limit: usize,
+    runtime: Rc<Runtime>,
+}
+
+impl Generator {
+    /// A generator that calls `thunk` on its first `resume`.
+    pub fn new(thunk: Object, env: &Rc<RefCell<Env>>) -> Result<Self, EvalError> {
+        let runtime = env.borrow().runtime();
+        let stack = DefaultStack::new(STACK_SIZE)
+            .map_err(|err| format!("Cannot allocate a generator stack: {}", err))?;
+        let stack_limit = stack.limit().get();
+        let mut env = env.clone();
+        let body = Coroutine::with_stack(stack, move |yielder: &Yielder<Object, Object>, _| {
+            YIELDERS.with(|yielders| yielders.borrow_mut().push(yielder));
+            let result = eval::apply(&thunk, vec![], &mut env);
+            YIELDERS.with(|yielders| yielders.borrow_mut().pop());
+            result
+        });
+        Ok(Generator {
+            state: RefCell::new(State::Suspended(body)),
+            depth: Cell::new(0),
+            stack_limit,
+            runtime,
+        })
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(*self.state.borrow(), State::Done)
+    }
+
+    /// Runs the body until its next `yield` and returns the yielded value,
+    /// or `None` once the body has returned. `value` becomes the result of
+    /// the `yield` the body is suspended in.
+    pub fn resume(&self, value: Object) -> Result<Option<Object>, EvalError> {
+        let mut body = match self.state.replace(State::Running) {
+            State::Suspended(body) => body,
+            State::Running => return Err("Generator resumed while it is running".into()),
+            State::Done => {
+                self.state.replace(State::Done);
+                return Ok(None);
+            }
+        };
+        let base = self.runtime.depth();
+        self.runtime.set_depth(base + self.depth.get());
+        match stack::with_limit(self.stack_limit, || body.resume(value)) {
+            CoroutineResult::Yield(value) => {
+                self.depth.set(self.runtime.depth() - base);
+                self.runtime.set_depth(base);
+                self.state.replace(State::Suspended(body));
+                Ok(Some(value))
+            }
+            CoroutineResult::Return(result) => {
+                self.depth.set(0);
+                self.state.replace(State::Done);
+                result.map(|_| None)
+            }
+        }
+    }
+}
+
+impl Drop for Generator {
+    fn drop(&mut self) {
+        // Dropping a suspended body unwinds it, which releases the depth
+        // its frames still hold.
+        if let State::Suspended(_) = *self.state.get_mut() {
+            self.runtime
+                .set_depth(self.runtime.depth() + self.depth.get());
+        }
+    }
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Generator")
+    }
+}
+
+/// `(yield value)`: suspends the innermost running generator body and
+/// returns the value it is resumed with.
+pub fn yield_value(value: Object) -> Result<Object, EvalError> {
+    let yielder = YIELDERS.with(|yielders| yielders.borrow_mut().pop());
+    let yielder = match yielder {
+        Some(yielder) => yielder,
+        None => return Err("yield called outside of a generator".into()),
+    };
+    // SAFETY: the pointer was pushed by a body that is still running, since
+    // bodies remove their yielder from the list before they suspend or
+    // return. A yielder lives as long as its body.
+    let resumed = unsafe { (*yielder).suspend(value) };
+    YIELDERS.with(|yielders| yielders.borrow_mut().push(yielder));
+    Ok(resumed)
+}
+
+impl Sequence for Generator {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        Box::new(std::iter::from_fn(move || {
+            self.resume(Object::Void).transpose()
+        }))
+    }
+}
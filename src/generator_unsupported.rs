@@ -0,0 +1,54 @@
+//! Generators with the `sync` feature, which does not support them.
+//!
+//! A generator body runs on a stack of its own that is tied to the thread
+//! it was started on, so generators cannot be `Send`. This keeps the
+//! interface of `generator` so the rest of the interpreter is unchanged,
+//! but no generator can be created.
+
+use std::convert::Infallible;
+use std::fmt;
+
+use crate::env::Env;
+use crate::eval::EvalError;
+use crate::object::Object;
+use crate::sequence::Sequence;
+use crate::sync::{Rc, RefCell};
+
+pub struct Generator(Infallible);
+
+impl Generator {
+    pub fn new(_thunk: Object, _env: &Rc<RefCell<Env>>) -> Result<Self, EvalError> {
+        Err("Generators are not available with the sync feature".into())
+    }
+
+    pub fn is_done(&self) -> bool {
+        match self.0 {}
+    }
+
+    pub fn resume(&self, _value: Object) -> Result<Option<Object>, EvalError> {
+        match self.0 {}
+    }
+}
+
+impl PartialEq for Generator {
+    fn eq(&self, _other: &Self) -> bool {
+        match self.0 {}
+    }
+}
+
+impl fmt::Debug for Generator {
+    fn fmt(&self, _f: &mut fmt::Formatter) -> fmt::Result {
+        match self.0 {}
+    }
+}
+
+/// `(yield value)`, which is always outside of a generator.
+pub fn yield_value(_value: Object) -> Result<Object, EvalError> {
+    Err("yield called outside of a generator".into())
+}
+
+impl Sequence for Generator {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        match self.0 {}
+    }
+}
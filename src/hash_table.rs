@@ -0,0 +1,190 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::object::Object;
+use crate::symbol::Symbol;
+use crate::sync::Rc;
+
+/// The hashable form of an `Object`, used as the key of a `HashTable`.
+///
+/// Only immutable values can be keys: nil, numbers, booleans, characters,
+/// strings, symbols, keywords and lists of those. Two keys are equal when
+/// they are of the same type and have the same value, so `1` and `1.0` are
+/// different keys. All NaNs are the same key, and so are `0.0` and `-0.0`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum HashKey {
+    Void,
+    Integer(i64),
+    Float(u64),
+    Bool(bool),
+    Char(char),
+    String(Rc<str>),
+    Symbol(Symbol),
+    KeywordLiteral(String),
+    List(Vec<HashKey>),
+}
+
+impl HashKey {
+    /// Returns `None` for objects that cannot be hashed, like vectors and
+    /// procedures.
+    pub fn from_object(obj: &Object) -> Option<HashKey> {
+        Some(match obj {
+            Object::Void => HashKey::Void,
+            Object::Integer(n) => HashKey::Integer(*n),
+            Object::Float(f) if f.is_nan() => HashKey::Float(f64::NAN.to_bits()),
+            Object::Float(f) if *f == 0.0 => HashKey::Float(0.0f64.to_bits()),
+            Object::Float(f) => HashKey::Float(f.to_bits()),
+            Object::Bool(b) => HashKey::Bool(*b),
+            Object::Char(c) => HashKey::Char(*c),
+            Object::String(s) => HashKey::String(s.clone()),
+            Object::Symbol(s) => HashKey::Symbol(*s),
+            Object::KeywordLiteral(s) => HashKey::KeywordLiteral(s.clone()),
+            Object::ListData(list) => HashKey::List(
+                list.iter()
+                    .map(HashKey::from_object)
+                    .collect::<Option<Vec<_>>>()?,
+            ),
+            _ => return None,
+        })
+    }
+
+    pub fn to_object(&self) -> Object {
+        match self {
+            HashKey::Void => Object::Void,
+            HashKey::Integer(n) => Object::Integer(*n),
+            HashKey::Float(bits) => Object::Float(f64::from_bits(*bits)),
+            HashKey::Bool(b) => Object::Bool(*b),
+            HashKey::Char(c) => Object::Char(*c),
+            HashKey::String(s) => Object::String(s.clone()),
+            HashKey::Symbol(s) => Object::Symbol(*s),
+            HashKey::KeywordLiteral(s) => Object::KeywordLiteral(s.clone()),
+            HashKey::List(list) => {
+                Object::ListData(Rc::new(list.iter().map(HashKey::to_object).collect()))
+            }
+        }
+    }
+}
+
+/// A hash table that iterates in insertion order, except that removing an
+/// entry moves the last entry into its place.
+#[derive(Clone, Default)]
+pub struct HashTable {
+    index: HashMap<HashKey, usize>,
+    entries: Vec<(HashKey, Object)>,
+}
+
+impl HashTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, key: &HashKey) -> Option<&Object> {
+        self.index.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    /// Returns the previous value, if any.
+    pub fn insert(&mut self, key: HashKey, value: Object) -> Option<Object> {
+        match self.index.get(&key) {
+            Some(&i) => Some(std::mem::replace(&mut self.entries[i].1, value)),
+            None => {
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, value));
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, key: &HashKey) -> Option<Object> {
+        let i = self.index.remove(key)?;
+        let (_, value) = self.entries.swap_remove(i);
+        if let Some((moved, _)) = self.entries.get(i) {
+            self.index.insert(moved.clone(), i);
+        }
+        Some(value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&HashKey, &Object)> {
+        self.entries.iter().map(|(key, value)| (key, value))
+    }
+}
+
+/// Tables are equal when they have the same keys with equal values,
+/// regardless of order.
+impl PartialEq for HashTable {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len()
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl fmt::Debug for HashTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+impl fmt::Display for HashTable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{} {}", key.to_object(), value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_insert_and_remove() {
+        let mut table = HashTable::new();
+        for (i, name) in ["a", "b", "c"].iter().enumerate() {
+            table.insert(
+                HashKey::KeywordLiteral(name.to_string()),
+                Object::Integer(i as i64),
+            );
+        }
+        assert_eq!(
+            table.remove(&HashKey::KeywordLiteral("a".to_string())),
+            Some(Object::Integer(0))
+        );
+        assert_eq!(table.len(), 2);
+        assert_eq!(
+            table.get(&HashKey::KeywordLiteral("c".to_string())),
+            Some(&Object::Integer(2))
+        );
+        assert_eq!(table.get(&HashKey::KeywordLiteral("a".to_string())), None);
+        assert_eq!(table.to_string(), "{:c 2 :b 1}");
+    }
+
+    #[test]
+    fn test_float_keys() {
+        assert_eq!(
+            HashKey::from_object(&Object::Float(-0.0)),
+            HashKey::from_object(&Object::Float(0.0))
+        );
+        assert_eq!(
+            HashKey::from_object(&Object::Float(f64::NAN)),
+            HashKey::from_object(&Object::Float(-f64::NAN))
+        );
+        assert_ne!(
+            HashKey::from_object(&Object::Float(1.0)),
+            HashKey::from_object(&Object::Integer(1))
+        );
+    }
+}
@@ -0,0 +1,182 @@
+//! Promises and the lazy streams built from them.
+
+use std::fmt;
+
+use crate::env::{Env, Frame};
+use crate::eval::{self, EvalError};
+use crate::gc::Tracer;
+use crate::object::Object;
+use crate::resolve::Expr;
+use crate::sync::{Rc, RefCell, Weak};
+
+/// A computation run by `Promise::force` from Rust rather than from an
+/// expression.
+#[cfg(not(feature = "sync"))]
+pub type NativeThunk = Rc<dyn Fn(&mut Rc<RefCell<Env>>) -> Result<Object, EvalError>>;
+/// With the `sync` feature a promise can be forced on any thread.
+#[cfg(feature = "sync")]
+pub type NativeThunk = Rc<dyn Fn(&mut Rc<RefCell<Env>>) -> Result<Object, EvalError> + Send + Sync>;
+
+#[derive(Clone)]
+enum Thunk {
+    Expr(Rc<Expr>, Rc<Frame>),
+    Native(NativeThunk),
+}
+
+enum State {
+    Delayed(Thunk),
+    Done(Object),
+}
+
+/// A value computed on first `force` and remembered afterwards. Clones
+/// share the same state.
+#[derive(Clone)]
+pub struct Promise(Rc<RefCell<State>>);
+
+impl Promise {
+    /// `(delay expr)`: evaluates `expr` in `frame` when forced.
+    pub fn delay(expr: Rc<Expr>, frame: Rc<Frame>) -> Self {
+        let runtime = frame.runtime().clone();
+        let promise = Promise(Rc::new(RefCell::new(State::Delayed(Thunk::Expr(
+            expr, frame,
+        )))));
+        // The value may refer back to the promise once it is forced.
+        runtime.watch_promise(&promise);
+        promise
+    }
+
+    pub fn native(thunk: NativeThunk) -> Self {
+        Promise(Rc::new(RefCell::new(State::Delayed(Thunk::Native(thunk)))))
+    }
+
+    /// An already forced promise.
+    pub fn ready(value: Object) -> Self {
+        Promise(Rc::new(RefCell::new(State::Done(value))))
+    }
+
+    pub fn is_forced(&self) -> bool {
+        matches!(*self.0.borrow(), State::Done(_))
+    }
+
+    /// Runs the computation the first time and returns the remembered value
+    /// every time after. If the computation forces this same promise, the
+    /// value of whichever finishes first is kept.
+    pub fn force(&self, env: &mut Rc<RefCell<Env>>) -> Result<Object, EvalError> {
+        let thunk = match &*self.0.borrow() {
+            State::Done(value) => return Ok(value.clone()),
+            State::Delayed(thunk) => thunk.clone(),
+        };
+        let value = match thunk {
+            Thunk::Expr(expr, frame) => eval::eval_expr(&expr, &frame, env)?,
+            Thunk::Native(thunk) => thunk(env)?,
+        };
+        let mut state = self.0.borrow_mut();
+        match &*state {
+            State::Done(value) => Ok(value.clone()),
+            State::Delayed(_) => {
+                *state = State::Done(value.clone());
+                Ok(value)
+            }
+        }
+    }
+}
+
+impl Promise {
+    /// Takes the value out of a forced promise that has no other clones.
+    fn take_forced(&self) -> Option<Object> {
+        if Rc::strong_count(&self.0) > 1 {
+            return None;
+        }
+        match &mut *self.0.borrow_mut() {
+            State::Done(value) => Some(std::mem::replace(value, Object::Void)),
+            State::Delayed(_) => None,
+        }
+    }
+}
+
+/// A promise watched by the cycle collector, see `gc`.
+pub(crate) struct WeakPromise(Weak<RefCell<State>>);
+
+impl WeakPromise {
+    pub(crate) fn upgrade(&self) -> Option<Promise> {
+        self.0.upgrade().map(Promise)
+    }
+
+    pub(crate) fn is_alive(&self) -> bool {
+        self.0.strong_count() > 0
+    }
+}
+
+impl Promise {
+    pub(crate) fn downgrade(&self) -> WeakPromise {
+        WeakPromise(Rc::downgrade(&self.0))
+    }
+
+    pub(crate) fn addr(&self) -> usize {
+        Rc::as_ptr(&self.0) as *const () as usize
+    }
+
+    pub(crate) fn strong_count(&self) -> usize {
+        Rc::strong_count(&self.0)
+    }
+
+    /// Reports the frame or value the promise holds. Native computations
+    /// are opaque to the collector.
+    pub(crate) fn trace(&self, tracer: &mut Tracer) {
+        if let Ok(state) = self.0.try_borrow() {
+            match &*state {
+                State::Delayed(Thunk::Expr(_, frame)) => tracer.frame(frame),
+                State::Delayed(Thunk::Native(_)) => {}
+                State::Done(value) => tracer.object(value),
+            }
+        }
+    }
+
+    /// Drops what the promise holds, once it is found to be garbage.
+    pub(crate) fn clear(&self) {
+        if let Ok(mut state) = self.0.try_borrow_mut() {
+            *state = State::Done(Object::Void);
+        }
+    }
+}
+
+impl PartialEq for Promise {
+    fn eq(&self, other: &Self) -> bool {
+        Rc::ptr_eq(&self.0, &other.0)
+    }
+}
+
+impl fmt::Debug for Promise {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Promise")
+    }
+}
+
+/// A stream cell: a head value and a promise of the rest of the stream.
+/// The rest is either another stream or the empty list.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stream {
+    pub head: Object,
+    pub tail: Promise,
+}
+
+impl Drop for Stream {
+    /// A forced stream is a chain of cells as long as the number of
+    /// elements walked. Unlinking it one cell at a time keeps dropping a
+    /// long chain from overflowing the stack.
+    fn drop(&mut self) {
+        let mut next = self.tail.take_forced();
+        while let Some(Object::Stream(cell)) = next {
+            next = match Rc::try_unwrap(cell) {
+                Ok(cell) => cell.tail.take_forced(),
+                Err(_) => None,
+            };
+        }
+    }
+}
+
+impl fmt::Display for Stream {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Stream({} ...)", self.head)
+    }
+}
@@ -1,4 +1,7 @@
-use std::{fmt::{self}, error::Error};
+use std::{
+    error::Error,
+    fmt::{self},
+};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Token {
@@ -6,11 +9,16 @@ pub enum Token {
     Float(f64),
     Symbol(String),
     Keyword(String),
+    KeywordLiteral(String),
+    Char(char),
     If,
     BinaryOp(String),
     String(String),
     LParen,
-    RParen
+    VectorStart,
+    RParen,
+    LBrace,
+    RBrace,
 }
 
 impl fmt::Display for Token {
@@ -18,15 +26,20 @@ impl fmt::Display for Token {
         use Token::*;
         f.write_str(
             (match self {
-                Integer(n) => format!("{}", n),
-                Float(f) => format!("{}", f),
-                Symbol(s) => format!("{}", s),
-                Keyword(s) => format!("{}", s),
-                BinaryOp(s) => format!("{}", s),
-                If => format!("if"),
+                Integer(n) => n.to_string(),
+                Float(f) => f.to_string(),
+                Symbol(s) => s.to_string(),
+                Keyword(s) => s.to_string(),
+                KeywordLiteral(s) => format!(":{}", s),
+                Char(c) => format!("#\\{}", c),
+                BinaryOp(s) => s.to_string(),
+                If => "if".to_string(),
                 String(s) => format!("\"{}\"", s),
-                LParen => format!("("),
-                RParen => format!(")"),
+                LParen => "(".to_string(),
+                VectorStart => "#(".to_string(),
+                RParen => ")".to_string(),
+                LBrace => "{".to_string(),
+                RBrace => "}".to_string(),
             })
             .as_str(),
         )
@@ -49,18 +62,24 @@ impl fmt::Display for TokenError {
 pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenError> {
     let mut tokens: Vec<Token> = Vec::new();
     let mut chars = input.chars().collect::<Vec<char>>();
-    while chars.len() > 0 {
+    while !chars.is_empty() {
         let mut ch = chars.remove(0);
         match ch {
             '(' => tokens.push(Token::LParen),
             ')' => tokens.push(Token::RParen),
+            '{' => tokens.push(Token::LBrace),
+            '}' => tokens.push(Token::RBrace),
+            '#' if chars.first() == Some(&'(') => {
+                chars.remove(0);
+                tokens.push(Token::VectorStart);
+            }
             '"' => {
                 let mut word = String::new();
-                while chars.len() > 0 && chars[0] != '"' {
+                while !chars.is_empty() && chars[0] != '"' {
                     word.push(chars.remove(0));
                 }
 
-                if chars.len() > 0 && chars[0] == '"' {
+                if !chars.is_empty() && chars[0] == '"' {
                     chars.remove(0);
                 } else {
                     return Err(TokenError {
@@ -70,12 +89,30 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenError> {
 
                 tokens.push(Token::String(word));
             }
+            '#' if chars.first() == Some(&'\\') => {
+                chars.remove(0);
+                if chars.is_empty() {
+                    return Err(TokenError {
+                        err: "Missing character after #\\".to_string(),
+                    });
+                }
+                // The first character is taken as is, so that #\( and #\space work.
+                let mut name = String::new();
+                name.push(chars.remove(0));
+                while !chars.is_empty() && !chars[0].is_whitespace() && !is_delimiter(chars[0]) {
+                    name.push(chars.remove(0));
+                }
+                tokens.push(Token::Char(parse_char(&name)?));
+            }
             _ => {
                 let mut word = String::new();
-                while chars.len() > 0 && !ch.is_whitespace() && ch != '(' && ch != ')' {
+                while !ch.is_whitespace() && !is_delimiter(ch) {
                     word.push(ch);
+                    if chars.is_empty() {
+                        break;
+                    }
                     let peek = chars[0];
-                    if peek == '(' || peek == ')' {
+                    if is_delimiter(peek) {
                         break;
                     }
 
@@ -86,27 +123,27 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenError> {
                     continue;
                 }
 
-                let i = word.parse::<i64>();
-                if i.is_ok() {
-                    tokens.push(Token::Integer(i.unwrap()));
+                if let Ok(i) = word.parse::<i64>() {
+                    tokens.push(Token::Integer(i));
                     continue;
                 }
 
-                let f = word.parse::<f64>();
-                if f.is_ok() {
-                    tokens.push(Token::Float(f.unwrap()));
+                if let Ok(f) = word.parse::<f64>() {
+                    tokens.push(Token::Float(f));
                     continue;
                 }
 
                 let token = match word.as_str() {
-                    "define" | "list" | "print" | "lambda" | "map" | "filter" | "reduce" => {
-                        Token::Keyword(word)
-                    },
+                    "define" | "list" | "lambda" | "case-lambda" | "begin" | "let" | "cond"
+                    | "delay" | "cons-stream" | "let-values" | "receive" => Token::Keyword(word),
                     "+" | "-" | "*" | "/" | "%" | "<" | ">" | "=" | "!=" | "&" | "|" => {
                         Token::BinaryOp(word)
                     }
                     "if" => Token::If,
-                    _ => Token::Symbol(word)
+                    _ if word.len() > 1 && word.starts_with(':') => {
+                        Token::KeywordLiteral(word[1..].to_string())
+                    }
+                    _ => Token::Symbol(word),
                 };
                 tokens.push(token)
             }
@@ -115,6 +152,32 @@ pub fn tokenize(input: &str) -> Result<Vec<Token>, TokenError> {
     Ok(tokens)
 }
 
+fn is_delimiter(ch: char) -> bool {
+    matches!(ch, '(' | ')' | '{' | '}')
+}
+
+/// Resolves the name after `#\\`: a single character, `space`, `newline`,
+/// `tab` or a hex code like `x41`.
+fn parse_char(name: &str) -> Result<char, TokenError> {
+    let mut chars = name.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        return Ok(c);
+    }
+    match name {
+        "space" => Ok(' '),
+        "newline" => Ok('\n'),
+        "tab" => Ok('\t'),
+        _ if name.starts_with('x') => u32::from_str_radix(&name[1..], 16)
+            .ok()
+            .and_then(char::from_u32)
+            .ok_or(TokenError {
+                err: format!("Invalid character code: #\\{}", name),
+            }),
+        _ => Err(TokenError {
+            err: format!("Unknown character name: #\\{}", name),
+        }),
+    }
+}
 
 #[cfg(test)]
 mod tests {
@@ -172,4 +235,74 @@ mod tests {
             ]
         );
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_atom_at_end_of_input() {
+        let tokens = tokenize("print").unwrap_or(vec![]);
+        assert_eq!(tokens, vec![Token::Symbol("print".to_string())]);
+    }
+
+    #[test]
+    fn test_keyword_literal() {
+        let tokens = tokenize("(connect :port 80)").unwrap_or(vec![]);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Symbol("connect".to_string()),
+                Token::KeywordLiteral("port".to_string()),
+                Token::Integer(80),
+                Token::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_char_literals() {
+        let tokens = tokenize("(list #\\a #\\space #\\newline #\\x41 #\\( #\\))").unwrap_or(vec![]);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LParen,
+                Token::Keyword("list".to_string()),
+                Token::Char('a'),
+                Token::Char(' '),
+                Token::Char('\n'),
+                Token::Char('A'),
+                Token::Char('('),
+                Token::Char(')'),
+                Token::RParen,
+            ]
+        );
+        assert!(tokenize("#\\bogus").is_err());
+    }
+
+    #[test]
+    fn test_vector_literal() {
+        let tokens = tokenize("#(1 #\\a)").unwrap_or(vec![]);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::VectorStart,
+                Token::Integer(1),
+                Token::Char('a'),
+                Token::RParen,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_hash_literal() {
+        let tokens = tokenize("{:a 1 :b}").unwrap_or(vec![]);
+        assert_eq!(
+            tokens,
+            vec![
+                Token::LBrace,
+                Token::KeywordLiteral("a".to_string()),
+                Token::Integer(1),
+                Token::KeywordLiteral("b".to_string()),
+                Token::RBrace,
+            ]
+        );
+    }
+}
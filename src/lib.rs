@@ -0,0 +1,25 @@
+pub mod builtins;
+pub mod bytecode;
+pub mod compiled;
+pub mod continuation;
+pub mod env;
+pub mod eval;
+pub mod gc;
+#[cfg(not(feature = "sync"))]
+pub mod generator;
+#[cfg(feature = "sync")]
+#[path = "generator_unsupported.rs"]
+pub mod generator;
+pub mod hash_table;
+pub mod lazy;
+pub mod lexer;
+pub mod object;
+pub mod parser;
+pub mod persistent;
+pub mod resolve;
+pub mod runtime;
+pub mod sequence;
+pub mod stack;
+pub mod symbol;
+pub mod sync;
+pub mod vm;
@@ -1,20 +1,127 @@
-use std::{cell::RefCell, rc::Rc };
+use std::path::Path;
 
-use env::Env;
 use linefeed::{Interface, ReadResult};
-use object::Object;
-
-mod lexer;
-mod object;
-mod parser;
-mod eval;
-mod env;
+use risp::{
+    compiled,
+    env::Env,
+    eval,
+    runtime::Engine,
+    sync::{Rc, RefCell},
+};
 
 const PROMPT: &str = "lisp-rs> ";
 
+/// The REPL and programs run on their own thread so that deep non-tail
+/// recursion gets more stack than the main thread has.
+const STACK_SIZE: usize = 256 * 1024 * 1024;
+const MAX_DEPTH: usize = 20_000;
+
+const USAGE: &str = "usage: risp [--bytecode] [FILE]
+       risp compile FILE [-o OUT]";
+
+/// `risp` starts the REPL and `risp FILE` runs a source or `.rispc` file.
+/// `risp compile foo.risp -o foo.rispc` precompiles a program, see
+/// `compiled`. `--bytecode` runs the code on the VM instead of the
+/// tree-walker.
 fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let engine = match args.iter().position(|arg| arg == "--bytecode") {
+        Some(i) => {
+            args.remove(i);
+            Engine::Bytecode
+        }
+        None => Engine::TreeWalker,
+    };
+    let ok = match args.as_slice() {
+        [] => spawn(move || {
+            repl(engine);
+            true
+        })?,
+        [command, input] if command == "compile" => {
+            compile(input, &Path::new(input).with_extension("rispc"))
+        }
+        [command, input, flag, output] if command == "compile" && flag == "-o" => {
+            compile(input, Path::new(output))
+        }
+        [path] if !path.starts_with('-') => {
+            let path = path.clone();
+            spawn(move || run(&path, engine))?
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            false
+        }
+    };
+    if !ok {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+fn spawn(f: impl FnOnce() -> bool + Send + 'static) -> std::io::Result<bool> {
+    let thread = std::thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(f)?;
+    Ok(thread.join().unwrap())
+}
+
+fn new_env(engine: Engine) -> Rc<RefCell<Env>> {
+    let env = Rc::new(RefCell::new(Env::new()));
+    env.borrow().runtime().set_max_depth(MAX_DEPTH);
+    env.borrow().runtime().set_engine(engine);
+    env
+}
+
+fn compile(input: &str, output: &Path) -> bool {
+    let result = std::fs::read_to_string(input)
+        .map_err(|err| err.to_string())
+        .and_then(|source| compiled::compile(&source).map_err(|err| err.to_string()))
+        .and_then(|bytes| std::fs::write(output, bytes).map_err(|err| err.to_string()));
+    match result {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("{}: {}", input, err);
+            false
+        }
+    }
+}
+
+/// Runs a program and prints its results. Files ending in `.rispc` are
+/// loaded as compiled programs.
+fn run(path: &str, engine: Engine) -> bool {
+    let mut env = new_env(engine);
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(err) => {
+            eprintln!("{}: {}", path, err);
+            return false;
+        }
+    };
+    let result = if path.ends_with(".rispc") {
+        eval::eval_compiled(&bytes, &mut env)
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(source) => eval::eval(&source, &mut env),
+            Err(_) => Err(format!("{}: not a UTF-8 source file", path).into()),
+        }
+    };
+    match result {
+        Ok(val) => {
+            for val in val.values() {
+                println!("{:?}", val);
+            }
+            true
+        }
+        Err(err) => {
+            eprintln!("Execution error. {}", err);
+            false
+        }
+    }
+}
+
+fn repl(engine: Engine) {
     let reader = Interface::new(PROMPT).unwrap();
-    let mut env = Rc::new(RefCell::new(Env::new()));
+    let mut env = new_env(engine);
     let mut current_source = "".to_string();
     let mut unclosed_lparen: i32 = 0;
     while let ReadResult::Input(input) = reader.read_line().unwrap() {
@@ -22,19 +129,30 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
             break;
         }
 
-        unclosed_lparen = unclosed_lparen + input.chars().fold(0,|a,b|  {if b == '(' { a + 1 } else if b == ')' { a - 1 } else { a }});
+        unclosed_lparen += input.chars().fold(0, |a, b| {
+            if b == '(' || b == '{' {
+                a + 1
+            } else if b == ')' || b == '}' {
+                a - 1
+            } else {
+                a
+            }
+        });
         current_source = current_source + " " + &input;
         if unclosed_lparen > 0 {
             continue;
         }
 
         match eval::eval(current_source.as_ref(), &mut env) {
-            Ok(val) => println!("{:?}", val),
-            Err(err) => println!("Execution error. {:?}", err)
+            Ok(val) => {
+                for val in val.values() {
+                    println!("{:?}", val);
+                }
+            }
+            Err(err) => println!("Execution error. {}", err),
         }
         current_source = String::new();
     }
 
     println!("Good bye");
-    Ok(())
-}
\ No newline at end of file
+}
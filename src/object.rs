@@ -1,6 +1,14 @@
-use std::{fmt, rc::Rc, cell::RefCell};
+use std::fmt;
 
-use crate::env::Env;
+use crate::builtins::Builtin;
+use crate::continuation::Continuation;
+use crate::generator::Generator;
+use crate::hash_table::HashTable;
+use crate::lazy::{Promise, Stream};
+use crate::persistent::{PersistentMap, PersistentVector};
+use crate::resolve::Closure;
+use crate::symbol::Symbol;
+use crate::sync::{Rc, RefCell};
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum Object {
@@ -8,14 +16,67 @@ pub enum Object {
     Integer(i64),
     Float(f64),
     Bool(bool),
-    String(String),
-    Symbol(String),
+    Char(char),
+    String(Rc<str>),
+    Symbol(Symbol),
     Keyword(String),
+    /// A self-evaluating `:name`, e.g. for keyword arguments.
+    KeywordLiteral(String),
     If,
     BinaryOp(String),
-    Lambda(Vec<String>, Vec<Object>, Rc<RefCell<Env>>),
-    List(Vec<Object>),
-    ListData(Vec<Object>),
+    Lambda(Rc<Closure>),
+    CaseLambda(Rc<Closure>),
+    Builtin(Builtin),
+    /// A continuation captured by `call/cc`, see `continuation`.
+    Continuation(Rc<Continuation>),
+    /// Code, shared between the closures created from it.
+    List(Rc<Vec<Object>>),
+    ListData(Rc<Vec<Object>>),
+    /// A `#(...)` vector. Clones share the same storage, so `vector-set!`
+    /// is visible through every reference.
+    Vector(Rc<RefCell<Vec<Object>>>),
+    /// A `{key value ...}` hash table, shared between clones like `Vector`.
+    HashTable(Rc<RefCell<HashTable>>),
+    /// Immutable vector compared by value, see `persistent`.
+    PersistentVector(PersistentVector),
+    /// Immutable map compared by value, see `persistent`.
+    PersistentMap(PersistentMap),
+    /// The result of `delay`, `make-promise` or a lazy stream operation.
+    Promise(Promise),
+    /// A lazy list cell, see `lazy::Stream`.
+    Stream(Rc<Stream>),
+    /// The result of `make-generator`, see `generator`.
+    Generator(Rc<Generator>),
+    /// Zero or several results of `values`. A single value is never
+    /// wrapped.
+    Values(Vec<Object>),
+}
+
+impl Object {
+    /// The result of `(values ...)` with these values.
+    pub fn from_values(mut values: Vec<Object>) -> Object {
+        if values.len() == 1 {
+            values.pop().unwrap()
+        } else {
+            Object::Values(values)
+        }
+    }
+
+    /// The values of a result, which is one value unless it came from
+    /// `values`.
+    pub fn values(&self) -> &[Object] {
+        match self {
+            Object::Values(values) => values,
+            _ => std::slice::from_ref(self),
+        }
+    }
+
+    pub fn into_values(self) -> Vec<Object> {
+        match self {
+            Object::Values(values) => values,
+            _ => vec![self],
+        }
+    }
 }
 
 impl fmt::Display for Object {
@@ -25,22 +86,33 @@ impl fmt::Display for Object {
             Object::Integer(n) => write!(f, "{}", n),
             Object::Float(f_) => write!(f, "{}", f_),
             Object::Bool(b) => write!(f, "{}", b),
+            Object::Char(c) => write!(f, "{}", c),
             Object::String(s) => write!(f, "{}", s),
             Object::Symbol(s) => write!(f, "{}", s),
             Object::Keyword(s) => write!(f, "{}", s),
+            Object::KeywordLiteral(s) => write!(f, ":{}", s),
             Object::If => write!(f, "if"),
             Object::BinaryOp(s) => write!(f, "{}", s),
-            Object::Lambda(params, body, _) => {
-                write!(f, "Lambda(")?;
-                for param in params {
-                    write!(f, "{} ", param)?;
-                }
-                write!(f, ")")?;
-                for expr in body {
+            Object::Lambda(closure) => {
+                let clause = &closure.lambda.clauses[0];
+                write!(f, "Lambda({})", clause.formals.params)?;
+                for expr in clause.source.iter() {
                     write!(f, " {}", expr)?;
                 }
                 Ok(())
             }
+            Object::CaseLambda(closure) => {
+                write!(f, "CaseLambda(")?;
+                for (i, clause) in closure.lambda.clauses.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "({})", clause.formals.params)?;
+                }
+                write!(f, ")")
+            }
+            Object::Builtin(builtin) => write!(f, "Builtin({})", builtin.name),
+            Object::Continuation(_) => write!(f, "Continuation"),
             Object::List(list) => {
                 write!(f, "(")?;
                 for (i, obj) in list.iter().enumerate() {
@@ -61,6 +133,93 @@ impl fmt::Display for Object {
                 }
                 write!(f, ")")
             }
+            Object::Vector(vector) => {
+                write!(f, "#(")?;
+                for (i, obj) in vector.borrow().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", obj)?;
+                }
+                write!(f, ")")
+            }
+            Object::HashTable(table) => write!(f, "{}", table.borrow()),
+            Object::PersistentVector(vector) => write!(f, "{}", vector),
+            Object::PersistentMap(map) => write!(f, "{}", map),
+            Object::Promise(_) => write!(f, "Promise"),
+            Object::Stream(stream) => write!(f, "{}", stream),
+            Object::Generator(_) => write!(f, "Generator"),
+            Object::Values(values) => {
+                for (i, obj) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{}", obj)?;
+                }
+                Ok(())
+            }
         }
     }
-}
\ No newline at end of file
+}
+
+/// Parameter list of a lambda, e.g. `(a b &optional (c 1) . rest)`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Params {
+    pub required: Vec<Symbol>,
+    /// Optional parameters with the expression for their default value.
+    /// The default is evaluated at call time, after the earlier parameters
+    /// have been bound.
+    pub optional: Vec<(Symbol, Object)>,
+    /// Parameter bound to a list of the remaining arguments.
+    pub rest: Option<Symbol>,
+    /// Keyword parameters, passed as `:name value` after the positional
+    /// arguments, with the expression for their default value.
+    pub keys: Vec<(Symbol, Object)>,
+}
+
+impl Params {
+    /// Whether a call with `arg_count` arguments can bind these parameters.
+    /// Keyword arguments are only checked when they are bound.
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        arg_count >= self.required.len()
+            && (self.rest.is_some()
+                || !self.keys.is_empty()
+                || arg_count <= self.required.len() + self.optional.len())
+    }
+
+    /// Describes the accepted number of arguments for error messages.
+    pub fn arity(&self) -> String {
+        let min = self.required.len();
+        let max = min + self.optional.len();
+        if self.rest.is_some() || !self.keys.is_empty() {
+            format!("at least {}", min)
+        } else if min == max {
+            format!("{}", min)
+        } else {
+            format!("{} to {}", min, max)
+        }
+    }
+}
+
+impl fmt::Display for Params {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut parts: Vec<String> = self.required.iter().map(|s| s.to_string()).collect();
+        if !self.optional.is_empty() {
+            parts.push("&optional".to_string());
+            for (name, default) in &self.optional {
+                parts.push(format!("({} {})", name, default));
+            }
+        }
+        if !self.keys.is_empty() {
+            parts.push("&key".to_string());
+            for (name, default) in &self.keys {
+                parts.push(format!("({} {})", name, default));
+            }
+        }
+        if let Some(rest) = &self.rest {
+            parts.push(".".to_string());
+            parts.push(rest.to_string());
+        }
+        write!(f, "{}", parts.join(" "))
+    }
+}
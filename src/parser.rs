@@ -1,5 +1,8 @@
+use crate::hash_table::{HashKey, HashTable};
 use crate::lexer::*;
 use crate::object::*;
+use crate::symbol::Symbol;
+use crate::sync::{Rc, RefCell};
 use std::error::Error;
 use std::fmt;
 
@@ -16,29 +19,43 @@ impl fmt::Display for ParseError {
 
 impl Error for ParseError {}
 
+/// Parses a program made of a single form.
 pub fn parse(program: &str) -> Result<Object, ParseError> {
-    let token_result = tokenize(program);
-    if token_result.is_err() {
-        return Err(ParseError {
-            err: format!("{}", token_result.err().unwrap()),
-        });
+    let mut forms = parse_program(program)?.into_iter();
+    match (forms.next(), forms.next()) {
+        (Some(form), None) => Ok(form),
+        (None, _) => Err(ParseError {
+            err: "Empty program".to_string(),
+        }),
+        (Some(_), Some(form)) => Err(ParseError {
+            err: format!("Unexpected {} after the end of the program", form),
+        }),
     }
+}
 
-    let mut tokens = token_result.unwrap().into_iter().rev().collect::<Vec<_>>();
-
-    let result = match tokens.last().unwrap() {
-        Token::LParen => {
-            parse_list(&mut tokens)?
-        },
-        Token::Integer(n) => Object::Integer(*n),
-        Token::Float(f) => Object::Float(*f),
-        Token::String(s) => Object::String(s.clone()),
-        Token::Symbol(s) => Object::Symbol(s.clone()),
-        Token::Keyword(s) => Object::Keyword(s.clone()),
-        _ => todo!()
+/// Parses every top-level form of `program`, in order.
+pub fn parse_program(program: &str) -> Result<Vec<Object>, ParseError> {
+    let mut tokens = match tokenize(program) {
+        Ok(tokens) => tokens.into_iter().rev().collect::<Vec<_>>(),
+        Err(err) => {
+            return Err(ParseError {
+                err: err.to_string(),
+            })
+        }
     };
 
-    Ok(result)
+    let mut forms = Vec::new();
+    while let Some(token) = tokens.last() {
+        let form = match token {
+            Token::LParen => parse_list(&mut tokens)?,
+            Token::VectorStart => parse_vector(&mut tokens)?,
+            Token::LBrace => parse_table(&mut tokens)?,
+            // Closing tokens are reported as unexpected.
+            _ => parse_atom(tokens.pop().unwrap())?,
+        };
+        forms.push(form);
+    }
+    Ok(forms)
 }
 
 fn parse_list(tokens: &mut Vec<Token>) -> Result<Object, ParseError> {
@@ -52,34 +69,128 @@ fn parse_list(tokens: &mut Vec<Token>) -> Result<Object, ParseError> {
     let mut list: Vec<Object> = Vec::new();
     while !tokens.is_empty() {
         let token = tokens.pop();
-        if token == None {
+        if token.is_none() {
             return Err(ParseError {
-                err: format!("Did not find enough tokens"),
+                err: "Did not find enough tokens".to_string(),
             });
         }
         let t = token.unwrap();
         match t {
-            Token::Integer(n) => list.push(Object::Integer(n)),
-            Token::Float(n) => list.push(Object::Float(n)),
-            Token::String(s) => list.push(Object::String(s)),
-            Token::Symbol(s) => list.push(Object::Symbol(s)),
-            Token::Keyword(s) => list.push(Object::Keyword(s)),
-            Token::If => list.push(Object::If),
-            Token::BinaryOp(b) => list.push(Object::BinaryOp(b)),
             Token::LParen => {
                 tokens.push(Token::LParen);
                 let sub_list = parse_list(tokens)?;
                 list.push(sub_list);
             }
+            Token::VectorStart => {
+                tokens.push(Token::VectorStart);
+                list.push(parse_vector(tokens)?);
+            }
+            Token::LBrace => {
+                tokens.push(Token::LBrace);
+                list.push(parse_table(tokens)?);
+            }
             Token::RParen => {
-                return Ok(Object::List(list));
+                return Ok(Object::List(Rc::new(list)));
             }
+            t => list.push(parse_atom(t)?),
         }
     }
 
-    Ok(Object::List(list))
+    Err(ParseError {
+        err: "Unterminated list".to_string(),
+    })
 }
 
+/// Parses `#(...)`. The elements are expressions, evaluated each time the
+/// vector is, see `resolve::Expr::Vector`.
+fn parse_vector(tokens: &mut Vec<Token>) -> Result<Object, ParseError> {
+    let token = tokens.pop();
+    if token != Some(Token::VectorStart) {
+        return Err(ParseError {
+            err: format!("Expected VectorStart, found {:?}", token),
+        });
+    }
+    tokens.push(Token::LParen);
+    match parse_list(tokens)? {
+        Object::List(list) => Ok(Object::Vector(Rc::new(RefCell::new(Rc::unwrap_or_clone(
+            list,
+        ))))),
+        _ => unreachable!(),
+    }
+}
+
+/// Parses `{key value ...}`. The keys are literals, checked to be hashable
+/// here. The values are expressions, evaluated each time the table is, see
+/// `resolve::Expr::HashTable`.
+fn parse_table(tokens: &mut Vec<Token>) -> Result<Object, ParseError> {
+    let token = tokens.pop();
+    if token != Some(Token::LBrace) {
+        return Err(ParseError {
+            err: format!("Expected LBrace, found {:?}", token),
+        });
+    }
+
+    let mut items = Vec::new();
+    loop {
+        match tokens.last() {
+            None => {
+                return Err(ParseError {
+                    err: "Unterminated hash table literal".to_string(),
+                })
+            }
+            Some(Token::RBrace) => {
+                tokens.pop();
+                break;
+            }
+            Some(Token::LParen) => items.push(parse_list(tokens)?),
+            Some(Token::VectorStart) => items.push(parse_vector(tokens)?),
+            Some(Token::LBrace) => items.push(parse_table(tokens)?),
+            Some(_) => {
+                let token = tokens.pop().unwrap();
+                items.push(parse_atom(token)?);
+            }
+        }
+    }
+
+    if !items.len().is_multiple_of(2) {
+        return Err(ParseError {
+            err: "Hash table literal needs an even number of items".to_string(),
+        });
+    }
+    let mut table = HashTable::new();
+    let mut items = items.into_iter();
+    while let (Some(key), Some(value)) = (items.next(), items.next()) {
+        let hash_key = match HashKey::from_object(&key) {
+            Some(hash_key) => hash_key,
+            None => {
+                return Err(ParseError {
+                    err: format!("Unhashable key in hash table literal: {}", key),
+                })
+            }
+        };
+        table.insert(hash_key, value);
+    }
+    Ok(Object::HashTable(Rc::new(RefCell::new(table))))
+}
+
+fn parse_atom(token: Token) -> Result<Object, ParseError> {
+    Ok(match token {
+        Token::Integer(n) => Object::Integer(n),
+        Token::Float(n) => Object::Float(n),
+        Token::String(s) => Object::String(s.into()),
+        Token::Symbol(s) => Object::Symbol(Symbol::intern(&s)),
+        Token::Keyword(s) => Object::Keyword(s),
+        Token::KeywordLiteral(s) => Object::KeywordLiteral(s),
+        Token::Char(c) => Object::Char(c),
+        Token::If => Object::If,
+        Token::BinaryOp(b) => Object::BinaryOp(b),
+        _ => {
+            return Err(ParseError {
+                err: format!("Unexpected {}", token),
+            })
+        }
+    })
+}
 
 #[cfg(test)]
 mod tests {
@@ -90,11 +201,11 @@ mod tests {
         let list = parse("(+ 1 2)").unwrap();
         assert_eq!(
             list,
-            Object::List(vec![
+            Object::List(Rc::new(vec![
                 Object::BinaryOp("+".to_string()),
                 Object::Integer(1),
                 Object::Integer(2),
-            ])
+            ]))
         );
     }
 
@@ -108,27 +219,93 @@ mod tests {
         let list = parse(program).unwrap();
         assert_eq!(
             list,
-            Object::List(vec![
-                Object::List(vec![
+            Object::List(Rc::new(vec![
+                Object::List(Rc::new(vec![
                     Object::Keyword("define".to_string()),
-                    Object::Symbol("r".to_string()),
+                    Object::Symbol("r".into()),
                     Object::Integer(10),
-                ]),
-                Object::List(vec![
+                ])),
+                Object::List(Rc::new(vec![
                     Object::Keyword("define".to_string()),
-                    Object::Symbol("pi".to_string()),
+                    Object::Symbol("pi".into()),
                     Object::Integer(314),
-                ]),
-                Object::List(vec![
+                ])),
+                Object::List(Rc::new(vec![
                     Object::BinaryOp("*".to_string()),
-                    Object::Symbol("pi".to_string()),
-                    Object::List(vec![
+                    Object::Symbol("pi".into()),
+                    Object::List(Rc::new(vec![
                         Object::BinaryOp("*".to_string()),
-                        Object::Symbol("r".to_string()),
-                        Object::Symbol("r".to_string()),
-                    ]),
-                ]),
-            ])
+                        Object::Symbol("r".into()),
+                        Object::Symbol("r".into()),
+                    ])),
+                ])),
+            ]))
+        );
+    }
+
+    #[test]
+    fn test_vector_literal() {
+        let vector = parse("#(1 (2) #(3))").unwrap();
+        assert_eq!(
+            vector,
+            Object::Vector(Rc::new(RefCell::new(vec![
+                Object::Integer(1),
+                Object::List(Rc::new(vec![Object::Integer(2)])),
+                Object::Vector(Rc::new(RefCell::new(vec![Object::Integer(3)]))),
+            ])))
+        );
+    }
+
+    #[test]
+    fn test_hash_table_literal() {
+        let table = parse("{:a 1 \"b\" (2)}").unwrap();
+        let mut expected = HashTable::new();
+        expected.insert(HashKey::KeywordLiteral("a".to_string()), Object::Integer(1));
+        expected.insert(
+            HashKey::String("b".into()),
+            Object::List(Rc::new(vec![Object::Integer(2)])),
+        );
+        assert_eq!(table, Object::HashTable(Rc::new(RefCell::new(expected))));
+
+        assert!(parse("{:a}").is_err());
+        assert!(parse("{#(1) 2}").is_err());
+        assert!(parse("(1 })").is_err());
+    }
+
+    #[test]
+    fn test_unexpected_closing_tokens() {
+        assert_eq!(
+            parse("}").unwrap_err().to_string(),
+            "Parse error: Unexpected }"
         );
+        assert_eq!(
+            parse(")").unwrap_err().to_string(),
+            "Parse error: Unexpected )"
+        );
+        assert!(parse("(a })").is_err());
+        assert!(parse("").is_err());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_unterminated_lists() {
+        assert_eq!(
+            parse("((define x 1) (+ x 41)").unwrap_err().to_string(),
+            "Parse error: Unterminated list"
+        );
+        assert_eq!(
+            parse("#(1 2").unwrap_err().to_string(),
+            "Parse error: Unterminated list"
+        );
+    }
+
+    #[test]
+    fn test_multiple_top_level_forms() {
+        let forms = parse_program("(define x 1) (+ x 41)").unwrap();
+        assert_eq!(forms.len(), 2);
+        assert_eq!(forms[1].to_string(), "(+ x 41)");
+        assert_eq!(
+            parse("(define x 1) (+ x 41)").unwrap_err().to_string(),
+            "Parse error: Unexpected (+ x 41) after the end of the program"
+        );
+    }
+}
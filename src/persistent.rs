@@ -0,0 +1,600 @@
+//! Immutable collections that share structure between versions, so that
+//! adding or replacing an element copies a path of small nodes instead of
+//! the whole collection.
+
+use std::collections::hash_map::DefaultHasher;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+
+use crate::hash_table::HashKey;
+use crate::object::Object;
+use crate::sync::Rc;
+
+const BITS: usize = 5;
+const WIDTH: usize = 1 << BITS;
+const MASK: usize = WIDTH - 1;
+
+#[derive(Clone)]
+enum VectorNode {
+    Branch(Vec<Rc<VectorNode>>),
+    Leaf(Vec<Object>),
+}
+
+/// A 32-way trie indexed by element position.
+#[derive(Clone)]
+pub struct PersistentVector {
+    len: usize,
+    /// Bits of the index consumed above the leaves, zero for a lone leaf.
+    shift: usize,
+    root: Rc<VectorNode>,
+}
+
+impl Default for PersistentVector {
+    fn default() -> Self {
+        PersistentVector {
+            len: 0,
+            shift: 0,
+            root: Rc::new(VectorNode::Leaf(Vec::new())),
+        }
+    }
+}
+
+impl PersistentVector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, index: usize) -> Option<&Object> {
+        if index >= self.len {
+            return None;
+        }
+        let mut node = &self.root;
+        let mut shift = self.shift;
+        loop {
+            match node.as_ref() {
+                VectorNode::Branch(children) => {
+                    node = &children[(index >> shift) & MASK];
+                    shift -= BITS;
+                }
+                VectorNode::Leaf(items) => return items.get(index & MASK),
+            }
+        }
+    }
+
+    /// Returns a copy with `value` appended.
+    pub fn push(&self, value: Object) -> Self {
+        if self.len == WIDTH << self.shift {
+            let root =
+                VectorNode::Branch(vec![self.root.clone(), Self::new_path(self.shift, value)]);
+            return PersistentVector {
+                len: self.len + 1,
+                shift: self.shift + BITS,
+                root: Rc::new(root),
+            };
+        }
+        PersistentVector {
+            len: self.len + 1,
+            shift: self.shift,
+            root: Self::push_in(&self.root, self.shift, self.len, value),
+        }
+    }
+
+    /// Returns a copy with the element at `index` replaced, or `None` if the
+    /// index is out of range.
+    pub fn set(&self, index: usize, value: Object) -> Option<Self> {
+        if index >= self.len {
+            return None;
+        }
+        Some(PersistentVector {
+            len: self.len,
+            shift: self.shift,
+            root: Self::set_in(&self.root, self.shift, index, value),
+        })
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Object> {
+        (0..self.len).map(move |i| self.get(i).unwrap())
+    }
+
+    fn new_path(shift: usize, value: Object) -> Rc<VectorNode> {
+        if shift == 0 {
+            Rc::new(VectorNode::Leaf(vec![value]))
+        } else {
+            Rc::new(VectorNode::Branch(vec![Self::new_path(
+                shift - BITS,
+                value,
+            )]))
+        }
+    }
+
+    fn push_in(node: &VectorNode, shift: usize, index: usize, value: Object) -> Rc<VectorNode> {
+        match node {
+            VectorNode::Leaf(items) => {
+                let mut items = items.clone();
+                items.push(value);
+                Rc::new(VectorNode::Leaf(items))
+            }
+            VectorNode::Branch(children) => {
+                let mut children = children.clone();
+                let sub = (index >> shift) & MASK;
+                if sub < children.len() {
+                    children[sub] = Self::push_in(&children[sub], shift - BITS, index, value);
+                } else {
+                    children.push(Self::new_path(shift - BITS, value));
+                }
+                Rc::new(VectorNode::Branch(children))
+            }
+        }
+    }
+
+    fn set_in(node: &VectorNode, shift: usize, index: usize, value: Object) -> Rc<VectorNode> {
+        match node {
+            VectorNode::Leaf(items) => {
+                let mut items = items.clone();
+                items[index & MASK] = value;
+                Rc::new(VectorNode::Leaf(items))
+            }
+            VectorNode::Branch(children) => {
+                let mut children = children.clone();
+                let sub = (index >> shift) & MASK;
+                children[sub] = Self::set_in(&children[sub], shift - BITS, index, value);
+                Rc::new(VectorNode::Branch(children))
+            }
+        }
+    }
+}
+
+impl FromIterator<Object> for PersistentVector {
+    fn from_iter<I: IntoIterator<Item = Object>>(iter: I) -> Self {
+        iter.into_iter()
+            .fold(PersistentVector::new(), |vector, item| vector.push(item))
+    }
+}
+
+impl PartialEq for PersistentVector {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len && self.iter().eq(other.iter())
+    }
+}
+
+impl fmt::Debug for PersistentVector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl fmt::Display for PersistentVector {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[")?;
+        for (i, obj) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{}", obj)?;
+        }
+        write!(f, "]")
+    }
+}
+
+fn hash_of(key: &HashKey) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[derive(Clone)]
+enum MapChild {
+    Entry(u64, HashKey, Object),
+    Node(Rc<MapNode>),
+}
+
+#[derive(Clone)]
+enum MapNode {
+    /// Children for each 5-bit slice of the hash present in `bitmap`, in
+    /// slice order.
+    Branch {
+        bitmap: u32,
+        children: Vec<MapChild>,
+    },
+    /// Entries whose keys have the same 64-bit hash.
+    Collision(u64, Vec<(HashKey, Object)>),
+}
+
+impl MapChild {
+    fn hash(&self) -> u64 {
+        match self {
+            MapChild::Entry(hash, _, _) => *hash,
+            MapChild::Node(node) => match node.as_ref() {
+                MapNode::Collision(hash, _) => *hash,
+                // Only collision nodes are ever merged with a new entry.
+                MapNode::Branch { .. } => unreachable!(),
+            },
+        }
+    }
+}
+
+fn slice(hash: u64, shift: usize) -> u32 {
+    1 << ((hash >> shift) as usize & MASK)
+}
+
+/// A hash array mapped trie.
+#[derive(Clone, Default)]
+pub struct PersistentMap {
+    len: usize,
+    root: Option<Rc<MapNode>>,
+}
+
+impl PersistentMap {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get(&self, key: &HashKey) -> Option<&Object> {
+        self.get_hashed(hash_of(key), key)
+    }
+
+    fn get_hashed(&self, hash: u64, key: &HashKey) -> Option<&Object> {
+        let mut node = self.root.as_ref()?;
+        let mut shift = 0;
+        loop {
+            match node.as_ref() {
+                MapNode::Branch { bitmap, children } => {
+                    let bit = slice(hash, shift);
+                    if bitmap & bit == 0 {
+                        return None;
+                    }
+                    match &children[(bitmap & (bit - 1)).count_ones() as usize] {
+                        MapChild::Entry(_, k, v) => return if k == key { Some(v) } else { None },
+                        MapChild::Node(child) => node = child,
+                    }
+                    shift += BITS;
+                }
+                MapNode::Collision(_, entries) => {
+                    return entries.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+                }
+            }
+        }
+    }
+
+    /// Returns a copy with `key` bound to `value`.
+    pub fn insert(&self, key: HashKey, value: Object) -> Self {
+        self.insert_hashed(hash_of(&key), key, value)
+    }
+
+    fn insert_hashed(&self, hash: u64, key: HashKey, value: Object) -> Self {
+        match &self.root {
+            None => PersistentMap {
+                len: 1,
+                root: Some(Rc::new(MapNode::Branch {
+                    bitmap: slice(hash, 0),
+                    children: vec![MapChild::Entry(hash, key, value)],
+                })),
+            },
+            Some(root) => {
+                let (root, added) = Self::insert_in(root, 0, hash, key, value);
+                PersistentMap {
+                    len: self.len + added as usize,
+                    root: Some(root),
+                }
+            }
+        }
+    }
+
+    /// Returns a copy without `key`, or `None` if the key is not present.
+    pub fn remove(&self, key: &HashKey) -> Option<Self> {
+        self.remove_hashed(hash_of(key), key)
+    }
+
+    fn remove_hashed(&self, hash: u64, key: &HashKey) -> Option<Self> {
+        let root = Self::remove_in(self.root.as_ref()?, 0, hash, key)?;
+        Some(PersistentMap {
+            len: self.len - 1,
+            root,
+        })
+    }
+
+    /// Entries in hash order, which is stable for a given set of keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&HashKey, &Object)> {
+        let mut entries = Vec::with_capacity(self.len);
+        if let Some(root) = &self.root {
+            Self::collect(root, &mut entries);
+        }
+        entries.into_iter()
+    }
+
+    fn collect<'a>(node: &'a MapNode, entries: &mut Vec<(&'a HashKey, &'a Object)>) {
+        match node {
+            MapNode::Branch { children, .. } => {
+                for child in children {
+                    match child {
+                        MapChild::Entry(_, key, value) => entries.push((key, value)),
+                        MapChild::Node(node) => Self::collect(node, entries),
+                    }
+                }
+            }
+            MapNode::Collision(_, items) => entries.extend(items.iter().map(|(k, v)| (k, v))),
+        }
+    }
+
+    fn insert_in(
+        node: &MapNode,
+        shift: usize,
+        hash: u64,
+        key: HashKey,
+        value: Object,
+    ) -> (Rc<MapNode>, bool) {
+        match node {
+            MapNode::Branch { bitmap, children } => {
+                let bit = slice(hash, shift);
+                let index = (bitmap & (bit - 1)).count_ones() as usize;
+                let mut children = children.clone();
+                let added = if bitmap & bit == 0 {
+                    children.insert(index, MapChild::Entry(hash, key, value));
+                    true
+                } else {
+                    let (child, added) = match &children[index] {
+                        MapChild::Entry(_, k, _) if *k == key => {
+                            (MapChild::Entry(hash, key, value), false)
+                        }
+                        MapChild::Node(child) if Self::is_branch(child) => {
+                            let (child, added) =
+                                Self::insert_in(child, shift + BITS, hash, key, value);
+                            (MapChild::Node(child), added)
+                        }
+                        MapChild::Node(child) if hash_collides(child, hash) => {
+                            let (child, added) =
+                                Self::insert_in(child, shift + BITS, hash, key, value);
+                            (MapChild::Node(child), added)
+                        }
+                        existing => {
+                            let entry = MapChild::Entry(hash, key, value);
+                            let node = Self::merge(shift + BITS, existing.clone(), entry);
+                            (MapChild::Node(node), true)
+                        }
+                    };
+                    children[index] = child;
+                    added
+                };
+                (
+                    Rc::new(MapNode::Branch {
+                        bitmap: bitmap | bit,
+                        children,
+                    }),
+                    added,
+                )
+            }
+            MapNode::Collision(collision_hash, entries) => {
+                let mut entries = entries.clone();
+                let added = match entries.iter_mut().find(|(k, _)| *k == key) {
+                    Some(entry) => {
+                        entry.1 = value;
+                        false
+                    }
+                    None => {
+                        entries.push((key, value));
+                        true
+                    }
+                };
+                (Rc::new(MapNode::Collision(*collision_hash, entries)), added)
+            }
+        }
+    }
+
+    fn is_branch(node: &MapNode) -> bool {
+        matches!(node, MapNode::Branch { .. })
+    }
+
+    /// Builds the smallest node holding two children with different keys.
+    fn merge(shift: usize, a: MapChild, b: MapChild) -> Rc<MapNode> {
+        let (hash_a, hash_b) = (a.hash(), b.hash());
+        if hash_a == hash_b {
+            let mut entries = Vec::new();
+            for child in [a, b] {
+                match child {
+                    MapChild::Entry(_, key, value) => entries.push((key, value)),
+                    MapChild::Node(node) => {
+                        if let MapNode::Collision(_, items) = node.as_ref() {
+                            entries.extend(items.iter().cloned());
+                        }
+                    }
+                }
+            }
+            return Rc::new(MapNode::Collision(hash_a, entries));
+        }
+        let (bit_a, bit_b) = (slice(hash_a, shift), slice(hash_b, shift));
+        let children = if bit_a == bit_b {
+            vec![MapChild::Node(Self::merge(shift + BITS, a, b))]
+        } else if bit_a < bit_b {
+            vec![a, b]
+        } else {
+            vec![b, a]
+        };
+        Rc::new(MapNode::Branch {
+            bitmap: bit_a | bit_b,
+            children,
+        })
+    }
+
+    /// `None` if the key is absent, `Some(None)` if the node became empty.
+    fn remove_in(
+        node: &MapNode,
+        shift: usize,
+        hash: u64,
+        key: &HashKey,
+    ) -> Option<Option<Rc<MapNode>>> {
+        match node {
+            MapNode::Branch { bitmap, children } => {
+                let bit = slice(hash, shift);
+                if bitmap & bit == 0 {
+                    return None;
+                }
+                let index = (bitmap & (bit - 1)).count_ones() as usize;
+                let replacement = match &children[index] {
+                    MapChild::Entry(_, k, _) if k == key => None,
+                    MapChild::Entry(_, _, _) => return None,
+                    MapChild::Node(child) => {
+                        Self::remove_in(child, shift + BITS, hash, key)?.map(MapChild::Node)
+                    }
+                };
+                let mut children = children.clone();
+                let bitmap = match replacement {
+                    Some(child) => {
+                        children[index] = child;
+                        *bitmap
+                    }
+                    None => {
+                        children.remove(index);
+                        bitmap & !bit
+                    }
+                };
+                if children.is_empty() {
+                    return Some(None);
+                }
+                Some(Some(Rc::new(MapNode::Branch { bitmap, children })))
+            }
+            MapNode::Collision(collision_hash, entries) => {
+                let index = entries.iter().position(|(k, _)| k == key)?;
+                let mut entries = entries.clone();
+                entries.remove(index);
+                if entries.is_empty() {
+                    return Some(None);
+                }
+                Some(Some(Rc::new(MapNode::Collision(*collision_hash, entries))))
+            }
+        }
+    }
+}
+
+fn hash_collides(node: &MapNode, hash: u64) -> bool {
+    matches!(node, MapNode::Collision(collision_hash, _) if *collision_hash == hash)
+}
+
+impl PartialEq for PersistentMap {
+    fn eq(&self, other: &Self) -> bool {
+        self.len == other.len
+            && self
+                .iter()
+                .all(|(key, value)| other.get(key) == Some(value))
+    }
+}
+
+impl fmt::Debug for PersistentMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map().entries(self.iter()).finish()
+    }
+}
+
+/// Written with commas between entries to tell it apart from a mutable
+/// hash table.
+impl fmt::Display for PersistentMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{{")?;
+        for (i, (key, value)) in self.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{} {}", key.to_object(), value)?;
+        }
+        write!(f, "}}")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_vector_push_and_set() {
+        let mut versions = vec![PersistentVector::new()];
+        for i in 0..2000 {
+            let next = versions.last().unwrap().push(Object::Integer(i));
+            versions.push(next);
+        }
+        let full = versions.last().unwrap();
+        assert_eq!(full.len(), 2000);
+        assert!((0..2000).all(|i| full.get(i as usize) == Some(&Object::Integer(i))));
+        // Older versions are unchanged.
+        assert_eq!(versions[40].len(), 40);
+        assert_eq!(versions[40].get(39), Some(&Object::Integer(39)));
+        assert_eq!(versions[40].get(40), None);
+
+        let changed = full.set(1500, Object::Bool(true)).unwrap();
+        assert_eq!(changed.get(1500), Some(&Object::Bool(true)));
+        assert_eq!(full.get(1500), Some(&Object::Integer(1500)));
+        assert!(full.set(2000, Object::Void).is_none());
+        assert_ne!(&changed, full);
+    }
+
+    #[test]
+    fn test_map_insert_and_remove() {
+        let mut map = PersistentMap::new();
+        for i in 0..1000 {
+            map = map.insert(HashKey::Integer(i), Object::Integer(i * 2));
+        }
+        let before = map.clone();
+        map = map.insert(HashKey::Integer(7), Object::Void);
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.get(&HashKey::Integer(7)), Some(&Object::Void));
+        assert_eq!(before.get(&HashKey::Integer(7)), Some(&Object::Integer(14)));
+
+        for i in 0..1000 {
+            map = map.remove(&HashKey::Integer(i)).unwrap();
+        }
+        assert!(map.is_empty());
+        assert!(map.remove(&HashKey::Integer(0)).is_none());
+        assert_eq!(before.iter().count(), 1000);
+    }
+
+    #[test]
+    fn test_map_collisions() {
+        let (one, two, three) = (
+            HashKey::Integer(1),
+            HashKey::Integer(2),
+            HashKey::Integer(3),
+        );
+        let map = PersistentMap::new()
+            .insert_hashed(42, one.clone(), Object::Integer(1))
+            .insert_hashed(42, two.clone(), Object::Integer(2))
+            .insert_hashed(42 + (1 << 20), three.clone(), Object::Integer(3));
+        assert_eq!(map.len(), 3);
+        assert_eq!(map.get_hashed(42, &two), Some(&Object::Integer(2)));
+        assert_eq!(map.get_hashed(42, &three), None);
+        assert_eq!(
+            map.get_hashed(42 + (1 << 20), &three),
+            Some(&Object::Integer(3))
+        );
+
+        let map = map.remove_hashed(42, &one).unwrap();
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.get_hashed(42, &one), None);
+        assert_eq!(map.get_hashed(42, &two), Some(&Object::Integer(2)));
+        assert_eq!(map.iter().count(), 2);
+    }
+
+    #[test]
+    fn test_map_equality_ignores_order() {
+        let a = PersistentMap::new()
+            .insert(HashKey::Integer(1), Object::Integer(1))
+            .insert(HashKey::Integer(2), Object::Integer(2));
+        let b = PersistentMap::new()
+            .insert(HashKey::Integer(2), Object::Integer(2))
+            .insert(HashKey::Integer(1), Object::Integer(1));
+        assert_eq!(a, b);
+    }
+}
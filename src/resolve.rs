@@ -0,0 +1,744 @@
+//! Lexical addressing: the pass that turns parsed code into `Expr`s before
+//! it is evaluated.
+//!
+//! Every variable is resolved once, here, instead of by name on every use.
+//! Locals become a `(depth, index)` pair into the chain of frames of the
+//! running procedure, and globals become the cell the global environment
+//! keeps the value in. A lambda records which variables of its surrounding
+//! procedures it uses, and its closures capture just those.
+//!
+//! A `define` inside a procedure or `let` body binds a local of that body
+//! for the whole body, so procedures defined next to each other can call
+//! each other. Malformed forms are turned into `Expr::Error`, so that they
+//! fail when they are evaluated, as they did before this pass existed.
+
+use std::fmt;
+
+use crate::{
+    bytecode::Chunk,
+    env::{Cell, Env, Frame},
+    eval::EvalError,
+    hash_table::HashKey,
+    object::{Object, Params},
+    symbol::{self, Symbol},
+    sync::{OnceCell, Rc, RefCell},
+};
+
+/// Where a variable is stored.
+#[derive(Debug, Clone)]
+pub enum VarRef {
+    /// Slot `index` of the frame `depth` levels up from the current one.
+    Local {
+        depth: usize,
+        index: usize,
+    },
+    Global(Cell),
+}
+
+/// Resolved code.
+#[derive(Debug)]
+pub enum Expr {
+    Const(Object),
+    Var(VarRef, Symbol),
+    Define(VarRef, Symbol, Box<Expr>),
+    If(Box<Expr>, Rc<Expr>, Rc<Expr>),
+    Begin(Body),
+    Cond(Vec<CondClause>),
+    Let(Box<Let>),
+    /// `let-values` and `receive`.
+    LetValues(Rc<LetValues>),
+    Lambda(Rc<Lambda>),
+    CaseLambda(Rc<Lambda>),
+    /// A call whose head is a name, e.g. `(f x)`.
+    Call(VarRef, Symbol, Vec<Expr>),
+    /// A list whose head is any other expression. It is a call if the head
+    /// evaluates to a procedure, and otherwise collects the values of its
+    /// elements, e.g. the results of a top-level program.
+    Combination(Box<Expr>, Vec<Expr>),
+    BinaryOp(String, Box<Expr>, Box<Expr>),
+    List(Vec<Expr>),
+    /// A `#(...)` literal, which makes a new vector each time it is
+    /// evaluated.
+    Vector(Vec<Expr>),
+    /// A `{key value ...}` literal, which makes a new hash table each time
+    /// it is evaluated. Only the values are evaluated.
+    HashTable(Vec<(HashKey, Expr)>),
+    Delay(Rc<Expr>),
+    ConsStream(Box<Expr>, Rc<Expr>),
+    /// A malformed form, reported when it is evaluated.
+    Error(EvalError),
+}
+
+/// A sequence of expressions. The last one is evaluated in tail position.
+#[derive(Debug)]
+pub struct Body {
+    pub init: Vec<Expr>,
+    pub last: Rc<Expr>,
+}
+
+#[derive(Debug)]
+pub struct CondClause {
+    pub test: Expr,
+    /// `None` if the clause yields the value of its test.
+    pub body: Option<Body>,
+}
+
+#[derive(Debug)]
+pub struct Let {
+    pub inits: Vec<Expr>,
+    pub size: usize,
+    pub body: Body,
+}
+
+#[derive(Debug)]
+pub struct LetValues {
+    pub bindings: Vec<(Formals, Expr)>,
+    pub size: usize,
+    pub body: Body,
+}
+
+/// A parameter list with the code of its default values. The parameters
+/// take the slots from `start` on, in the order required, optional, keys
+/// and rest.
+#[derive(Debug)]
+pub struct Formals {
+    pub params: Params,
+    /// Defaults of the optional parameters followed by those of the keys.
+    pub defaults: Vec<Expr>,
+    pub start: usize,
+}
+
+impl Formals {
+    pub fn optional_slot(&self, i: usize) -> usize {
+        self.start + self.params.required.len() + i
+    }
+
+    pub fn key_slot(&self, i: usize) -> usize {
+        self.optional_slot(self.params.optional.len() + i)
+    }
+
+    pub fn rest_slot(&self) -> usize {
+        self.key_slot(self.params.keys.len())
+    }
+
+    fn names(&self) -> impl Iterator<Item = Symbol> + '_ {
+        let params = &self.params;
+        params
+            .required
+            .iter()
+            .copied()
+            .chain(params.optional.iter().map(|(name, _)| *name))
+            .chain(params.keys.iter().map(|(name, _)| *name))
+            .chain(params.rest)
+    }
+}
+
+/// The code of a `lambda`, or of a `case-lambda` with one clause per case.
+#[derive(Debug)]
+pub struct Lambda {
+    pub clauses: Vec<Clause>,
+    /// The variables of the surrounding procedures the body uses, resolved
+    /// where the lambda is evaluated. They become the frame its closures
+    /// capture.
+    pub captures: Vec<(usize, usize)>,
+}
+
+#[derive(Debug)]
+pub struct Clause {
+    pub formals: Formals,
+    /// Number of slots in the frame of a call: the parameters and the
+    /// variables defined in the body.
+    pub size: usize,
+    pub body: Body,
+    /// The body as written, for printing.
+    pub source: Vec<Object>,
+    /// The body compiled to bytecode, the first time it is run by the VM.
+    pub compiled: OnceCell<Rc<Chunk>>,
+}
+
+/// A procedure created by evaluating a `lambda` or `case-lambda`.
+pub struct Closure {
+    pub lambda: Rc<Lambda>,
+    pub captured: Rc<Frame>,
+}
+
+impl PartialEq for Closure {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl fmt::Debug for Closure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Closure(")?;
+        for (i, clause) in self.lambda.clauses.iter().enumerate() {
+            if i > 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "({})", clause.formals.params)?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// Resolves parsed code against the global environment `env`.
+pub fn resolve(obj: &Object, env: &Rc<RefCell<Env>>) -> Expr {
+    let mut resolver = Resolver {
+        scopes: Vec::new(),
+        env: env.clone(),
+    };
+    resolver.expr(obj)
+}
+
+/// The variables a procedure's closures capture, with where each is found
+/// outside of the procedure.
+type Captures = Vec<(Symbol, (usize, usize))>;
+
+/// The variables of one frame while its code is being resolved.
+struct Scope {
+    names: Vec<Symbol>,
+    /// `Some` for the outermost scope of a procedure.
+    captures: Option<Captures>,
+}
+
+struct Resolver {
+    scopes: Vec<Scope>,
+    env: Rc<RefCell<Env>>,
+}
+
+impl Resolver {
+    fn expr(&mut self, obj: &Object) -> Expr {
+        match obj {
+            Object::List(list) if list.is_empty() => Expr::Const(Object::Void),
+            Object::List(list) => self.form(list).unwrap_or_else(Expr::Error),
+            Object::Symbol(symbol::TRUE) => Expr::Const(Object::Bool(true)),
+            Object::Symbol(symbol::FALSE) => Expr::Const(Object::Bool(false)),
+            Object::Symbol(symbol::NIL) => Expr::Const(Object::Void),
+            Object::Symbol(name) => Expr::Var(self.lookup(*name), *name),
+            Object::BinaryOp(op) => {
+                let name = Symbol::intern(op);
+                Expr::Var(self.lookup(name), name)
+            }
+            Object::Keyword(_) | Object::If => {
+                Expr::Error(format!("Invalid object: {:?},", obj).into())
+            }
+            Object::Vector(items) => Expr::Vector(self.exprs(&items.borrow())),
+            Object::HashTable(table) => Expr::HashTable(
+                table
+                    .borrow()
+                    .iter()
+                    .map(|(key, value)| (key.clone(), self.expr(value)))
+                    .collect(),
+            ),
+            _ => Expr::Const(obj.clone()),
+        }
+    }
+
+    fn exprs(&mut self, objs: &[Object]) -> Vec<Expr> {
+        objs.iter().map(|obj| self.expr(obj)).collect()
+    }
+
+    fn body(&mut self, objs: &[Object]) -> Body {
+        match objs.split_last() {
+            Some((last, init)) => Body {
+                init: self.exprs(init),
+                last: Rc::new(self.expr(last)),
+            },
+            None => Body {
+                init: Vec::new(),
+                last: Rc::new(Expr::Const(Object::Void)),
+            },
+        }
+    }
+
+    /// Finds a variable visible from the innermost `level` scopes.
+    fn lookup_in(&mut self, name: Symbol, level: usize) -> VarRef {
+        for (depth, i) in (0..level).rev().enumerate() {
+            if let Some(index) = self.scopes[i].names.iter().rposition(|n| *n == name) {
+                return VarRef::Local { depth, index };
+            }
+            // The frame above the outermost frame of a procedure holds the
+            // variables its closures capture.
+            let depth = depth + 1;
+            let captures = match &self.scopes[i].captures {
+                Some(captures) => captures,
+                None => continue,
+            };
+            if let Some(index) = captures.iter().position(|(n, _)| *n == name) {
+                return VarRef::Local { depth, index };
+            }
+            return match self.lookup_in(name, i) {
+                VarRef::Local {
+                    depth: outer_depth,
+                    index: outer_index,
+                } => {
+                    let captures = self.scopes[i].captures.as_mut().unwrap();
+                    captures.push((name, (outer_depth, outer_index)));
+                    VarRef::Local {
+                        depth,
+                        index: captures.len() - 1,
+                    }
+                }
+                global => global,
+            };
+        }
+        VarRef::Global(self.env.borrow_mut().cell(name))
+    }
+
+    fn lookup(&mut self, name: Symbol) -> VarRef {
+        self.lookup_in(name, self.scopes.len())
+    }
+
+    /// Runs `resolve` in a new scope with the variables `names` and those
+    /// that `body` defines. Returns its result, the size of the scope's
+    /// frame and the variables captured if it is a procedure scope.
+    fn scoped<T>(
+        &mut self,
+        mut names: Vec<Symbol>,
+        captures: Option<Captures>,
+        body: &[Object],
+        resolve: impl FnOnce(&mut Self) -> T,
+    ) -> (T, usize, Option<Captures>) {
+        scan_defines(body, &mut names);
+        self.scopes.push(Scope { names, captures });
+        let resolved = resolve(self);
+        let scope = self.scopes.pop().unwrap();
+        (resolved, scope.names.len(), scope.captures)
+    }
+
+    fn form(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        let head = &list[0];
+        match head {
+            Object::BinaryOp(op) => {
+                if list.len() != 3 {
+                    return Err(format!("Invalid number of arguments for {} operator", head).into());
+                }
+                Ok(Expr::BinaryOp(
+                    op.clone(),
+                    Box::new(self.expr(&list[1])),
+                    Box::new(self.expr(&list[2])),
+                ))
+            }
+            Object::Keyword(k) => match k.as_str() {
+                "begin" => Ok(Expr::Begin(self.body(&list[1..]))),
+                "let" => self.let_(list),
+                "let-values" => self.let_values(list),
+                "receive" => self.receive(list),
+                "cond" => Ok(self.cond(list)),
+                "define" => self.define(list),
+                "list" => Ok(Expr::List(self.exprs(&list[1..]))),
+                "lambda" => self.lambda(list),
+                "case-lambda" => self.case_lambda(list),
+                "delay" => {
+                    if list.len() != 2 {
+                        return Err("Invalid number of arguments for delay".into());
+                    }
+                    Ok(Expr::Delay(Rc::new(self.expr(&list[1]))))
+                }
+                "cons-stream" => {
+                    if list.len() != 3 {
+                        return Err("Invalid number of arguments for cons-stream".into());
+                    }
+                    Ok(Expr::ConsStream(
+                        Box::new(self.expr(&list[1])),
+                        Rc::new(self.expr(&list[2])),
+                    ))
+                }
+                _ => Err(format!("Invalid keyword: {}", head).into()),
+            },
+            Object::If => {
+                if list.len() != 4 {
+                    return Err("Invalid number of arguments for if statement".into());
+                }
+                Ok(Expr::If(
+                    Box::new(self.expr(&list[1])),
+                    Rc::new(self.expr(&list[2])),
+                    Rc::new(self.expr(&list[3])),
+                ))
+            }
+            Object::Symbol(name) => Ok(Expr::Call(
+                self.lookup(*name),
+                *name,
+                self.exprs(&list[1..]),
+            )),
+            _ => Ok(Expr::Combination(
+                Box::new(self.expr(head)),
+                self.exprs(&list[1..]),
+            )),
+        }
+    }
+
+    fn define(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        if list.len() != 3 {
+            return Err("Invalid number of arguments for define".into());
+        }
+        let name = match &list[1] {
+            Object::Symbol(name) => *name,
+            _ => return Err("Invalid define".into()),
+        };
+        // The name was added to the innermost scope by `scan_defines`.
+        let var = self.lookup(name);
+        Ok(Expr::Define(var, name, Box::new(self.expr(&list[2]))))
+    }
+
+    /// `(let ((name expr) ...) body ...)`. The expressions are resolved
+    /// outside of the new scope.
+    fn let_(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        if list.len() < 3 {
+            return Err("Invalid number of arguments for let".into());
+        }
+        let bindings = match &list[1] {
+            Object::List(bindings) => bindings,
+            _ => return Err("Invalid let bindings".into()),
+        };
+        let mut names = Vec::with_capacity(bindings.len());
+        let mut inits = Vec::with_capacity(bindings.len());
+        for binding in bindings.iter() {
+            match binding {
+                Object::List(pair) if pair.len() == 2 => match &pair[0] {
+                    Object::Symbol(name) => {
+                        names.push(*name);
+                        inits.push(&pair[1]);
+                    }
+                    _ => return Err(format!("Invalid let binding: {}", binding).into()),
+                },
+                _ => return Err(format!("Invalid let binding: {}", binding).into()),
+            }
+        }
+        let inits = inits.into_iter().map(|init| self.expr(init)).collect();
+        let (body, size, _) = self.scoped(names, None, &list[2..], |r| r.body(&list[2..]));
+        Ok(Expr::Let(Box::new(Let { inits, size, body })))
+    }
+
+    /// `(let-values ((formals expr) ...) body ...)`. The formals of all
+    /// bindings share one frame.
+    fn let_values(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        if list.len() < 3 {
+            return Err("Invalid number of arguments for let-values".into());
+        }
+        let bindings = match &list[1] {
+            Object::List(bindings) => bindings,
+            _ => return Err("Invalid let-values bindings".into()),
+        };
+        let mut parsed = Vec::with_capacity(bindings.len());
+        for binding in bindings.iter() {
+            match binding {
+                Object::List(pair) if pair.len() == 2 => {
+                    parsed.push((parse_params(&pair[0])?, &pair[1]));
+                }
+                _ => return Err(format!("Invalid let-values binding: {}", binding).into()),
+            }
+        }
+        Ok(self.bind_values(parsed, &list[2..]))
+    }
+
+    /// `(receive formals expr body ...)`, the single-binding form of
+    /// `let-values`.
+    fn receive(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        if list.len() < 4 {
+            return Err("Invalid number of arguments for receive".into());
+        }
+        let params = parse_params(&list[1])?;
+        Ok(self.bind_values(vec![(params, &list[2])], &list[3..]))
+    }
+
+    fn bind_values(&mut self, bindings: Vec<(Params, &Object)>, body: &[Object]) -> Expr {
+        let mut formals = Vec::with_capacity(bindings.len());
+        let mut inits = Vec::with_capacity(bindings.len());
+        let mut start = 0;
+        for (params, init) in bindings {
+            inits.push(self.expr(init));
+            let f = Formals {
+                params,
+                defaults: Vec::new(),
+                start,
+            };
+            start = f.rest_slot() + usize::from(f.params.rest.is_some());
+            formals.push(f);
+        }
+        let names = formals.iter().flat_map(Formals::names).collect();
+        let ((bindings, body), size, _) = self.scoped(names, None, body, |r| {
+            let bindings = formals
+                .into_iter()
+                .zip(inits)
+                .map(|(mut formals, init)| {
+                    formals.defaults = r.defaults(&formals.params);
+                    (formals, init)
+                })
+                .collect();
+            (bindings, r.body(body))
+        });
+        Expr::LetValues(Rc::new(LetValues {
+            bindings,
+            size,
+            body,
+        }))
+    }
+
+    fn defaults(&mut self, params: &Params) -> Vec<Expr> {
+        params
+            .optional
+            .iter()
+            .chain(params.keys.iter())
+            .map(|(_, default)| self.expr(default))
+            .collect()
+    }
+
+    /// Finds the first `cond` clause whose test is true. A malformed clause
+    /// is only reported if the clauses before it are all false.
+    fn cond(&mut self, list: &[Object]) -> Expr {
+        let clauses = list[1..]
+            .iter()
+            .map(|clause| match clause {
+                Object::List(clause) if !clause.is_empty() => CondClause {
+                    test: match &clause[0] {
+                        Object::Symbol(symbol::ELSE) => Expr::Const(Object::Bool(true)),
+                        test => self.expr(test),
+                    },
+                    body: (clause.len() > 1).then(|| self.body(&clause[1..])),
+                },
+                _ => CondClause {
+                    test: Expr::Error(format!("Invalid cond clause: {}", clause).into()),
+                    body: None,
+                },
+            })
+            .collect();
+        Expr::Cond(clauses)
+    }
+
+    fn lambda(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        if list.len() < 3 {
+            return Err("Invalid lambda".into());
+        }
+        let params = parse_params(&list[1])?;
+        Ok(Expr::Lambda(self.procedure(vec![(params, &list[2..])])))
+    }
+
+    /// `(case-lambda (params body ...) ...)`. A call runs the first clause
+    /// that accepts the number of arguments given.
+    fn case_lambda(&mut self, list: &[Object]) -> Result<Expr, EvalError> {
+        let mut clauses = Vec::new();
+        for clause in list[1..].iter() {
+            match clause {
+                Object::List(clause) if clause.len() >= 2 => {
+                    clauses.push((parse_params(&clause[0])?, &clause[1..]));
+                }
+                _ => return Err(format!("Invalid case-lambda clause: {}", clause).into()),
+            }
+        }
+        Ok(Expr::CaseLambda(self.procedure(clauses)))
+    }
+
+    /// Resolves the clauses of a procedure. They share the variables the
+    /// closures capture.
+    fn procedure(&mut self, clauses: Vec<(Params, &[Object])>) -> Rc<Lambda> {
+        let mut captures = Some(Vec::new());
+        let mut resolved = Vec::with_capacity(clauses.len());
+        for (params, body) in clauses {
+            let mut formals = Formals {
+                params,
+                defaults: Vec::new(),
+                start: 0,
+            };
+            let names = formals.names().collect();
+            let ((defaults, code), size, used) = self.scoped(names, captures.take(), body, |r| {
+                (r.defaults(&formals.params), r.body(body))
+            });
+            captures = used;
+            formals.defaults = defaults;
+            resolved.push(Clause {
+                formals,
+                size,
+                body: code,
+                source: body.to_vec(),
+                compiled: OnceCell::new(),
+            });
+        }
+        Rc::new(Lambda {
+            clauses: resolved,
+            captures: captures
+                .unwrap()
+                .into_iter()
+                .map(|(_, outer)| outer)
+                .collect(),
+        })
+    }
+}
+
+/// Adds the names that `define` forms in `body` bind in the body's own
+/// frame. Forms that run in the same frame are searched too, e.g. the
+/// branches of an `if` and the expression of a `delay`, but not the bodies
+/// of nested procedures and `let`s.
+fn scan_defines(body: &[Object], names: &mut Vec<Symbol>) {
+    for obj in body {
+        let list = match obj {
+            Object::List(list) => list,
+            _ => continue,
+        };
+        match list.first() {
+            Some(Object::Keyword(k)) => match k.as_str() {
+                "define" => {
+                    if let Some(Object::Symbol(name)) = list.get(1) {
+                        if !names.contains(name) {
+                            names.push(*name);
+                        }
+                    }
+                    scan_defines(&list[1..], names);
+                }
+                "lambda" | "case-lambda" => {}
+                "let" | "let-values" => {
+                    if let Some(Object::List(bindings)) = list.get(1) {
+                        for binding in bindings.iter() {
+                            if let Object::List(pair) = binding {
+                                scan_defines(&pair[1..], names);
+                            }
+                        }
+                    }
+                }
+                "receive" => {
+                    if let Some(init) = list.get(2) {
+                        scan_defines(std::slice::from_ref(init), names);
+                    }
+                }
+                _ => scan_defines(&list[1..], names),
+            },
+            _ => scan_defines(list, names),
+        }
+    }
+}
+
+/// Which kind of parameter a plain name in a lambda parameter list declares.
+enum ParamKind {
+    Required,
+    Optional,
+    Key,
+}
+
+/// Parses a lambda parameter list. Besides plain names it accepts
+/// `&optional` and `&key` (or `#!optional` and `#!key`), each followed by
+/// `name` or `(name default)`, and a rest parameter written `. rest` or
+/// `&rest rest`. A single symbol instead of a list takes all arguments as a
+/// list.
+fn parse_params(obj: &Object) -> Result<Params, EvalError> {
+    let list = match obj {
+        Object::Symbol(rest) => {
+            return Ok(Params {
+                rest: Some(*rest),
+                ..Default::default()
+            })
+        }
+        Object::List(list) => list,
+        _ => return Err("Invalid lambda".into()),
+    };
+
+    let mut params = Params::default();
+    let mut kind = ParamKind::Required;
+    let mut iter = list.iter();
+    while let Some(param) = iter.next() {
+        let (name, default) = match param {
+            Object::Symbol(s) if matches!(s.as_str(), "&optional" | "#!optional") => {
+                kind = ParamKind::Optional;
+                continue;
+            }
+            Object::Symbol(s) if matches!(s.as_str(), "&key" | "#!key") => {
+                kind = ParamKind::Key;
+                continue;
+            }
+            Object::Symbol(s) if matches!(s.as_str(), "." | "&rest" | "#!rest") => {
+                match (iter.next(), iter.next()) {
+                    (Some(Object::Symbol(rest)), None) => params.rest = Some(*rest),
+                    _ => return Err(format!("Invalid rest parameter after {}", s).into()),
+                }
+                continue;
+            }
+            Object::Symbol(s) => (*s, Object::Void),
+            Object::List(pair) => match pair.as_slice() {
+                [Object::Symbol(s), default] => (*s, default.clone()),
+                _ => return Err(format!("Invalid lambda parameter: {}", param).into()),
+            },
+            _ => return Err(format!("Invalid lambda parameter: {}", param).into()),
+        };
+        match kind {
+            ParamKind::Required => match param {
+                Object::Symbol(_) => params.required.push(name),
+                _ => return Err(format!("Invalid lambda parameter: {}", param).into()),
+            },
+            ParamKind::Optional => params.optional.push((name, default)),
+            ParamKind::Key => params.keys.push((name, default)),
+        }
+    }
+    Ok(params)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::eval;
+
+    #[test]
+    fn test_closures_capture_free_variables() {
+        let env = Rc::new(RefCell::new(Env::new()));
+        let program = crate::parser::parse("(lambda (a b) (lambda (c) (+ a (* c d))))").unwrap();
+        let outer = match resolve(&program, &env) {
+            Expr::Lambda(lambda) => lambda,
+            expr => panic!("expected a lambda, found {:?}", expr),
+        };
+        assert!(outer.captures.is_empty());
+        let inner = match &*outer.clauses[0].body.last {
+            Expr::Lambda(lambda) => lambda.clone(),
+            expr => panic!("expected a lambda, found {:?}", expr),
+        };
+        // Only `a`, slot 0 of the outer call. `d` is a global.
+        assert_eq!(inner.captures, vec![(0, 0)]);
+
+        let mut env = env;
+        let closure = eval("((lambda (a b) (lambda (c) (+ a c))) 1 2)", &mut env).unwrap();
+        match closure {
+            Object::Lambda(closure) => assert_eq!(closure.captured.len(), 1),
+            _ => panic!("expected a closure, found {}", closure),
+        }
+    }
+
+    #[test]
+    fn test_scopes() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let program = "(
+            (define x 1)
+            (define f (lambda (n)
+              (define even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+              (define odd? (lambda (n) (if (= n 0) false (even? (- n 1)))))
+              (define x 10)
+              (list (even? n) x)))
+            (f 4)
+            x
+            (define g (lambda () (h)))
+            (define h (lambda () 7))
+            (g)
+            (let ((y 2)) (define z (* y 3)) (+ y z))
+            (((lambda (a) (lambda (b) (lambda (c) (+ a (+ b c))))) 1) 2)
+            ((((lambda (a) (lambda (b) (lambda (c) (+ a (+ b c))))) 1) 2) 3)
+            (define make (lambda () (define get (lambda () v)) (define v 5) get))
+            ((make))
+        )";
+        let result = eval(program, &mut env).unwrap();
+        assert_eq!(
+            result.to_string(),
+            "((true 10) 1 7 8 Lambda(c) (+ a (+ b c)) 6 5)"
+        );
+    }
+
+    #[test]
+    fn test_errors_are_reported_when_evaluated() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let result = eval("(if true 1 (define))", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(1));
+        let err = eval("(if false 1 (define))", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "Invalid number of arguments for define");
+
+        let err = eval("((lambda () (define a b) (define b 1) a))", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "Unbound symbol: b");
+        let err = eval("(undefined-function 1)", &mut env).unwrap_err();
+        assert_eq!(err.to_string(), "Unbound function: undefined-function");
+        assert_eq!(env.borrow().get("undefined-function"), None);
+    }
+}
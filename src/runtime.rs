@@ -0,0 +1,487 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::mem::size_of;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::env;
+use crate::eval::EvalError;
+use crate::gc::{self, GcStats, Heap};
+use crate::hash_table::HashTable;
+use crate::lazy::Promise;
+use crate::object::Object;
+use crate::resolve::Closure;
+use crate::stack;
+use crate::symbol::Symbol;
+use crate::sync::{Cell, Rc, RefCell, Weak};
+
+/// Maximum number of nested `eval_expr` calls. Tail calls do not count towards
+/// this, only evaluation that has to come back to its caller.
+///
+/// Evaluation also stops when the native stack is about to run out, see
+/// `stack`, so a deeper limit is safe on any thread. This one only bounds
+/// runaway recursion.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+/// Accounted bytes up to which values are not checked for being dropped,
+/// see `Runtime::hold`.
+const MIN_RELEASE_THRESHOLD: usize = 1 << 20;
+
+/// Bounds on a single evaluation, see `eval::eval_with_limits`.
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    /// Number of evaluation steps allowed. Every iteration of the evaluator
+    /// loop, including each tail call, takes one step.
+    pub fuel: Option<u64>,
+    /// Wall-clock time after which evaluation stops.
+    pub deadline: Option<Instant>,
+    /// Evaluation stops as soon as another thread sets this flag.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// Approximate number of bytes the values and environments alive at
+    /// any one time may take on top of those alive when the limits were
+    /// installed, see `Runtime::memory_usage`.
+    pub max_memory: Option<usize>,
+}
+
+/// How `eval::eval` and `eval::apply` run code, see `Runtime::set_engine`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Engine {
+    /// Walks the resolved code, see `eval::eval_expr`.
+    #[default]
+    TreeWalker,
+    /// Compiles procedure bodies to bytecode and runs them on a stack
+    /// machine, see `vm`.
+    Bytecode,
+}
+
+/// State of the evaluation running on a thread. It is kept out of the
+/// `Runtime`, which with the `sync` feature is shared by evaluations on
+/// other threads, so that each one counts its own depth and steps and is
+/// bound only by its own limits.
+#[derive(Default)]
+struct Evaluation {
+    depth: usize,
+    fuel: Option<u64>,
+    limits: Limits,
+    /// Accounted bytes when the current limits were installed.
+    memory_baseline: usize,
+}
+
+thread_local! {
+    static EVALUATION: std::cell::RefCell<Evaluation> = std::cell::RefCell::default();
+}
+
+/// Approximate heap size of a list with `len` elements.
+pub fn list_size(len: usize) -> usize {
+    len * size_of::<Object>()
+}
+
+/// Approximate heap size of one variable: its cell and, for globals, the
+/// interned name it is stored under.
+pub fn var_size() -> usize {
+    size_of::<Symbol>() + size_of::<env::Cell>() + size_of::<RefCell<Option<Object>>>()
+}
+
+/// A value whose bytes are accounted, referred to without keeping it alive.
+enum Held {
+    List(Weak<Vec<Object>>),
+    String(Weak<str>),
+    Vector(Weak<RefCell<Vec<Object>>>),
+    HashTable(Weak<RefCell<HashTable>>),
+}
+
+impl Held {
+    /// The address of the value and a weak reference to it, for the values
+    /// that are shared through an `Rc`.
+    fn new(value: &Object) -> Option<(usize, Held)> {
+        let held = match value {
+            Object::List(list) | Object::ListData(list) => (
+                Rc::as_ptr(list) as *const () as usize,
+                Held::List(Rc::downgrade(list)),
+            ),
+            Object::String(s) => (
+                Rc::as_ptr(s) as *const u8 as usize,
+                Held::String(Rc::downgrade(s)),
+            ),
+            Object::Vector(vector) => (
+                Rc::as_ptr(vector) as *const () as usize,
+                Held::Vector(Rc::downgrade(vector)),
+            ),
+            Object::HashTable(table) => (
+                Rc::as_ptr(table) as *const () as usize,
+                Held::HashTable(Rc::downgrade(table)),
+            ),
+            _ => return None,
+        };
+        Some(held)
+    }
+
+    fn is_alive(&self) -> bool {
+        match self {
+            Held::List(list) => list.strong_count() > 0,
+            Held::String(s) => s.strong_count() > 0,
+            Held::Vector(vector) => vector.strong_count() > 0,
+            Held::HashTable(table) => table.strong_count() > 0,
+        }
+    }
+}
+
+/// The values bytes were charged for, by address, with the bytes each one
+/// holds.
+struct Allocations {
+    held: HashMap<usize, (Held, usize)>,
+    /// Accounted bytes at which the values are next checked for being
+    /// dropped.
+    threshold: usize,
+}
+
+impl Default for Allocations {
+    fn default() -> Self {
+        Allocations {
+            held: HashMap::new(),
+            threshold: MIN_RELEASE_THRESHOLD,
+        }
+    }
+}
+
+impl fmt::Debug for Allocations {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Allocations({} held)", self.held.len())
+    }
+}
+
+impl Allocations {
+    /// Returns the bytes to release right away, those of a value that
+    /// cannot be held. The weak references keep the address of a dropped
+    /// value from being reused before its bytes are released.
+    ///
+    /// Persistent collections share nodes between versions, so no single
+    /// version owns the bytes charged for it. They stay accounted until
+    /// `Runtime::reset_memory`.
+    fn hold(&mut self, value: &Object, bytes: usize) -> usize {
+        if let Object::PersistentVector(_) | Object::PersistentMap(_) = value {
+            return 0;
+        }
+        match Held::new(value) {
+            Some((addr, held)) => {
+                self.held.entry(addr).or_insert((held, 0)).1 += bytes;
+                0
+            }
+            None => bytes,
+        }
+    }
+
+    /// Returns the bytes actually taken off `value`.
+    fn shrink(&mut self, value: &Object, bytes: usize) -> usize {
+        let addr = match Held::new(value) {
+            Some((addr, _)) => addr,
+            None => return 0,
+        };
+        match self.held.get_mut(&addr) {
+            Some((_, held)) => {
+                let taken = bytes.min(*held);
+                *held -= taken;
+                taken
+            }
+            None => 0,
+        }
+    }
+
+    /// Forgets the values that were dropped and returns their bytes.
+    fn release_dropped(&mut self) -> usize {
+        let mut released = 0;
+        self.held.retain(|_, (held, bytes)| {
+            let alive = held.is_alive();
+            if !alive {
+                released += *bytes;
+            }
+            alive
+        });
+        released
+    }
+}
+
+/// State shared by an environment and the frames of the code it runs. The
+/// depth, fuel and limits of the evaluation in progress belong to the
+/// thread running it instead.
+#[derive(Debug)]
+pub struct Runtime {
+    max_depth: Cell<usize>,
+    memory: Cell<usize>,
+    peak_memory: Cell<usize>,
+    allocations: RefCell<Allocations>,
+    engine: Cell<Engine>,
+    heap: RefCell<Heap>,
+    frames: Cell<usize>,
+}
+
+impl Default for Runtime {
+    fn default() -> Self {
+        Runtime {
+            max_depth: Cell::new(DEFAULT_MAX_DEPTH),
+            memory: Cell::new(0),
+            peak_memory: Cell::new(0),
+            allocations: RefCell::new(Allocations::default()),
+            engine: Cell::new(Engine::default()),
+            heap: RefCell::new(Heap::default()),
+            frames: Cell::new(0),
+        }
+    }
+}
+
+impl PartialEq for Runtime {
+    fn eq(&self, other: &Self) -> bool {
+        std::ptr::eq(self, other)
+    }
+}
+
+impl Runtime {
+    pub fn max_depth(&self) -> usize {
+        self.max_depth.get()
+    }
+
+    pub fn set_max_depth(&self, max_depth: usize) {
+        self.max_depth.set(max_depth);
+    }
+
+    pub fn engine(&self) -> Engine {
+        self.engine.get()
+    }
+
+    /// Selects the engine for code evaluated from now on. Procedures
+    /// created under one engine can be called under the other.
+    pub fn set_engine(&self, engine: Engine) {
+        self.engine.set(engine);
+    }
+
+    /// Installs new limits for the evaluations on this thread and returns
+    /// the ones they replace. Memory that is accounted already does not
+    /// count towards `Limits::max_memory`.
+    pub fn set_limits(&self, limits: Limits) -> Limits {
+        let memory_baseline = self.memory_usage();
+        EVALUATION.with_borrow_mut(|evaluation| {
+            evaluation.fuel = limits.fuel;
+            evaluation.memory_baseline = memory_baseline;
+            std::mem::replace(&mut evaluation.limits, limits)
+        })
+    }
+
+    /// Bytes currently accounted to values and environments.
+    ///
+    /// Environments and frames give their bytes back when they are dropped.
+    /// Values may be shared freely, so the runtime only notices they were
+    /// dropped when it looks for them, which it does here, whenever the
+    /// accounted bytes have doubled and before failing `Limits::max_memory`.
+    pub fn memory_usage(&self) -> usize {
+        self.release_dropped();
+        self.memory.get()
+    }
+
+    /// Highest accounted bytes since the last `reset_peak_memory` or
+    /// `reset_memory`. This can include values that were dropped before the
+    /// runtime noticed.
+    pub fn peak_memory(&self) -> usize {
+        self.peak_memory.get()
+    }
+
+    /// Lowers `peak_memory` to the bytes accounted now.
+    pub fn reset_peak_memory(&self) {
+        self.peak_memory.set(self.memory_usage());
+    }
+
+    /// Forgets all accounted bytes, e.g. before reusing a runtime for
+    /// unrelated code.
+    pub fn reset_memory(&self) {
+        self.memory.set(0);
+        EVALUATION.with_borrow_mut(|evaluation| evaluation.memory_baseline = 0);
+        self.peak_memory.set(0);
+        *self.allocations.borrow_mut() = Allocations::default();
+    }
+
+    /// Accounts for `bytes` about to be allocated, failing without
+    /// accounting them if that would go over `Limits::max_memory`. The
+    /// caller gives them back with `release`, or ties them to the value
+    /// they were allocated for with `hold`.
+    pub fn charge(&self, bytes: usize) -> Result<(), EvalError> {
+        let memory = self.memory.get().saturating_add(bytes);
+        if memory > self.allocations.borrow().threshold {
+            self.release_dropped();
+        }
+        if let Some(max_memory) = self.exceeded(bytes) {
+            return Err(EvalError::OutOfMemory(max_memory));
+        }
+        self.account(bytes);
+        Ok(())
+    }
+
+    /// Accounts for `bytes` even over `Limits::max_memory`, for allocations
+    /// that cannot fail, such as environments and frames, which `release`
+    /// their bytes when dropped. Going over the limit is reported by the
+    /// next evaluation step.
+    pub(crate) fn account(&self, bytes: usize) {
+        self.memory.update(|memory| memory.saturating_add(bytes));
+        let memory = self.memory.get();
+        self.peak_memory.update(|peak| peak.max(memory));
+    }
+
+    /// Ties `bytes` charged for `value` to it, so that they are released
+    /// once the value is dropped.
+    pub fn hold(&self, value: &Object, bytes: usize) {
+        let released = self.allocations.borrow_mut().hold(value, bytes);
+        self.release(released);
+    }
+
+    /// Charges `bytes` for a value that was just built and holds them.
+    pub fn allocate(&self, value: Object, bytes: usize) -> Result<Object, EvalError> {
+        self.charge(bytes)?;
+        self.hold(&value, bytes);
+        Ok(value)
+    }
+
+    /// Gives back `bytes` held by `value`, e.g. for an entry removed from a
+    /// hash table.
+    pub fn shrink(&self, value: &Object, bytes: usize) {
+        let released = self.allocations.borrow_mut().shrink(value, bytes);
+        self.release(released);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.memory.update(|memory| memory.saturating_sub(bytes));
+    }
+
+    fn release_dropped(&self) {
+        let mut allocations = self.allocations.borrow_mut();
+        let released = allocations.release_dropped();
+        self.release(released);
+        allocations.threshold = MIN_RELEASE_THRESHOLD.max(self.memory.get().saturating_mul(2));
+    }
+
+    /// The memory limit, if `bytes` more would go over it even once the
+    /// values that were dropped are released.
+    fn exceeded(&self, bytes: usize) -> Option<usize> {
+        let (max_memory, memory_baseline) = EVALUATION.with_borrow(|evaluation| {
+            Some((evaluation.limits.max_memory?, evaluation.memory_baseline))
+        })?;
+        let over = || {
+            let used = self.memory.get().saturating_sub(memory_baseline);
+            used.saturating_add(bytes) > max_memory
+        };
+        if !over() {
+            return None;
+        }
+        self.release_dropped();
+        over().then_some(max_memory)
+    }
+
+    fn check_memory(&self) -> Result<(), EvalError> {
+        match self.exceeded(0) {
+            Some(max_memory) => Err(EvalError::OutOfMemory(max_memory)),
+            None => Ok(()),
+        }
+    }
+
+    /// Frees the closures, frames and containers that only refer to each
+    /// other, see `gc`. Returns the number of objects found to be garbage.
+    ///
+    /// Also runs on its own whenever the number of objects the collector
+    /// watches has doubled since the last collection, except with the
+    /// `sync` feature.
+    pub fn collect_garbage(&self) -> usize {
+        let roots = self.heap.borrow_mut().start_collection();
+        let freed = gc::collect(roots);
+        self.heap.borrow_mut().end_collection(freed);
+        freed
+    }
+
+    pub fn gc_stats(&self) -> GcStats {
+        self.heap.borrow().stats(self.frames.get())
+    }
+
+    pub(crate) fn watch_closure(&self, closure: &Rc<Closure>) {
+        if self.heap.borrow_mut().watch_closure(closure) {
+            self.collect_garbage();
+        }
+    }
+
+    pub(crate) fn watch_promise(&self, promise: &Promise) {
+        if self.heap.borrow_mut().watch_promise(promise) {
+            self.collect_garbage();
+        }
+    }
+
+    /// Called when `value` is stored in the vector or hash table
+    /// `container`.
+    pub(crate) fn watch_container(&self, container: &Object, value: &Object) {
+        if self.heap.borrow_mut().watch_container(container, value) {
+            self.collect_garbage();
+        }
+    }
+
+    pub(crate) fn frame_created(&self) {
+        self.frames.update(|frames| frames + 1);
+    }
+
+    pub(crate) fn frame_dropped(&self) {
+        self.frames.update(|frames| frames - 1);
+    }
+
+    /// The current level of nested evaluation on this thread.
+    pub fn depth(&self) -> usize {
+        EVALUATION.with_borrow(|evaluation| evaluation.depth)
+    }
+
+    /// Used by generators to take the frames of a suspended body out of the
+    /// count and to put them back when it is resumed.
+    pub(crate) fn set_depth(&self, depth: usize) {
+        EVALUATION.with_borrow_mut(|evaluation| evaluation.depth = depth);
+    }
+
+    /// Records one more level of nested evaluation. The level is released
+    /// when the returned guard is dropped. Fails at `max_depth`, or earlier
+    /// if the native stack is about to run out.
+    pub fn enter(&self) -> Result<DepthGuard<'_>, EvalError> {
+        let max_depth = self.max_depth();
+        EVALUATION.with_borrow_mut(|evaluation| {
+            if evaluation.depth >= max_depth || stack::exhausted() {
+                return Err(EvalError::StackOverflow(evaluation.depth));
+            }
+            evaluation.depth += 1;
+            Ok(DepthGuard { runtime: self })
+        })
+    }
+
+    /// Accounts for one evaluation step against the current limits.
+    pub fn tick(&self) -> Result<(), EvalError> {
+        EVALUATION.with_borrow_mut(|evaluation| {
+            if let Some(fuel) = evaluation.fuel {
+                if fuel == 0 {
+                    return Err(EvalError::OutOfFuel);
+                }
+                evaluation.fuel = Some(fuel - 1);
+            }
+            if let Some(cancel) = &evaluation.limits.cancel {
+                if cancel.load(Ordering::Relaxed) {
+                    return Err(EvalError::Cancelled);
+                }
+            }
+            if let Some(deadline) = evaluation.limits.deadline {
+                if Instant::now() >= deadline {
+                    return Err(EvalError::Timeout);
+                }
+            }
+            Ok(())
+        })?;
+        self.check_memory()
+    }
+}
+
+pub struct DepthGuard<'a> {
+    runtime: &'a Runtime,
+}
+
+impl Drop for DepthGuard<'_> {
+    fn drop(&mut self) {
+        self.runtime.set_depth(self.runtime.depth() - 1);
+    }
+}
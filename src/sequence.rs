@@ -0,0 +1,110 @@
+//! Uniform iteration over the collection types and generators, used by
+//! `map`, `filter`, `reduce` and `for-each`.
+
+use crate::eval::EvalError;
+use crate::hash_table::HashTable;
+use crate::object::Object;
+use crate::persistent::{PersistentMap, PersistentVector};
+use crate::sync::{Rc, RefCell};
+
+/// A collection whose elements can be visited in order.
+pub trait Sequence {
+    /// The elements in order. Maps yield their entries as `(key value)`
+    /// lists and strings yield characters.
+    ///
+    /// Mutable collections are copied first, so a procedure called during
+    /// the iteration may modify them without affecting it. Only generators
+    /// can fail, since they run code to produce their elements.
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_>;
+}
+
+fn entry(key: Object, value: &Object) -> Object {
+    Object::ListData(Rc::new(vec![key, value.clone()]))
+}
+
+impl Sequence for Vec<Object> {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        Box::new(self.iter().cloned().map(Ok))
+    }
+}
+
+impl Sequence for Rc<str> {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        Box::new(self.chars().map(|c| Ok(Object::Char(c))))
+    }
+}
+
+impl Sequence for RefCell<Vec<Object>> {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        Box::new(self.borrow().clone().into_iter().map(Ok))
+    }
+}
+
+impl Sequence for RefCell<HashTable> {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        let entries = self
+            .borrow()
+            .iter()
+            .map(|(key, value)| entry(key.to_object(), value))
+            .collect::<Vec<_>>();
+        Box::new(entries.into_iter().map(Ok))
+    }
+}
+
+impl Sequence for PersistentVector {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        Box::new(self.iter().cloned().map(Ok))
+    }
+}
+
+impl Sequence for PersistentMap {
+    fn elements(&self) -> Box<dyn Iterator<Item = Result<Object, EvalError>> + '_> {
+        Box::new(
+            self.iter()
+                .map(|(key, value)| Ok(entry(key.to_object(), value))),
+        )
+    }
+}
+
+/// The object as a sequence, if it is a collection.
+pub fn as_sequence(obj: &Object) -> Option<&dyn Sequence> {
+    match obj {
+        Object::ListData(list) => Some(list.as_ref()),
+        Object::String(s) => Some(s),
+        Object::Vector(vector) => Some(vector.as_ref()),
+        Object::HashTable(table) => Some(table.as_ref()),
+        Object::PersistentVector(vector) => Some(vector),
+        Object::PersistentMap(map) => Some(map),
+        Object::Generator(generator) => Some(generator.as_ref()),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sync::Rc;
+
+    #[test]
+    fn test_elements() {
+        let string = Object::String("héj".into());
+        let chars = as_sequence(&string)
+            .unwrap()
+            .elements()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            chars,
+            vec![Object::Char('h'), Object::Char('é'), Object::Char('j')]
+        );
+
+        let vector = Rc::new(RefCell::new(vec![Object::Integer(1)]));
+        let obj = Object::Vector(vector.clone());
+        let mut elements = as_sequence(&obj).unwrap().elements();
+        vector.borrow_mut().push(Object::Integer(2));
+        assert_eq!(elements.next(), Some(Ok(Object::Integer(1))));
+        assert_eq!(elements.next(), None);
+
+        assert!(as_sequence(&Object::Integer(1)).is_none());
+    }
+}
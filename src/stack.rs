@@ -0,0 +1,51 @@
+//! How much of the native stack evaluation has left.
+//!
+//! Counting the evaluation depth alone does not keep the Rust stack from
+//! overflowing: how much stack a level takes depends on the build, on the
+//! builtins in between and on the thread the host evaluates on. So
+//! `Runtime::enter` also checks the stack that is really left, and fails
+//! with `EvalError::StackOverflow` well before it runs out.
+
+use std::cell::Cell;
+
+/// Stack kept free below the deepest evaluation. It has to hold whatever
+/// runs between two checks, e.g. a builtin calling back into a procedure,
+/// and the unwinding of an error.
+pub const RED_ZONE: usize = 128 * 1024;
+
+thread_local! {
+    /// Lowest address of the stack evaluation is running on, when that is
+    /// not the stack of the thread, e.g. in the body of a generator.
+    static LIMIT: Cell<Option<usize>> = const { Cell::new(None) };
+}
+
+/// Bytes of native stack left, if they can be told on this platform.
+pub fn remaining() -> Option<usize> {
+    match LIMIT.with(Cell::get) {
+        Some(limit) => {
+            let marker = 0u8;
+            Some((&marker as *const u8 as usize).saturating_sub(limit))
+        }
+        None => stacker::remaining_stack(),
+    }
+}
+
+/// Whether evaluation has to stop before it overflows the native stack.
+pub fn exhausted() -> bool {
+    remaining().is_some_and(|remaining| remaining < RED_ZONE)
+}
+
+/// Runs `f`, which switches to a stack whose lowest address is `limit`.
+#[cfg(not(feature = "sync"))]
+pub(crate) fn with_limit<T>(limit: usize, f: impl FnOnce() -> T) -> T {
+    struct Restore(Option<usize>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            LIMIT.with(|cell| cell.set(self.0));
+        }
+    }
+
+    let _restore = Restore(LIMIT.with(|cell| cell.replace(Some(limit))));
+    f()
+}
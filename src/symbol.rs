@@ -0,0 +1,137 @@
+//! Interned symbol names.
+//!
+//! Every name is stored once in a global table and symbols refer to it by
+//! index, so comparing, hashing and copying a symbol is as cheap as for an
+//! integer. Interned names are never freed.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::{BuildHasherDefault, Hasher};
+use std::sync::{LazyLock, RwLock};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Symbol(u32);
+
+struct Table {
+    ids: HashMap<&'static str, Symbol>,
+    names: Vec<&'static str>,
+}
+
+/// Names interned before any other, so that their symbols are constants.
+const PREDEFINED: &[&str] = &["true", "false", "nil", "else"];
+
+pub const TRUE: Symbol = Symbol(0);
+pub const FALSE: Symbol = Symbol(1);
+pub const NIL: Symbol = Symbol(2);
+pub const ELSE: Symbol = Symbol(3);
+
+static TABLE: LazyLock<RwLock<Table>> = LazyLock::new(|| {
+    let names = PREDEFINED.to_vec();
+    let ids = names
+        .iter()
+        .enumerate()
+        .map(|(i, name)| (*name, Symbol(i as u32)))
+        .collect();
+    RwLock::new(Table { ids, names })
+});
+
+impl Symbol {
+    /// The symbol for `name`, adding it to the table the first time.
+    pub fn intern(name: &str) -> Symbol {
+        if let Some(symbol) = TABLE.read().unwrap().ids.get(name) {
+            return *symbol;
+        }
+        let mut table = TABLE.write().unwrap();
+        // Another thread may have added it in the meantime.
+        if let Some(symbol) = table.ids.get(name) {
+            return *symbol;
+        }
+        let symbol = Symbol(table.names.len() as u32);
+        let name: &'static str = Box::leak(name.into());
+        table.names.push(name);
+        table.ids.insert(name, symbol);
+        symbol
+    }
+
+    pub fn as_str(self) -> &'static str {
+        TABLE.read().unwrap().names[self.0 as usize]
+    }
+}
+
+impl From<&str> for Symbol {
+    fn from(name: &str) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl From<&Symbol> for Symbol {
+    fn from(symbol: &Symbol) -> Self {
+        *symbol
+    }
+}
+
+impl From<&String> for Symbol {
+    fn from(name: &String) -> Self {
+        Symbol::intern(name)
+    }
+}
+
+impl fmt::Display for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for Symbol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:?}", self.as_str())
+    }
+}
+
+/// Hashes a symbol by its index alone. The indices are small and dense, so
+/// spreading them with a multiplication is enough.
+#[derive(Default)]
+pub struct SymbolHasher(u64);
+
+impl Hasher for SymbolHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 << 8 | u64::from(*byte)).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+        }
+    }
+
+    fn write_u32(&mut self, n: u32) {
+        self.0 = u64::from(n).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+    }
+}
+
+/// A map keyed by symbols, e.g. the variables of an environment.
+pub type SymbolMap<V> = HashMap<Symbol, V, BuildHasherDefault<SymbolHasher>>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_intern() {
+        let a = Symbol::intern("intern-test-a");
+        let b = Symbol::intern("intern-test-b");
+        assert_ne!(a, b);
+        assert_eq!(Symbol::intern("intern-test-a"), a);
+        assert_eq!(Symbol::from(&"intern-test-b".to_string()), b);
+        assert_eq!(a.as_str(), "intern-test-a");
+        assert_eq!(format!("{} {:?}", b, b), "intern-test-b \"intern-test-b\"");
+
+        let mut map = SymbolMap::default();
+        map.insert(a, 1);
+        map.insert(b, 2);
+        assert_eq!(map.get(&a), Some(&1));
+
+        assert_eq!(Symbol::intern("nil"), NIL);
+        assert_eq!(ELSE.as_str(), "else");
+    }
+}
@@ -0,0 +1,287 @@
+//! The shared ownership types the interpreter is built on.
+//!
+//! By default these are the `Rc` and `RefCell` family from the standard
+//! library, and an interpreter stays on the thread that created it. With
+//! the `sync` feature they are `Arc`, locks and `OnceLock` instead, so
+//! `Env`, `Object` and everything they refer to are `Send` and `Sync`: an
+//! environment can be moved to another thread and values, procedures
+//! included, can be shared between interpreters on different threads.
+//!
+//! The locks keep the `RefCell` interface, so the rest of the interpreter is
+//! written once against these names. Locks are not reentrant, which is fine
+//! since the interpreter never holds a borrow across a call that could
+//! borrow the same cell again, the same rule `RefCell` already enforces.
+
+#[cfg(not(feature = "sync"))]
+pub use std::cell::{Cell, OnceCell, RefCell};
+#[cfg(not(feature = "sync"))]
+pub use std::rc::{Rc, Weak};
+
+#[cfg(feature = "sync")]
+pub use self::lock::{Cell, RefCell};
+#[cfg(feature = "sync")]
+pub use std::sync::{Arc as Rc, OnceLock as OnceCell, Weak};
+
+#[cfg(feature = "sync")]
+mod lock {
+    use std::fmt;
+    use std::sync::{Mutex, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard, TryLockError};
+
+    /// A `RefCell` backed by a read-write lock.
+    ///
+    /// A panic while a borrow is held does not make the value unusable, as
+    /// with `RefCell`.
+    #[derive(Default)]
+    pub struct RefCell<T>(RwLock<T>);
+
+    /// The value is borrowed, mutably or by another thread.
+    #[derive(Debug)]
+    pub struct BorrowError;
+
+    impl<T> RefCell<T> {
+        pub const fn new(value: T) -> Self {
+            RefCell(RwLock::new(value))
+        }
+
+        pub fn borrow(&self) -> RwLockReadGuard<'_, T> {
+            self.0.read().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn borrow_mut(&self) -> RwLockWriteGuard<'_, T> {
+            self.0.write().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn try_borrow(&self) -> Result<RwLockReadGuard<'_, T>, BorrowError> {
+            match self.0.try_read() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+
+        pub fn try_borrow_mut(&self) -> Result<RwLockWriteGuard<'_, T>, BorrowError> {
+            match self.0.try_write() {
+                Ok(guard) => Ok(guard),
+                Err(TryLockError::Poisoned(err)) => Ok(err.into_inner()),
+                Err(TryLockError::WouldBlock) => Err(BorrowError),
+            }
+        }
+
+        pub fn replace(&self, value: T) -> T {
+            std::mem::replace(&mut *self.borrow_mut(), value)
+        }
+
+        pub fn get_mut(&mut self) -> &mut T {
+            self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn into_inner(self) -> T {
+            self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+        }
+    }
+
+    impl<T: Default> RefCell<T> {
+        pub fn take(&self) -> T {
+            self.replace(T::default())
+        }
+    }
+
+    impl<T: PartialEq> PartialEq for RefCell<T> {
+        fn eq(&self, other: &Self) -> bool {
+            std::ptr::eq(self, other) || *self.borrow() == *other.borrow()
+        }
+    }
+
+    impl<T: fmt::Debug> fmt::Debug for RefCell<T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.try_borrow() {
+                Ok(value) => f.debug_tuple("RefCell").field(&*value).finish(),
+                Err(_) => write!(f, "RefCell(<borrowed>)"),
+            }
+        }
+    }
+
+    /// A `Cell` backed by a mutex, for the counters of a runtime. Counters
+    /// that several threads can change, e.g. the number of frames when a
+    /// frame is dropped by another interpreter, are changed with `update`.
+    #[derive(Default)]
+    pub struct Cell<T>(Mutex<T>);
+
+    impl<T: Copy> Cell<T> {
+        pub const fn new(value: T) -> Self {
+            Cell(Mutex::new(value))
+        }
+
+        pub fn get(&self) -> T {
+            *self.0.lock().unwrap_or_else(PoisonError::into_inner)
+        }
+
+        pub fn set(&self, value: T) {
+            *self.0.lock().unwrap_or_else(PoisonError::into_inner) = value;
+        }
+
+        /// Replaces the value with `f` of it, without another thread
+        /// getting in between.
+        pub fn update(&self, f: impl FnOnce(T) -> T) {
+            let mut value = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+            *value = f(*value);
+        }
+    }
+
+    impl<T: Copy + fmt::Debug> fmt::Debug for Cell<T> {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_tuple("Cell").field(&self.get()).finish()
+        }
+    }
+}
+
+#[cfg(all(test, feature = "sync"))]
+mod tests {
+    use super::{Rc, RefCell};
+    use crate::env::Env;
+    use crate::eval::{apply, eval, eval_with_limits, EvalError};
+    use crate::object::Object;
+    use crate::runtime::{Engine, Limits};
+    use std::thread;
+    use std::time::Duration;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_interpreter_is_send_and_sync() {
+        assert_send_sync::<Object>();
+        assert_send_sync::<Rc<RefCell<Env>>>();
+    }
+
+    #[test]
+    fn test_moves_interpreter_to_another_thread() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        eval("(define square (lambda (x) (* x x)))", &mut env).unwrap();
+        let result = thread::spawn(move || eval("(square 12)", &mut env).unwrap())
+            .join()
+            .unwrap();
+        assert_eq!(result, Object::Integer(144));
+    }
+
+    #[test]
+    fn test_runs_interpreters_in_parallel() {
+        let threads = [Engine::TreeWalker, Engine::Bytecode]
+            .into_iter()
+            .flat_map(|engine| (15..18).map(move |n| (engine, n)))
+            .map(|(engine, n)| {
+                thread::spawn(move || {
+                    let mut env = Rc::new(RefCell::new(Env::new()));
+                    env.borrow().runtime().set_engine(engine);
+                    eval(
+                        "(define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2))))))",
+                        &mut env,
+                    )
+                    .unwrap();
+                    eval(&format!("(fib {})", n), &mut env).unwrap().to_string()
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results, ["610", "987", "1597", "610", "987", "1597"]);
+    }
+
+    #[test]
+    fn test_shares_globals_between_threads() {
+        let env = Rc::new(RefCell::new(Env::new()));
+        eval(
+            "(
+                (define square (lambda (x) (* x x)))
+                (define sum-squares (lambda (n) (if (= n 0) 0 (+ (square n) (sum-squares (- n 1))))))
+            )",
+            &mut env.clone(),
+        )
+        .unwrap();
+        let threads = (1..=4)
+            .map(|i| {
+                let mut env = env.clone();
+                thread::spawn(move || eval(&format!("(sum-squares {})", i * 10), &mut env).unwrap())
+            })
+            .collect::<Vec<_>>();
+        let results = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            results,
+            [385, 2870, 9455, 22140].map(Object::Integer).to_vec()
+        );
+        assert_eq!(env.borrow().runtime().depth(), 0);
+    }
+
+    #[test]
+    fn test_limits_apply_to_their_own_thread() {
+        let env = Rc::new(RefCell::new(Env::new()));
+        eval(
+            "(define count (lambda (n) (if (= n 0) 0 (count (- n 1)))))",
+            &mut env.clone(),
+        )
+        .unwrap();
+        // The short evaluation starts while the long one is running.
+        let runs = [(Some(1_000), 50_000, 50), (Some(10_000_000), 500_000, 0)];
+        let threads = runs
+            .into_iter()
+            .map(|(fuel, n, delay)| {
+                let mut env = env.clone();
+                thread::spawn(move || {
+                    thread::sleep(Duration::from_millis(delay));
+                    let limits = Limits {
+                        fuel,
+                        ..Default::default()
+                    };
+                    eval_with_limits(&format!("(count {})", n), &mut env, limits)
+                })
+            })
+            .collect::<Vec<_>>();
+        let results = threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(results, [Err(EvalError::OutOfFuel), Ok(Object::Integer(0))]);
+        assert_eq!(
+            eval("(count 50000)", &mut env.clone()),
+            Ok(Object::Integer(0))
+        );
+        assert_eq!(env.borrow().runtime().depth(), 0);
+    }
+
+    #[test]
+    fn test_shares_procedures_between_interpreters() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let bump = eval(
+            "(
+                (define hits (make-vector 4 0))
+                (lambda (i) (vector-set! hits i (+ 1 (vector-ref hits i))))
+            )",
+            &mut env,
+        )
+        .unwrap();
+        let bump = match bump {
+            Object::List(items) => items.last().unwrap().clone(),
+            bump => bump,
+        };
+        let threads = (0..4)
+            .map(|i| {
+                let bump = bump.clone();
+                thread::spawn(move || {
+                    let mut env = Rc::new(RefCell::new(Env::new()));
+                    for _ in 0..100 {
+                        apply(&bump, vec![Object::Integer(i)], &mut env).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        let result = eval("hits", &mut env).unwrap();
+        assert_eq!(result.to_string(), "#(100 100 100 100)");
+    }
+}
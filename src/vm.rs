@@ -0,0 +1,467 @@
+//! A stack machine running the bytecode of `bytecode`.
+//!
+//! Calls between compiled procedures do not recurse on the Rust stack: the
+//! machine keeps its own stack of suspended calls, and a tail call replaces
+//! the running one. Non-tail calls still count towards
+//! `Runtime::max_depth`, so programs overflow about where they do with
+//! `eval_expr`. Builtins, and procedures they call back, run nested as
+//! they do there.
+
+use std::mem;
+
+use crate::{
+    builtins::{hash, vector},
+    bytecode::{self, Chunk, Op},
+    env::{Env, Frame},
+    eval::{self, binary_op, bind_params, close, is_procedure, unbound, Entry, EvalError},
+    hash_table::HashTable,
+    lazy::{Promise, Stream},
+    object::Object,
+    resolve::Closure,
+    runtime::{list_size, Runtime},
+    symbol::Symbol,
+    sync::{Rc, RefCell},
+};
+
+/// A procedure call being run, or suspended while it calls another.
+struct Activation {
+    chunk: Rc<Chunk>,
+    pc: usize,
+    frame: Rc<Frame>,
+}
+
+/// Puts the evaluation depth back to where a run started, also when a
+/// suspended generator is unwound.
+struct RestoreDepth<'a> {
+    runtime: &'a Runtime,
+    depth: usize,
+}
+
+impl Drop for RestoreDepth<'_> {
+    fn drop(&mut self) {
+        self.runtime.set_depth(self.depth);
+    }
+}
+
+/// Runs `chunk` with `frame` holding the locals it refers to.
+pub(crate) fn run(
+    chunk: Rc<Chunk>,
+    frame: Rc<Frame>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    let runtime = env.borrow().runtime();
+    let _depth = runtime.enter()?;
+    runtime.tick()?;
+    let _restore = RestoreDepth {
+        runtime: &runtime,
+        depth: runtime.depth(),
+    };
+    let mut current = Activation {
+        chunk,
+        pc: 0,
+        frame,
+    };
+    let mut calls: Vec<Activation> = Vec::new();
+    let mut stack: Vec<Object> = Vec::new();
+    loop {
+        let op = current.chunk.code[current.pc];
+        current.pc += 1;
+        let returned = match op {
+            Op::Const(i) => {
+                stack.push(current.chunk.constants[i as usize].clone());
+                continue;
+            }
+            Op::Local { depth, index, name } => {
+                stack.push(local(&current.frame, depth, index, name, "Unbound symbol")?);
+                continue;
+            }
+            Op::Global(i) => {
+                let (cell, name) = &current.chunk.globals[i as usize];
+                let val = cell.borrow().clone();
+                stack.push(val.ok_or_else(|| unbound("Unbound symbol", name.as_str()))?);
+                continue;
+            }
+            Op::LocalFunction { depth, index, name } => {
+                let func = local(&current.frame, depth, index, name, "Unbound function")?;
+                stack.push(procedure(func, name)?);
+                continue;
+            }
+            Op::GlobalFunction(i) => {
+                let (cell, name) = &current.chunk.globals[i as usize];
+                let func = cell.borrow().clone();
+                let func = func.ok_or_else(|| unbound("Unbound function", name.as_str()))?;
+                stack.push(procedure(func, *name)?);
+                continue;
+            }
+            Op::DefineLocal { depth, index } => {
+                let val = pop(&mut stack);
+                *current
+                    .frame
+                    .cell(depth as usize, index as usize)
+                    .borrow_mut() = Some(val);
+                stack.push(Object::Void);
+                continue;
+            }
+            Op::DefineGlobal(i) => {
+                let val = pop(&mut stack);
+                let name = current.chunk.globals[i as usize].1;
+                env.borrow_mut().set(name, val);
+                stack.push(Object::Void);
+                continue;
+            }
+            Op::Pop => {
+                stack.pop();
+                continue;
+            }
+            Op::Jump(to) => {
+                current.pc = to as usize;
+                continue;
+            }
+            Op::Branch(to) => {
+                match pop(&mut stack) {
+                    Object::Bool(true) => {}
+                    Object::Bool(false) => current.pc = to as usize,
+                    _ => return Err("Condition must be a boolean".into()),
+                }
+                continue;
+            }
+            Op::Return => pop(&mut stack),
+            Op::Closure(i) => {
+                let lambda = &current.chunk.lambdas[i as usize];
+                stack.push(Object::Lambda(close(lambda, &current.frame, env)));
+                continue;
+            }
+            Op::CaseClosure(i) => {
+                let lambda = &current.chunk.lambdas[i as usize];
+                stack.push(Object::CaseLambda(close(lambda, &current.frame, env)));
+                continue;
+            }
+            Op::Call(argc) | Op::TailCall(argc) | Op::Combine(argc) | Op::TailCombine(argc) => {
+                let tail = matches!(op, Op::TailCall(_) | Op::TailCombine(_));
+                let args = stack.split_off(stack.len() - argc as usize);
+                let func = pop(&mut stack);
+                let val =
+                    if matches!(op, Op::Combine(_) | Op::TailCombine(_)) && !is_procedure(&func) {
+                        combine(func, args, &runtime)?
+                    } else {
+                        runtime.tick()?;
+                        match eval::enter(&func, args, env)? {
+                            Entry::Return(val) => val,
+                            Entry::Clause(closure, i, frame) => {
+                                let callee = Activation {
+                                    chunk: compiled(&closure, i),
+                                    pc: 0,
+                                    frame,
+                                };
+                                if tail {
+                                    current = callee;
+                                } else {
+                                    let depth = runtime.depth() + 1;
+                                    if depth > runtime.max_depth() {
+                                        return Err(EvalError::StackOverflow(runtime.depth()));
+                                    }
+                                    runtime.set_depth(depth);
+                                    calls.push(mem::replace(&mut current, callee));
+                                }
+                                continue;
+                            }
+                        }
+                    };
+                if !tail {
+                    stack.push(val);
+                    continue;
+                }
+                val
+            }
+            Op::BinaryOp(i) => {
+                let right = pop(&mut stack);
+                let left = pop(&mut stack);
+                let op = &current.chunk.operators[i as usize];
+                stack.push(binary_op(op, &left, &right, env)?);
+                continue;
+            }
+            Op::List(n) => {
+                let items = stack.split_off(stack.len() - n as usize);
+                let list = Object::ListData(items.into());
+                stack.push(runtime.allocate(list, list_size(n as usize))?);
+                continue;
+            }
+            Op::Vector(n) => {
+                let items = stack.split_off(stack.len() - n as usize);
+                stack.push(vector::new_vector(items, env)?);
+                continue;
+            }
+            Op::HashTable(i) => {
+                let keys = &current.chunk.tables[i as usize];
+                let values = stack.split_off(stack.len() - keys.len());
+                let mut table = HashTable::new();
+                for (key, value) in keys.iter().zip(values) {
+                    table.insert(key.clone(), value);
+                }
+                stack.push(hash::new_table(table, env)?);
+                continue;
+            }
+            Op::Delay(i) => {
+                let expr = current.chunk.exprs[i as usize].clone();
+                let promise = Promise::delay(expr, current.frame.clone());
+                stack.push(Object::Promise(promise));
+                continue;
+            }
+            Op::ConsStream(i) => {
+                let head = pop(&mut stack);
+                let expr = current.chunk.exprs[i as usize].clone();
+                let tail = Promise::delay(expr, current.frame.clone());
+                stack.push(Object::Stream(Rc::new(Stream { head, tail })));
+                continue;
+            }
+            Op::EnterLet { size, count } => {
+                let inits = stack.split_off(stack.len() - count as usize);
+                let frame = Frame::new(size as usize, Some(current.frame.clone()), runtime.clone());
+                for (i, init) in inits.into_iter().enumerate() {
+                    frame.set(i, init);
+                }
+                current.frame = frame;
+                continue;
+            }
+            Op::EnterLetValues(i) => {
+                let let_values = current.chunk.let_values[i as usize].clone();
+                let bound = stack.split_off(stack.len() - let_values.bindings.len());
+                let frame = Frame::new(
+                    let_values.size,
+                    Some(current.frame.clone()),
+                    runtime.clone(),
+                );
+                for ((formals, _), values) in let_values.bindings.iter().zip(bound) {
+                    bind_params(formals, values.into_values(), &frame, env)?;
+                }
+                current.frame = frame;
+                continue;
+            }
+            Op::LeaveFrame => {
+                let parent = current
+                    .frame
+                    .parent()
+                    .expect("left a frame without a parent");
+                current.frame = parent.clone();
+                continue;
+            }
+            Op::Fail(i) => return Err(current.chunk.errors[i as usize].clone()),
+        };
+        // The running call returned `returned`.
+        match calls.pop() {
+            Some(caller) => {
+                current = caller;
+                runtime.set_depth(runtime.depth() - 1);
+                stack.push(returned);
+            }
+            None => return Ok(returned),
+        }
+    }
+}
+
+fn pop(stack: &mut Vec<Object>) -> Object {
+    stack.pop().expect("bytecode popped an empty stack")
+}
+
+fn local(
+    frame: &Frame,
+    depth: u32,
+    index: u32,
+    name: Symbol,
+    err: &str,
+) -> Result<Object, EvalError> {
+    let val = frame.cell(depth as usize, index as usize).borrow().clone();
+    val.ok_or_else(|| unbound(err, name.as_str()))
+}
+
+/// Checks that the head of a call by name is a procedure.
+fn procedure(func: Object, name: Symbol) -> Result<Object, EvalError> {
+    if !is_procedure(&func) {
+        return Err(format!("Not a lambda: {}", name).into());
+    }
+    Ok(func)
+}
+
+/// Collects the values of a combination whose head is not a procedure,
+/// leaving out `Void`s.
+fn combine(head: Object, args: Vec<Object>, runtime: &Runtime) -> Result<Object, EvalError> {
+    let mut new_list = Vec::with_capacity(args.len() + 1);
+    new_list.extend(Some(head).filter(|head| *head != Object::Void));
+    new_list.extend(args.into_iter().filter(|arg| *arg != Object::Void));
+    let size = list_size(new_list.len());
+    runtime.allocate(Object::List(new_list.into()), size)
+}
+
+/// The bytecode of clause `i` of a closure, compiled on its first call.
+fn compiled(closure: &Closure, i: usize) -> Rc<Chunk> {
+    let clause = &closure.lambda.clauses[i];
+    clause
+        .compiled
+        .get_or_init(|| Rc::new(bytecode::compile_body(&clause.body)))
+        .clone()
+}
+
+/// Calls a procedure from Rust code on the VM, see `eval::apply`.
+pub fn apply(
+    func: &Object,
+    args: Vec<Object>,
+    env: &mut Rc<RefCell<Env>>,
+) -> Result<Object, EvalError> {
+    match eval::enter(func, args, env)? {
+        Entry::Return(val) => Ok(val),
+        Entry::Clause(closure, i, frame) => run(compiled(&closure, i), frame, env),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eval::{eval, eval_with_limits};
+    use crate::runtime::{Engine, Limits};
+
+    fn run_on(engine: Engine, program: &str) -> Result<String, String> {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        env.borrow().runtime().set_engine(engine);
+        let result = eval(program, &mut env);
+        assert_eq!(env.borrow().runtime().depth(), 0, "{}", program);
+        result
+            .map(|val| val.to_string())
+            .map_err(|err| err.to_string())
+    }
+
+    /// Checks that the VM gives the same result or error as the
+    /// tree-walker.
+    fn assert_same(program: &str) {
+        let expected = run_on(Engine::TreeWalker, program);
+        let actual = run_on(Engine::Bytecode, program);
+        assert_eq!(actual, expected, "{}", program);
+    }
+
+    #[test]
+    fn test_matches_tree_walker() {
+        let programs = [
+            "(+ 1 2)",
+            "((define r 10) (define pi 314) (* pi (* r r)))",
+            "((define fact (lambda (n) (if (< n 1) 1 (* n (fact (- n 1)))))) (fact 10))",
+            "((define fib (lambda (n) (if (< n 2) n (+ (fib (- n 1)) (fib (- n 2)))))) (fib 15))",
+            "((define sum-n (lambda (n a) (if (= n 0) a (sum-n (- n 1) (+ n a))))) (sum-n 5000 0))",
+            "((define add-n (lambda (n) (lambda (a) (+ n a)))) (define add-5 (add-n 5)) (add-5 10))",
+            "((define curry (lambda (a) (lambda (b) (lambda (c) (list a b c)))))
+              (((curry 1) 2) 3))",
+            "((define even? (lambda (n) (if (= n 0) true (odd? (- n 1)))))
+              (define odd? (lambda (n) (if (= n 0) false (even? (- n 1)))))
+              (even? 10001))",
+            "((define f (lambda (x)
+                (cond ((< x 0) :negative) ((= x 0)) (else (let ((y (* x 2))) (+ y 1))))))
+              (f -1) (f 0) (f 4))",
+            "(cond (false 1))",
+            "(let ((x 1) (y 2)) (define z 3) (list x y z))",
+            "((lambda (x) (let ((x (+ x 1))) (let ((x (* x 2))) x))) 3)",
+            "(begin 1 2 (list 3 nil 4))",
+            "(map (lambda (x) (* x x)) (list 1 2 3 4 5))",
+            "(reduce (lambda (a b) (+ a b)) 0 (range 10))",
+            "((lambda (a &optional (b (* a 2)) . rest) (list a b rest)) 1)",
+            "((lambda (a &optional (b (* a 2)) . rest) (list a b rest)) 1 5 6 7)",
+            "((lambda (&key (x 1) (y (+ x 1))) (list x y)) :y 5)",
+            "((lambda (&key x) x) :z 1)",
+            "((lambda (a b) a) 1)",
+            "((define area (case-lambda ((r) (* 3 (* r r))) ((w h) (* w h)))) (area 2) (area 2 3))",
+            "((case-lambda ((a) a)) 1 2)",
+            "(let-values (((a b) (values 1 2)) ((c . d) (values 3 4 5))) (list a b c d))",
+            "(receive (q &optional (r 0)) (values 7) (list q r))",
+            "(call-with-values (lambda () (values 1 2)) (lambda (a b) (+ a b)))",
+            "(apply + (list 1 2))",
+            "((define count (lambda (n) (if (= n 0) :done (apply count (list (- n 1))))))
+              (count 5000))",
+            "(funcall (lambda (x) (* x 3)) 4)",
+            "(+ 1 (call/cc (lambda (k) (+ 10 (k 2)))))",
+            "((define log (hash))
+              (define note (lambda (x) (hash-set! log (hash-count log) x)))
+              (call/cc (lambda (k)
+                (dynamic-wind
+                    (lambda () (note :before))
+                    (lambda () (begin (note :during) (k :escaped) (note :unreachable)))
+                    (lambda () (note :after)))))
+              (hash-values log))",
+            "((define g (make-generator (lambda () (begin (yield 1) (yield 2) 3))))
+              (generator-next g) (generator-next g) (generator-next g) (generator-done? g))",
+            "((define naturals (make-generator (lambda ()
+                (define loop (lambda (n) (begin (yield n) (loop (+ n 1)))))
+                (loop 0))))
+              (map + (list 10 20) naturals))",
+            "((define ints (lambda (n) (cons-stream n (ints (+ n 1)))))
+              (stream->list (stream-take (stream-map (lambda (x) (* x x)) (ints 1)) 5)))",
+            "((define p (delay (+ 1 2))) (force p))",
+            "(vector-map (lambda (x) (+ x 1)) (vector 1 2 3))",
+            "(let ((x 1)) #(x (+ x 1)))",
+            "(let ((x 1)) {:a x :b (+ x 1)})",
+            "(\"a\" \"b\")",
+            "(undefined-function 1)",
+            "(+ 1 undefined-variable)",
+            "((define x 1) (x 2))",
+            "(if 1 2 3)",
+            "(+ 1 \"a\")",
+            "(let ((x)) x)",
+            "((define f (lambda () (begin (define y y) y))) (f))",
+            "(read-file \"/dev/null\")",
+        ];
+        for program in programs {
+            assert_same(program);
+        }
+    }
+
+    #[test]
+    fn test_procedures_cross_engines() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let runtime = env.borrow().runtime();
+        eval("(define twice (lambda (f x) (f (f x))))", &mut env).unwrap();
+        runtime.set_engine(Engine::Bytecode);
+        eval("(define inc (lambda (x) (+ x 1)))", &mut env).unwrap();
+        let result = eval("(twice inc 1)", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(3));
+        runtime.set_engine(Engine::TreeWalker);
+        let result = eval("(twice inc 5)", &mut env).unwrap();
+        assert_eq!(result, Object::Integer(7));
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let runtime = env.borrow().runtime();
+        runtime.set_engine(Engine::Bytecode);
+
+        let err = eval(
+            "((define deep (lambda (n) (+ 1 (deep n)))) (deep 0))",
+            &mut env,
+        )
+        .unwrap_err();
+        assert_eq!(err, EvalError::StackOverflow(runtime.max_depth()));
+        assert_eq!(runtime.depth(), 0);
+
+        let limits = Limits {
+            fuel: Some(10_000),
+            ..Default::default()
+        };
+        let err = eval_with_limits(
+            "((define forever (lambda (n) (forever (+ n 1)))) (forever 0))",
+            &mut env,
+            limits,
+        )
+        .unwrap_err();
+        assert_eq!(err, EvalError::OutOfFuel);
+    }
+
+    #[test]
+    fn test_deep_recursion_does_not_use_the_native_stack() {
+        let mut env = Rc::new(RefCell::new(Env::new()));
+        let runtime = env.borrow().runtime();
+        runtime.set_engine(Engine::Bytecode);
+        runtime.set_max_depth(200_000);
+        let result = eval(
+            "((define count (lambda (n) (if (= n 0) 0 (+ 1 (count (- n 1)))))) (count 100000))",
+            &mut env,
+        )
+        .unwrap();
+        assert_eq!(result.to_string(), "(100000)");
+    }
+}